@@ -1,4 +1,9 @@
-use std::{collections::HashSet, env};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
 type SnekVal = u64;
 
@@ -10,13 +15,50 @@ pub enum ErrCode {
     IndexOutOfBounds = 3,
     InvalidVecSize = 4,
     OutOfMemory = 5,
+    WrongArity = 6,
 }
 
 const TRUE: u64 = 7;
 const FALSE: u64 = 3;
+const TAG_MASK: u64 = 0b111;
+const CLOSURE_TAG: u64 = 0b101;
 
 static mut HEAP_START: *const u64 = std::ptr::null();
 static mut HEAP_END: *const u64 = std::ptr::null();
+/// The heap, replaced by a larger one when collecting doesn't free enough of it
+static mut HEAP: Vec<u64> = Vec::new();
+/// How large, in words, the heap may grow
+static mut MAX_HEAP_SIZE: usize = DEFAULT_MAX_HEAP_SIZE;
+/// The end of the old generation, which starts at `HEAP_START`
+static mut OLD_TOP: *mut u64 = std::ptr::null_mut();
+/// The start of the nursery, which ends at `HEAP_END`. Generated code allocates in the nursery
+/// and reads this in the write barrier of `vec-set!`.
+#[export_name = "\x01snek_nursery_start"]
+static mut NURSERY_START: *const u64 = std::ptr::null();
+/// Old vectors that may refer to nursery objects, with their gc word set to `REMEMBERED_TAG`
+static mut REMEMBERED: Vec<*mut u64> = Vec::new();
+
+/// Counters reported when the program exits, kept when `SNEK_GC_STATS` is set, see [`report`]
+static mut GC_STATS: Option<GcStats> = None;
+
+/// Set through `SNEK_GC_VERIFY`, see [`verify_heap`]
+static mut VERIFY_HEAP: bool = false;
+
+/// Set through `SNEK_GC_CORRUPT`, see [`corrupt_heap`]
+#[cfg(gc_corruption)]
+static mut CORRUPT_HEAP: Option<Corruption> = None;
+
+/// The largest nursery, in words
+const NURSERY_SIZE: usize = 4096;
+/// 1 GiB
+const DEFAULT_MAX_HEAP_SIZE: usize = 1 << 27;
+/// Percentage of the heap a collection may leave live before the heap grows, so that a live set
+/// filling most of it doesn't make every allocation collect again
+const GROW_PERCENT: usize = 60;
+const REMEMBERED_TAG: u64 = 2;
+
+/// Address of every stack map by the return address of its call, see `SNEK_STACK_MAPS`
+static STACK_MAPS: OnceLock<HashMap<u64, usize>> = OnceLock::new();
 
 #[link(name = "our_code")]
 extern "C" {
@@ -25,6 +67,12 @@ extern "C" {
     // Courtesy of Max New (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
     #[link_name = "\x01our_code_starts_here"]
     fn our_code_starts_here(input: u64, heap_start: *const u64, heap_end: *const u64) -> u64;
+
+    /// The number of stack maps, followed by the maps. A map describes a call that may end up in
+    /// the GC: the return address of the call, the number of offsets and the offsets from `rbp`
+    /// (in bytes) of the words of the calling frame holding live snek values during the call.
+    #[link_name = "\x01snek_stack_maps"]
+    static SNEK_STACK_MAPS: u64;
 }
 
 #[export_name = "\x01snek_error"]
@@ -37,10 +85,12 @@ pub extern "C" fn snek_error(errcode: i64) {
         eprintln!("index out of bounds");
     } else if errcode == ErrCode::InvalidVecSize as i64 {
         eprintln!("vector size must be non-negative");
+    } else if errcode == ErrCode::WrongArity as i64 {
+        eprintln!("wrong number of arguments");
     } else {
         eprintln!("an error ocurred {}", errcode);
     }
-    std::process::exit(errcode as i32);
+    exit(errcode as i32);
 }
 
 #[export_name = "\x01snek_print"]
@@ -49,6 +99,16 @@ pub unsafe extern "C" fn snek_print(val: SnekVal) -> SnekVal {
     val
 }
 
+/// Vectors and closures are both pointers into the heap, tagged with `0b001` and `0b101`
+/// respectively. Returns the untagged address of the object `val` points to, if any.
+fn heap_object(val: SnekVal) -> Option<*mut u64> {
+    if val != TRUE && val != FALSE && val != 1 && val & 1 == 1 {
+        Some((val & !TAG_MASK) as *mut u64)
+    } else {
+        None
+    }
+}
+
 /// This function is called when the program needs to allocate `count` words of memory and there's no
 /// space left. The function should try to clean up space by triggering a garbage collection. If there's
 /// not enough space to hold `count` words after running the garbage collector, the program should terminate
@@ -64,187 +124,553 @@ pub unsafe extern "C" fn snek_print(val: SnekVal) -> SnekVal {
 ///     * `stack_base`: A pointer to the "base" of the stack.
 ///     * `curr_rbp`: The value of `%rbp` in the stack frame that triggered the allocation.
 ///     * `curr_rsp`: The value of `%rsp` in the stack frame that triggered the allocation.
+///     * `stack_map`: The stack map of the call to this function, or null to scan the whole stack
+///       conservatively (see [`stack_roots`]).
 ///
 /// Returns:
 ///
 /// The new heap pointer where the program should allocate the vector (i.e., the new value of `%r15`)
+/// and the new end of the heap (i.e., the new value of `%r14`), in `%rax` and `%rdx` respectively.
 ///
 #[export_name = "\x01snek_try_gc"]
-pub unsafe fn snek_try_gc(
+pub unsafe extern "C" fn snek_try_gc(
     count: isize,
     heap_ptr: *const u64,
     stack_base: *const u64,
     curr_rbp: *const u64,
     curr_rsp: *const u64,
-) -> *const u64 {
-    // print the heap
-    //snek_print_stack(stack_base, curr_rbp, curr_rsp);
-    //print_heap(heap_ptr);
-    // iterate the stack and find roots
-    let mut stack_ptr = stack_base.sub(1);
-    let mut to_visit:Vec<*mut u64> = Vec::new();
-    let mut root_set:HashSet<*mut u64> = HashSet::new();
-    let mut free_space =(((HEAP_END as u64) - (HEAP_START as u64))/8) as i64;
-    while stack_ptr >= curr_rsp {
-        let val = *stack_ptr;
-        //println!("{}", val);
-        if val != TRUE && val != FALSE && val != 1 && val & 1 == 1  &&
-            (val < (HEAP_END as u64) && val >= (HEAP_START as u64)){ // make sure its not an instruction pointer
-            //println!("active");
-            let addr = (val - 1) as *mut u64;
-            if root_set.insert(addr) {
-                to_visit.push(addr);
-                let active_size = addr.add(1).read() as i64;
-                free_space = free_space - active_size - 2;
+    stack_map: *const u64,
+) -> Heap {
+    let roots = stack_roots(stack_base, curr_rbp, curr_rsp, stack_map);
+    let precise = !stack_map.is_null();
+    collect(count as usize, heap_ptr, roots, !precise, precise)
+}
+
+/// This function should trigger garbage collection and return the updated heap pointer (i.e., the new
+/// value of `%r15`) and end of the heap. See [`snek_try_gc`] for a description of the meaning of the
+/// arguments.
+#[export_name = "\x01snek_gc"]
+pub unsafe extern "C" fn snek_gc(
+    heap_ptr: *const u64,
+    stack_base: *const u64,
+    curr_rbp: *const u64,
+    curr_rsp: *const u64,
+    stack_map: *const u64,
+) -> Heap {
+    let roots = stack_roots(stack_base, curr_rbp, curr_rsp, stack_map);
+    collect(0, heap_ptr, roots, true, !stack_map.is_null())
+}
+
+/// Where generated code allocates after a collection: from `heap_ptr` up to `heap_end`
+#[repr(C)]
+pub struct Heap {
+    heap_ptr: *const u64,
+    heap_end: *const u64,
+}
+
+/// The write barrier of `vec-set!`, called when storing a reference to a nursery object into the
+/// old vector `vec` that isn't remembered yet. Its gc word is set until the next collection,
+/// which treats its elements as roots.
+#[export_name = "\x01snek_remember"]
+pub unsafe extern "C" fn snek_remember(vec: SnekVal) {
+    let addr = (vec & !TAG_MASK) as *mut u64;
+    *addr = REMEMBERED_TAG;
+    remembered().push(addr);
+}
+
+unsafe fn remembered() -> &'static mut Vec<*mut u64> {
+    &mut *std::ptr::addr_of_mut!(REMEMBERED)
+}
+
+/// Frees the nursery, which is in use up to `heap_ptr`, and places a new one that fits `count`
+/// words, growing the heap when there is no room for it or more than `GROW_PERCENT` of it is
+/// still live, and exiting when there is no room and it can't grow enough. A
+/// minor collection promotes what survives to the old generation. A full one, when `full` is set
+/// or the free space below the nursery may not fit every survivor, compacts both generations
+/// together. `precise` tells whether `roots` come from stack maps.
+unsafe fn collect(
+    count: usize,
+    heap_ptr: *const u64,
+    mut roots: Vec<*mut u64>,
+    full: bool,
+    precise: bool,
+) -> Heap {
+    let start = Instant::now();
+    // updating a root twice would follow a forwarding address twice
+    roots.sort();
+    roots.dedup();
+    if VERIFY_HEAP {
+        #[cfg(gc_corruption)]
+        corrupt_heap(Corruption::Header, heap_ptr);
+        verify_heap("before", heap_ptr, &roots, precise);
+    }
+    let used = heap_ptr.offset_from(NURSERY_START) as usize;
+    let reserve = NURSERY_START.offset_from(OLD_TOP) as usize;
+    let in_use = OLD_TOP.offset_from(HEAP_START) as usize + used;
+    let kind = if full || reserve < used {
+        collect_full(&roots, heap_ptr);
+        "full"
+    } else {
+        collect_nursery(&roots, heap_ptr);
+        if !place_nursery(count) {
+            collect_full(&roots, OLD_TOP);
+            "minor+full"
+        } else {
+            "minor"
+        }
+    };
+    let live = OLD_TOP.offset_from(HEAP_START) as usize;
+    let size = HEAP_END.offset_from(HEAP_START) as usize;
+    let crowded = live * 100 > size * GROW_PERCENT && size < MAX_HEAP_SIZE;
+    let fits = place_nursery(count);
+    let grown = (!fits || crowded) && grow(count, &roots);
+    if !fits && !grown {
+        eprintln!("out of memory");
+        exit(ErrCode::OutOfMemory as i32)
+    }
+    if grown {
+        place_nursery(count);
+    }
+    if VERIFY_HEAP {
+        #[cfg(gc_corruption)]
+        corrupt_heap(Corruption::Forward, NURSERY_START);
+        verify_heap("after", NURSERY_START, &roots, precise);
+    }
+    report(kind, in_use, live, roots.len(), grown, start.elapsed());
+    Heap {
+        heap_ptr: NURSERY_START,
+        heap_end: HEAP_END,
+    }
+}
+
+/// Checks, `when` a collection runs, that both generations are sequences of objects whose size
+/// fits in them and whose gc word is 0, or `REMEMBERED_TAG` for old vectors (so no mark or
+/// forwarding address is left over), and that every reference in them points to the start of one
+/// of these objects. With `precise` roots, the references on the stack are checked too, and old
+/// objects referring to the nursery have to be remembered (the legacy backend has no write
+/// barrier). Aborts with a dump of the offending object.
+unsafe fn verify_heap(when: &str, heap_ptr: *const u64, roots: &[*mut u64], precise: bool) {
+    let mut starts = HashSet::new();
+    let generations = [
+        (HEAP_START, OLD_TOP as *const u64, true),
+        (NURSERY_START, heap_ptr, false),
+    ];
+    for (start, end, old) in generations {
+        let mut cursor = start;
+        while cursor < end {
+            if *cursor != 0 && !(old && *cursor == REMEMBERED_TAG) {
+                heap_error(when, "unexpected gc word", cursor);
+            }
+            if end.offset_from(cursor) < 2 || *cursor.add(1) > end.offset_from(cursor) as u64 - 2 {
+                heap_error(when, "the object runs past the end of its generation", cursor);
             }
+            starts.insert(cursor);
+            cursor = cursor.add(2 + *cursor.add(1) as usize);
         }
-        stack_ptr = stack_ptr.sub(1);
-    }
-    // traverse tree from roots to find all live data and mark it
-    while to_visit.len() > 0 {
-        let curr_ptr = to_visit.remove(0);
-        // mark
-        *curr_ptr = 1;
-        //println!("mark");
-        let size = curr_ptr.add(1).read() as usize;
-        for i in 0..size { // queue up any members of this vec not already queued up
-            let elem = curr_ptr.add(2+i).read();
-            if elem != TRUE && elem != FALSE && elem != 1 && elem & 1 == 1 {
-                let addr = (elem - 1) as *mut u64;
-                if root_set.insert(addr) {
-                    to_visit.push(addr);
-                    let active_size = addr.add(1).read() as i64;
-                    free_space = free_space - active_size-2;
-                }
+    }
+    for &obj in &starts {
+        for i in 0..*obj.add(1) as usize {
+            let Some(addr) = heap_object(*obj.add(2 + i)) else {
+                continue;
+            };
+            if !starts.contains(&(addr as *const u64)) {
+                heap_error(when, &format!("element {i} doesn't point to an object"), obj);
+            }
+            let young = addr as *const u64 >= NURSERY_START;
+            if precise && obj < NURSERY_START && young && *obj != REMEMBERED_TAG {
+                let problem = format!("element {i} points to the nursery but the object isn't remembered");
+                heap_error(when, &problem, obj);
             }
         }
     }
-    //print_heap(heap_ptr);
-    //println!("{}",free_space);
-    if (free_space as isize) < count {
-        eprintln!("out of memory");
-        std::process::exit(ErrCode::OutOfMemory as i32)
-    }
-    // compacting 1: compute forwarding addresses
-    let mut heap_cursor = HEAP_START as *mut u64;
-    let mut free_heap_cursor = HEAP_START as *mut u64;
-    // move both cursors until finding something that can be cleared
-    while (free_heap_cursor as *const u64)< heap_ptr {
-        let gc_tag = free_heap_cursor.read();
-        if gc_tag & 1 != 0 { // move both cursors up by one vec
-            let size = free_heap_cursor.add(1).read() as usize;
-            heap_cursor = heap_cursor.add(2+size);
-            free_heap_cursor = free_heap_cursor.add(2+size);
-        } else {
-            let size = heap_cursor.add(1).read() as usize;
-            if size == 0 { // have hit the end of heap, cannot compact anymore
-                return heap_ptr;
-            } else {
-                heap_cursor = heap_cursor.add(2+size); // advance the scanning cursor to next
+    if !precise {
+        return;
+    }
+    for root in roots {
+        if let Some(addr) = heap_object(**root) {
+            if !starts.contains(&(addr as *const u64)) {
+                eprintln!(
+                    "heap verification failed {when} collection: the stack slot {:?} holds {:#x}, which doesn't point to an object",
+                    *root, **root
+                );
+                std::process::abort()
             }
-            break;
         }
     }
-    // start assigning fowarding addresses
-    while (heap_cursor as *const u64) < heap_ptr {
-        let gc_tag = heap_cursor.read();
-        let heap_cursor_size = heap_cursor.add(1).read() as usize;
-        if gc_tag != 0 { 
-            *heap_cursor = (free_heap_cursor as u64) + 1; // assign free space + tagged
-            // move free heap cursor up
-            let free_size = heap_cursor_size;
-            free_heap_cursor = free_heap_cursor.add(2+free_size);
+}
+
+/// A way of damaging the heap, to check that [`verify_heap`] notices
+#[cfg(gc_corruption)]
+#[derive(Clone, Copy, PartialEq)]
+enum Corruption {
+    /// The size of the first nursery object runs past the nursery, before a collection
+    Header,
+    /// The gc word of the first old object is left holding a forwarding address, after a
+    /// collection
+    Forward,
+}
+
+/// Damages the heap the first time a collection gets to the point where `SNEK_GC_CORRUPT`
+/// (`header` or `forward`) asks for `corruption`, if there is an object to damage. Only the
+/// tests build it in, with `--cfg gc_corruption`
+#[cfg(gc_corruption)]
+unsafe fn corrupt_heap(corruption: Corruption, heap_ptr: *const u64) {
+    if CORRUPT_HEAP != Some(corruption) {
+        return;
+    }
+    match corruption {
+        Corruption::Header if NURSERY_START < heap_ptr => {
+            *(NURSERY_START as *mut u64).add(1) = u32::MAX as u64;
         }
-        let heap_cursor_size = heap_cursor.add(1).read() as usize;
-        heap_cursor = heap_cursor.add(2+heap_cursor_size);
-    }
-    //print_heap(heap_ptr);
-
-    // compacting 2: update references
-    // linear scan of stack
-    stack_ptr = stack_base.sub(1);
-    while stack_ptr >= curr_rsp {
-        let val = *stack_ptr;
-        if val != TRUE && val != FALSE && val != 1 && val & 1 == 1 && (val < (HEAP_END as u64) && val >= (HEAP_START as u64)){
-            let addr = (val - 1) as *mut u64;
-            // check if forwarding addr has been set for this addr
-            let gc_tag = addr.read();
-            if gc_tag & 1 == 1 && gc_tag != 1{
-                *(stack_ptr as *mut u64) = gc_tag;
-            }
+        Corruption::Forward if HEAP_START < OLD_TOP => {
+            *(HEAP_START as *mut u64) = HEAP_START as u64 + 1;
+        }
+        _ => return,
+    }
+    CORRUPT_HEAP = None;
+}
+
+/// Reports `problem` with the object at `obj`: its address, gc word, size and first elements
+unsafe fn heap_error(when: &str, problem: &str, obj: *const u64) -> ! {
+    eprintln!("heap verification failed {when} collection: {problem}");
+    eprintln!("object at {obj:?} (heap from {HEAP_START:?} to {HEAP_END:?}):");
+    eprintln!("  gc word: {:#x}", *obj);
+    if obj.add(1) < HEAP_END {
+        let size = *obj.add(1) as usize;
+        eprintln!("  size: {size}");
+        let shown = size.min(16).min(HEAP_END.offset_from(obj.add(2)).max(0) as usize);
+        for i in 0..shown {
+            eprintln!("  [{i}]: {:#x}", *obj.add(2 + i));
         }
-        stack_ptr = stack_ptr.sub(1);
-    }
-    // linear scan of heap
-    heap_cursor = HEAP_START as *mut u64;
-    while (heap_cursor as *const u64) < heap_ptr {
-        let gc_tag = heap_cursor.read();
-        let heap_cursor_size = heap_cursor.add(1).read() as usize;
-        if gc_tag != 0 { 
-            // if gc_tag == 1 { // marked, but not moving
-            //     *heap_cursor = 0;
-            // } else {
-                for i in 2..2+heap_cursor_size {
-                    let heap_val = heap_cursor.add(i).read();
-                    if heap_val != TRUE && heap_val != FALSE && heap_val != 1 && heap_val & 1 == 1 {
-                        let fwd_tag = ((heap_val-1) as *const u64).read();
-                        if fwd_tag != 0  && fwd_tag != 1 {
-                            let heap_val_ptr = heap_cursor.add(i) as *mut u64;
-                            *heap_val_ptr = fwd_tag;
-                        }
-                    }
-                    //println!("{}", heap_val);
-                }
-           // }
+        if shown < size {
+            eprintln!("  ...");
         }
-        heap_cursor = heap_cursor.add(2+heap_cursor_size);
-    }
-    //snek_print_stack(stack_base, curr_rbp, curr_rsp);
-    //print_heap(heap_ptr);
-    // compacting 3: move the objects
-    heap_cursor = HEAP_START as *mut u64;
-    while (heap_cursor as *const u64) < heap_ptr {
-        let gc_tag = heap_cursor.read();
-        let heap_cursor_size = heap_cursor.add(1).read() as usize;
-        if gc_tag != 0 { 
-            if gc_tag == 1 {// marked, but not moving
-                    *heap_cursor = 0;
-            } else{
-                let new_addr = (gc_tag - 1) as *mut u64;
-                *new_addr = 0; // zero out gc metainfo
-                *(new_addr.add(1)) = heap_cursor_size as u64; // set size field
-                for i in 2..2+heap_cursor_size {
-                    let heap_val = heap_cursor.add(i).read();
-                    *(new_addr.add(i)) = heap_val;
-                }
+    }
+    std::process::abort()
+}
+
+#[derive(Default)]
+struct GcStats {
+    collections: usize,
+    /// Collections promoting the nursery, some followed by a full one
+    minor: usize,
+    full: usize,
+    growths: usize,
+    freed: usize,
+    max_live: usize,
+    time: Duration,
+}
+
+unsafe fn gc_stats() -> Option<&'static mut GcStats> {
+    (*std::ptr::addr_of_mut!(GC_STATS)).as_mut()
+}
+
+/// With `SNEK_GC_STATS` set, prints a line about the collection that just ran to stderr: how
+/// many words were in use before it and live after it, and the difference, which it freed.
+unsafe fn report(
+    kind: &str,
+    in_use: usize,
+    live: usize,
+    roots: usize,
+    grown: bool,
+    time: Duration,
+) {
+    let Some(stats) = gc_stats() else {
+        return;
+    };
+    stats.collections += 1;
+    if kind != "full" {
+        stats.minor += 1;
+    }
+    if kind != "minor" {
+        stats.full += 1;
+    }
+    stats.growths += grown as usize;
+    stats.freed += in_use - live;
+    stats.max_live = stats.max_live.max(live);
+    stats.time += time;
+    let heap = if grown {
+        format!(", heap grown to {} words", HEAP_END.offset_from(HEAP_START))
+    } else {
+        String::new()
+    };
+    eprintln!(
+        "gc {} ({kind}): {live} live words, {} freed words, {roots} roots, {:.3} ms{heap}",
+        stats.collections,
+        in_use - live,
+        time.as_secs_f64() * 1000.0,
+    );
+}
+
+/// Exits with `code`, printing the summary of the collections first when `SNEK_GC_STATS` is set
+fn exit(code: i32) -> ! {
+    if let Some(stats) = unsafe { gc_stats() } {
+        eprintln!(
+            "gc summary: {} collections ({} minor, {} full), {} heap growths, {} freed words, at most {} live words, {:.3} ms",
+            stats.collections,
+            stats.minor,
+            stats.full,
+            stats.growths,
+            stats.freed,
+            stats.max_live,
+            stats.time.as_secs_f64() * 1000.0,
+        );
+    }
+    std::process::exit(code)
+}
+
+unsafe fn heap() -> &'static mut Vec<u64> {
+    &mut *std::ptr::addr_of_mut!(HEAP)
+}
+
+/// Moves the old generation, right after a full collection, to a new heap twice as large as the
+/// current one and as the old generation plus `count` words, within `MAX_HEAP_SIZE`. Every
+/// reference into the current heap, from `roots` and from the moved objects, is shifted by as
+/// much as the heap moved.
+unsafe fn grow(count: usize, roots: &[*mut u64]) -> bool {
+    let size = HEAP_END.offset_from(HEAP_START) as usize;
+    let live = OLD_TOP.offset_from(HEAP_START) as usize;
+    if live + count > MAX_HEAP_SIZE {
+        return false;
+    }
+    let new_size = (2 * size).max(2 * (live + count)).min(MAX_HEAP_SIZE);
+    let mut new_heap = vec![0; new_size];
+    let new_start = new_heap.as_mut_ptr();
+    std::ptr::copy_nonoverlapping(HEAP_START, new_start, live);
+    let delta = (new_start as u64).wrapping_sub(HEAP_START as u64);
+    let relocate = |slot: *mut u64| {
+        if let Some(addr) = heap_object(*slot) {
+            if (addr as *const u64) >= HEAP_START && (addr as *const u64) < HEAP_END {
+                *slot = (*slot).wrapping_add(delta);
             }
+        }
+    };
+    for root in roots {
+        relocate(*root);
+    }
+    let new_top = new_start.add(live);
+    let mut cursor = new_start;
+    while cursor < new_top {
+        let size = *cursor.add(1) as usize;
+        for i in 0..size {
+            relocate(cursor.add(2 + i));
+        }
+        cursor = cursor.add(2 + size);
+    }
+    *heap() = new_heap;
+    HEAP_START = new_start;
+    HEAP_END = new_start.add(new_size);
+    OLD_TOP = new_top;
+    true
+}
+
+/// Puts the nursery at the end of the heap, taking half the free space (at most `NURSERY_SIZE`
+/// words, at least `count`) so that the other half can take whatever survives it. The gap left
+/// between the two generations is never a single word, see [`collect_full`].
+unsafe fn place_nursery(count: usize) -> bool {
+    let free = HEAP_END.offset_from(OLD_TOP) as usize;
+    if free < count {
+        return false;
+    }
+    let mut size = (free / 2).min(NURSERY_SIZE).max(count);
+    if free - size == 1 {
+        size += 1;
+    }
+    NURSERY_START = HEAP_END.sub(size);
+    true
+}
 
+/// Copies the nursery objects reachable from `roots` and from remembered old vectors to the end
+/// of the old generation, then scans the copies for more of them, updating every reference.
+unsafe fn collect_nursery(roots: &[*mut u64], heap_ptr: *const u64) {
+    let scan_start = OLD_TOP;
+    for root in roots {
+        promote(*root, heap_ptr);
+    }
+    for vec in remembered().drain(..) {
+        *vec = 0;
+        for i in 0..*vec.add(1) as usize {
+            promote(vec.add(2 + i), heap_ptr);
         }
-        heap_cursor = heap_cursor.add(2+heap_cursor_size);
     }
-    let new_heap_ptr = free_heap_cursor;
-    while (free_heap_cursor as *const u64) < heap_ptr {
-        *free_heap_cursor = 0;
-        free_heap_cursor = free_heap_cursor.add(1);
+    let mut cursor = scan_start;
+    while cursor < OLD_TOP {
+        let size = *cursor.add(1) as usize;
+        for i in 0..size {
+            promote(cursor.add(2 + i), heap_ptr);
+        }
+        cursor = cursor.add(2 + size);
     }
-    // print the heap
-    //snek_print_stack(stack_base, curr_rbp, curr_rsp);
-    //print_heap(heap_ptr);
-    return new_heap_ptr;
-    // eprintln!("out of memory");
-    // std::process::exit(ErrCode::OutOfMemory as i32)
+    zero(NURSERY_START as *mut u64, heap_ptr);
 }
 
-/// This function should trigger garbage collection and return the updated heap pointer (i.e., the new
-/// value of `%r15`). See [`snek_try_gc`] for a description of the meaning of the arguments.
-#[export_name = "\x01snek_gc"]
-pub unsafe fn snek_gc(
-    heap_ptr: *const u64,
+/// Makes `slot` refer to the old copy of the nursery object it refers to, if any. The gc word of
+/// a copied nursery object holds the address of the copy plus one.
+unsafe fn promote(slot: *mut u64, heap_ptr: *const u64) {
+    let val = *slot;
+    let Some(addr) = heap_object(val) else {
+        return;
+    };
+    if (addr as *const u64) < NURSERY_START || (addr as *const u64) >= heap_ptr {
+        return;
+    }
+    if *addr == 0 {
+        let words = *addr.add(1) as usize + 2;
+        std::ptr::copy_nonoverlapping(addr, OLD_TOP, words);
+        *addr = OLD_TOP as u64 + 1;
+        OLD_TOP = OLD_TOP.add(words);
+    }
+    *slot = (*addr - 1) | (val & TAG_MASK);
+}
+
+/// Mark-compact over the whole heap up to `heap_ptr`, leaving every live object in the old
+/// generation. The gc word of an object is 1 once it is marked, then the address it moves to
+/// plus one.
+unsafe fn collect_full(roots: &[*mut u64], heap_ptr: *const u64) {
+    for vec in remembered().drain(..) {
+        *vec = 0;
+    }
+    // the free space between the generations becomes a garbage object, so that the heap can be
+    // walked object by object
+    if OLD_TOP < NURSERY_START as *mut u64 && NURSERY_START <= heap_ptr {
+        *OLD_TOP = 0;
+        *OLD_TOP.add(1) = NURSERY_START.offset_from(OLD_TOP) as u64 - 2;
+    }
+    // mark
+    let mut to_visit: Vec<*mut u64> = vec![];
+    for root in roots {
+        if let Some(addr) = heap_object(**root) {
+            to_visit.push(addr);
+        }
+    }
+    while let Some(addr) = to_visit.pop() {
+        if *addr != 0 {
+            continue;
+        }
+        *addr = 1;
+        for i in 0..*addr.add(1) as usize {
+            if let Some(elem) = heap_object(*addr.add(2 + i)) {
+                to_visit.push(elem);
+            }
+        }
+    }
+    // compute forwarding addresses
+    let start = HEAP_START as *mut u64;
+    let mut free = start;
+    let mut cursor = start;
+    while (cursor as *const u64) < heap_ptr {
+        let words = *cursor.add(1) as usize + 2;
+        if *cursor != 0 {
+            *cursor = free as u64 + 1;
+            free = free.add(words);
+        }
+        cursor = cursor.add(words);
+    }
+    // update references, from the roots and from live objects
+    let forward = |slot: *mut u64| {
+        if let Some(addr) = heap_object(*slot) {
+            *slot = (*addr - 1) | (*slot & TAG_MASK);
+        }
+    };
+    for root in roots {
+        forward(*root);
+    }
+    cursor = start;
+    while (cursor as *const u64) < heap_ptr {
+        let size = *cursor.add(1) as usize;
+        if *cursor != 0 {
+            for i in 0..size {
+                forward(cursor.add(2 + i));
+            }
+        }
+        cursor = cursor.add(2 + size);
+    }
+    // move the objects
+    cursor = start;
+    while (cursor as *const u64) < heap_ptr {
+        let words = *cursor.add(1) as usize + 2;
+        if *cursor != 0 {
+            let new_addr = (*cursor - 1) as *mut u64;
+            std::ptr::copy(cursor, new_addr, words);
+            *new_addr = 0;
+        }
+        cursor = cursor.add(words);
+    }
+    zero(free, heap_ptr);
+    OLD_TOP = free;
+    NURSERY_START = free;
+}
+
+unsafe fn zero(from: *mut u64, to: *const u64) {
+    let mut cursor = from;
+    while (cursor as *const u64) < to {
+        *cursor = 0;
+        cursor = cursor.add(1);
+    }
+}
+
+unsafe fn stack_maps() -> &'static HashMap<u64, usize> {
+    STACK_MAPS.get_or_init(|| {
+        let mut maps = HashMap::new();
+        let mut cursor = &SNEK_STACK_MAPS as *const u64;
+        let count = *cursor;
+        cursor = cursor.add(1);
+        for _ in 0..count {
+            maps.insert(*cursor, cursor as usize);
+            cursor = cursor.add(2 + *cursor.add(1) as usize);
+        }
+        maps
+    })
+}
+
+/// Addresses of the stack words that may hold heap references.
+///
+/// With the stack map of the call into the runtime, the frames are walked from `curr_rbp` up to
+/// `stack_base` (the frame of `our_code_starts_here`) through the saved `rbp` at the bottom of
+/// every frame, and the roots are exactly the words the map of each frame lists: the map of a
+/// caller is found by the return address right above the saved `rbp`. Without one, for code
+/// that doesn't emit maps, every word between `curr_rsp` and `stack_base` that looks like a
+/// pointer into the heap is a root.
+unsafe fn stack_roots(
     stack_base: *const u64,
     curr_rbp: *const u64,
     curr_rsp: *const u64,
-) -> *const u64 {
-    return snek_try_gc(0, heap_ptr, stack_base, curr_rbp, curr_rsp);
+    stack_map: *const u64,
+) -> Vec<*mut u64> {
+    let mut roots = vec![];
+    if stack_map.is_null() {
+        let mut stack_ptr = stack_base.sub(1);
+        while stack_ptr >= curr_rsp {
+            let val = *stack_ptr;
+            // make sure its not an instruction pointer
+            if val < (HEAP_END as u64) && val >= (HEAP_START as u64) {
+                roots.push(stack_ptr as *mut u64);
+            }
+            stack_ptr = stack_ptr.sub(1);
+        }
+        return roots;
+    }
+    let mut rbp = curr_rbp;
+    let mut map = stack_map;
+    loop {
+        let count = *map.add(1) as usize;
+        for i in 0..count {
+            let offset = *map.add(2 + i) as i64 as isize;
+            roots.push((rbp as *mut u8).offset(offset) as *mut u64);
+        }
+        if rbp == stack_base {
+            return roots;
+        }
+        let ret = *rbp.add(1);
+        map = match stack_maps().get(&ret) {
+            Some(map) => *map as *const u64,
+            None => {
+                eprintln!("no stack map for the call returning to {ret:#x}");
+                std::process::abort()
+            }
+        };
+        rbp = *rbp as *const u64;
+    }
 }
 
 /// A helper function that can called with the `(snek-printstack)` snek function. It prints the stack
@@ -270,6 +696,8 @@ unsafe fn snek_str(val: SnekVal, seen: &mut HashSet<SnekVal>) -> String {
         format!("{}", (val as i64) >> 1)
     } else if val == 1 {
         format!("nil")
+    } else if val & TAG_MASK == CLOSURE_TAG {
+        format!("<closure>")
     } else if val & 1 == 1 {
         if !seen.insert(val) {
             return "[...]".to_string();
@@ -322,14 +750,35 @@ fn main() {
     let heap_size = if args.len() >= 3 { &args[2] } else { "10000" };
     let input = parse_input(&input);
     let heap_size = parse_heap_size(&heap_size);
+    let max_heap_size = if args.len() >= 4 {
+        parse_heap_size(&args[3])
+    } else {
+        DEFAULT_MAX_HEAP_SIZE
+    };
 
     // Initialize heap
-    let mut heap: Vec<u64> = Vec::with_capacity(heap_size);
     unsafe {
-        HEAP_START = heap.as_mut_ptr();
+        if env::var_os("SNEK_GC_STATS").is_some() {
+            GC_STATS = Some(GcStats::default());
+        }
+        VERIFY_HEAP = env::var_os("SNEK_GC_VERIFY").is_some();
+        #[cfg(gc_corruption)]
+        {
+            CORRUPT_HEAP = match env::var("SNEK_GC_CORRUPT").as_deref() {
+                Ok("header") => Some(Corruption::Header),
+                Ok("forward") => Some(Corruption::Forward),
+                _ => None,
+            };
+        }
+        MAX_HEAP_SIZE = max_heap_size.max(heap_size);
+        *heap() = vec![0; heap_size];
+        HEAP_START = heap().as_mut_ptr();
         HEAP_END = HEAP_START.add(heap_size);
+        OLD_TOP = HEAP_START as *mut u64;
+        place_nursery(0);
     }
 
-    let i: u64 = unsafe { our_code_starts_here(input, HEAP_START, HEAP_END) };
+    let i: u64 = unsafe { our_code_starts_here(input, NURSERY_START, HEAP_END) };
     unsafe { snek_print(i) };
+    exit(0);
 }
@@ -1,8 +1,7 @@
 use core::panic;
-use std::collections::{HashSet};
-use im::HashMap;
+use im::{HashMap, HashSet};
 
-use crate::syntax::{Expr, FunDecl, Symbol, Prog, Op1, Op2};
+use crate::syntax::{Expr, FunDecl, Symbol, Prog, Op1, Op2, Span};
 pub enum FlatVal {
     Num(i64),
     True,
@@ -11,12 +10,13 @@ pub enum FlatVal {
 }
 
 pub enum FlatOp {
-    Add1(Box<FlatVal>),
-    Sub1(Box<FlatVal>),
-    Plus(Box<FlatVal>, Box<FlatVal>),
-    Minus(Box<FlatVal>, Box<FlatVal>),
-    Times(Box<FlatVal>, Box<FlatVal>),
-    Divide(Box<FlatVal>, Box<FlatVal>),
+    /// The arithmetic keeps the span of the source expression, to say where it overflows
+    Add1(Box<FlatVal>, Span),
+    Sub1(Box<FlatVal>, Span),
+    Plus(Box<FlatVal>, Box<FlatVal>, Span),
+    Minus(Box<FlatVal>, Box<FlatVal>, Span),
+    Times(Box<FlatVal>, Box<FlatVal>, Span),
+    Divide(Box<FlatVal>, Box<FlatVal>, Span),
     Eq(Box<FlatVal>, Box<FlatVal>),
     Gt(Box<FlatVal>, Box<FlatVal>),
     Ge(Box<FlatVal>, Box<FlatVal>),
@@ -32,6 +32,14 @@ pub enum FlatOp {
 
     Call(Symbol, Vec<FlatVal>),
 
+    /// Only exists until `lift_lambdas_op` turns it into a `MakeClosure` and a new definition
+    Lambda(Vec<Symbol>, Box<FlatBlock>),
+    /// Allocates a closure for the lifted definition with the given arity and captured values
+    MakeClosure(Symbol, usize, Vec<FlatVal>),
+    CallIndirect(Box<FlatVal>, Vec<FlatVal>),
+    /// Reads the n-th captured value out of a closure
+    ClosureGet(Box<FlatVal>, usize),
+
     MakeVec(Box<FlatVal>, Box<FlatVal>),
     Vec(Vec<FlatVal>),
     VecSet(Box<FlatVal>, Box<FlatVal>, Box<FlatVal>),
@@ -78,14 +86,14 @@ fn get_uniq_name(s: Symbol, idx: u32) -> Symbol{
     Symbol::new (format!("uq_{s}_{idx}"))
 }
 
-fn anf_val(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u32>) -> (FlatVal, Vec<(Symbol, FlatOp)>) {
+fn anf_val(e: &Expr, i: &mut i32, bound_vars: &HashMap<Symbol, u32>) -> (FlatVal, Vec<(Symbol, FlatOp)>) {
     match e {
         Expr::Number(n) => (FlatVal::Num(*n), vec![]),
-        Expr::Var(s) => (FlatVal::Var(get_uniq_name(s.clone(), *bound_vars.get(s).unwrap())), vec![]),
+        Expr::Var(s, _) => (FlatVal::Var(get_uniq_name(s.clone(), *bound_vars.get(s).unwrap())), vec![]),
         Expr::Boolean(b) if *b==true => (FlatVal::True, vec![]),
         Expr::Boolean(_) => (FlatVal::False,vec![]),
         _ => {
-            let (op, mut binds) = anf_expr(e, i, in_main, bound_vars);
+            let (op, mut binds) = anf_expr(e, i, bound_vars);
             let tmp = new_label(i, "%t");
             binds.push((tmp.clone(), op));
             (FlatVal::Var(tmp), binds)
@@ -93,11 +101,11 @@ fn anf_val(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u3
     }
 }
 
-fn anf_op1(op: &Op1, e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u32>) -> (FlatOp, Vec<(Symbol, FlatOp)>){
-    let (e, binds) = anf_val(e, i, in_main, bound_vars);
+fn anf_op1(op: &Op1, e: &Expr, span: Span, i: &mut i32, bound_vars: &HashMap<Symbol, u32>) -> (FlatOp, Vec<(Symbol, FlatOp)>){
+    let (e, binds) = anf_val(e, i, bound_vars);
     match op {
-        Op1::Add1 => (FlatOp::Add1(Box::new(e)), binds),
-        Op1::Sub1 => (FlatOp::Sub1(Box::new(e)), binds),
+        Op1::Add1 => (FlatOp::Add1(Box::new(e), span), binds),
+        Op1::Sub1 => (FlatOp::Sub1(Box::new(e), span), binds),
         Op1::IsNum => (FlatOp::IsNum(Box::new(e)), binds),
         Op1::IsBool => (FlatOp::IsBool(Box::new(e)), binds),
         Op1::IsVec => (FlatOp::IsVec(Box::new(e)), binds),
@@ -105,15 +113,15 @@ fn anf_op1(op: &Op1, e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<
     }
 }
 
-fn anf_op2(op: &Op2, e1: &Expr, e2: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u32>) -> (FlatOp, Vec<(Symbol, FlatOp)>) {
-    let (e1, mut binds1) = anf_val(e1, i, in_main, bound_vars);
-    let (e2, mut binds2) = anf_val(e2, i, in_main, bound_vars);
+fn anf_op2(op: &Op2, e1: &Expr, e2: &Expr, span: Span, i: &mut i32, bound_vars: &HashMap<Symbol, u32>) -> (FlatOp, Vec<(Symbol, FlatOp)>) {
+    let (e1, mut binds1) = anf_val(e1, i, bound_vars);
+    let (e2, mut binds2) = anf_val(e2, i, bound_vars);
     binds1.append(&mut binds2);
     match op {
-        Op2::Plus => (FlatOp::Plus(Box::new(e1), Box::new(e2)), binds1),
-        Op2::Minus => (FlatOp::Minus(Box::new(e1), Box::new(e2)), binds1),
-        Op2::Times => (FlatOp::Times(Box::new(e1), Box::new(e2)), binds1),
-        Op2::Divide => (FlatOp::Divide(Box::new(e1), Box::new(e2)), binds1),
+        Op2::Plus => (FlatOp::Plus(Box::new(e1), Box::new(e2), span), binds1),
+        Op2::Minus => (FlatOp::Minus(Box::new(e1), Box::new(e2), span), binds1),
+        Op2::Times => (FlatOp::Times(Box::new(e1), Box::new(e2), span), binds1),
+        Op2::Divide => (FlatOp::Divide(Box::new(e1), Box::new(e2), span), binds1),
         Op2::Equal => (FlatOp::Eq(Box::new(e1), Box::new(e2)), binds1),
         Op2::Greater => (FlatOp::Gt(Box::new(e1), Box::new(e2)), binds1),
         Op2::GreaterEqual => (FlatOp::Ge(Box::new(e1), Box::new(e2)), binds1),
@@ -123,21 +131,17 @@ fn anf_op2(op: &Op2, e1: &Expr, e2: &Expr, i: &mut i32, in_main: bool, bound_var
     
 }
 
-fn anf_expr(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u32>) -> (FlatOp, Vec<(Symbol, FlatOp)>) {
+fn anf_expr(e: &Expr, i: &mut i32, bound_vars: &HashMap<Symbol, u32>) -> (FlatOp, Vec<(Symbol, FlatOp)>) {
     match e {
         Expr::Number(n) => (FlatOp::Val(Box::new(FlatVal::Num(*n))), vec![]),
         Expr::Boolean(b) if *b==true => (FlatOp::Val(Box::new(FlatVal::True)), vec![]),
         Expr::Boolean(_) => (FlatOp::Val(Box::new(FlatVal::False)), vec![]),
-        Expr::Var(s) => (FlatOp::Val(Box::new(FlatVal::Var(get_uniq_name(*s, *bound_vars.get(s).unwrap())))), vec![]),
-        Expr::Let(binds, body) => {
+        Expr::Var(s, _) => (FlatOp::Val(Box::new(FlatVal::Var(get_uniq_name(*s, *bound_vars.get(s).unwrap())))), vec![]),
+        Expr::Let(binds, body, _) => {
             let mut anfbinds = vec![];
             let mut index = 0;
-            let mut seen:HashSet<Symbol> = HashSet::new();
             let mut bind_vars = bound_vars.clone();
             for (s, e) in binds.into_iter() {
-                if !seen.insert(*s) {
-                    raise_duplicate_binding(*s);
-                }
                 let e_vars = bind_vars.clone();
                 let uniq_s;
                 match bind_vars.clone().get(s) {
@@ -151,40 +155,40 @@ fn anf_expr(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u
                     }
                 }
                 if index == binds.len() - 1 {
-                    let (v, mut vbinds) = anf_expr(e, i, in_main, &e_vars);
-                    let (body, mut bbinds) = anf_expr(body, i, in_main, &bind_vars);
+                    let (v, mut vbinds) = anf_expr(e, i, &e_vars);
+                    let (body, mut bbinds) = anf_expr(body, i, &bind_vars);
                     anfbinds.append(&mut vbinds);
                     anfbinds.push((uniq_s, v));
                     anfbinds.append(&mut bbinds);
                     return (body, anfbinds);
                 }
                 index += 1;
-                let (v, mut vbinds) = anf_expr(e, i, in_main, &e_vars);
+                let (v, mut vbinds) = anf_expr(e, i, &e_vars);
                 anfbinds.append(&mut vbinds);
                 anfbinds.push((uniq_s, v));
             }
             panic!("empty let")
         },
-        Expr::UnOp(op, e) => anf_op1(op, e, i, in_main, bound_vars),
-        Expr::BinOp(op, e1, e2) => anf_op2(op, e1, e2, i, in_main, bound_vars),
+        Expr::UnOp(op, e, span) => anf_op1(op, e, *span, i, bound_vars),
+        Expr::BinOp(op, e1, e2, span) => anf_op2(op, e1, e2, *span, i, bound_vars),
         Expr::If(e1, e2, e3) => {
-            let (e1, binds1) = anf_val(e1, i, in_main, bound_vars);
-            let e2 = anf_block(e2, i, in_main, bound_vars);
-            let e3 = anf_block(e3, i, in_main, bound_vars);
+            let (e1, binds1) = anf_val(e1, i, bound_vars);
+            let e2 = anf_block(e2, i, bound_vars);
+            let e3 = anf_block(e3, i, bound_vars);
             (FlatOp::If(Box::new(e1), Box::new(e2), Box::new(e3)), binds1)
         },
-        Expr::Loop(e) => (FlatOp::Loop(Box::new(anf_block(e, i, in_main, bound_vars))), vec![]),
-        Expr::Break(e) => {
-            let (e, binds) = anf_val(e, i, in_main, bound_vars);
+        Expr::Loop(e) => (FlatOp::Loop(Box::new(anf_block(e, i, bound_vars))), vec![]),
+        Expr::Break(e, _) => {
+            let (e, binds) = anf_val(e, i, bound_vars);
             (FlatOp::Break(Box::new(e)), binds)
         },
-        Expr::Set(x, e) => {
-            let (e, binds) = anf_val(e, i, in_main, bound_vars);
+        Expr::Set(x, e, _) => {
+            let (e, binds) = anf_val(e, i, bound_vars);
             (FlatOp::Set(get_uniq_name(x.clone(), *bound_vars.get(x).unwrap()), Box::new(e)), binds)
         },
         Expr::MakeVec(cnt, val) => {
-            let (c, mut binds1) = anf_val(cnt, i, in_main, bound_vars);
-            let (v, mut binds2) = anf_val(val, i, in_main, bound_vars);
+            let (c, mut binds1) = anf_val(cnt, i, bound_vars);
+            let (v, mut binds2) = anf_val(val, i, bound_vars);
             binds1.append(&mut binds2);
             (FlatOp::MakeVec(Box::new(c), Box::new(v)), binds1)
         },
@@ -192,28 +196,28 @@ fn anf_expr(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u
             let mut binds = vec![];
             let mut flat_vec = vec![];
             for e in es {
-                let (flate, mut tmpbind) = anf_val(e, i, in_main, bound_vars);
+                let (flate, mut tmpbind) = anf_val(e, i, bound_vars);
                 binds.append(&mut tmpbind);
                 flat_vec.push(flate);
             }
             (FlatOp::Vec(flat_vec), binds)
         },
         Expr::VecSet(vec, ind, val) => {
-            let (vc, mut binds1) = anf_val(vec, i, in_main, bound_vars);
-            let (id, mut binds2) = anf_val(ind, i, in_main, bound_vars);
-            let (vl, mut binds3) = anf_val(val, i, in_main, bound_vars);
+            let (vc, mut binds1) = anf_val(vec, i, bound_vars);
+            let (id, mut binds2) = anf_val(ind, i, bound_vars);
+            let (vl, mut binds3) = anf_val(val, i, bound_vars);
             binds1.append(&mut binds2);
             binds1.append(&mut binds3);
             (FlatOp::VecSet(Box::new(vc), Box::new(id), Box::new(vl)), binds1)
         },
         Expr::VecGet(vec, ind) => {
-            let (vc, mut binds1) = anf_val(vec, i, in_main, bound_vars);
-            let (id, mut binds2) = anf_val(ind, i, in_main, bound_vars);
+            let (vc, mut binds1) = anf_val(vec, i, bound_vars);
+            let (id, mut binds2) = anf_val(ind, i, bound_vars);
             binds1.append(&mut binds2);
             (FlatOp::VecGet(Box::new(vc), Box::new(id)), binds1)
         },
         Expr::VecLen(vec) => {
-            let (vc, binds1) = anf_val(vec, i, in_main, bound_vars);
+            let (vc, binds1) = anf_val(vec, i, bound_vars);
             (FlatOp::VecLen(Box::new(vc)), binds1)
         },
         Expr::Block(vec) => {
@@ -221,51 +225,69 @@ fn anf_expr(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u
             let mut index = 0;
             for e in vec {
                 if index == vec.len() - 1 {
-                    let (e, mut ebinds) = anf_expr(e, i, in_main, bound_vars);
+                    let (e, mut ebinds) = anf_expr(e, i, bound_vars);
                     binds.append(&mut ebinds);
                     return (e, binds);
                 }
                 index += 1;
-                let (e, mut ebinds) = anf_expr(e, i, in_main, bound_vars);
+                let (e, mut ebinds) = anf_expr(e, i, bound_vars);
                 let tmp = new_label(i, "%block_unused_");
                 binds.append(&mut ebinds);
                 binds.push((tmp.clone(), e));
             }
             panic!("Empty block")
         },
-        Expr::Call(name, args) => {
+        Expr::Call(name, args, _) => {
             let mut binds = vec![];
             let mut aargs = vec![];
             for arg in args {
-                let (e, mut ebind) = anf_val(arg, i, in_main, bound_vars);
+                let (e, mut ebind) = anf_val(arg, i, bound_vars);
                 binds.append(&mut ebind);
                 aargs.push(e);
             }
             (FlatOp::Call(name.clone(), aargs), binds)
         },
-        Expr::Input if !in_main => panic!("cannot use input inside funciton definition"),
-        Expr::Input => (FlatOp::Input, vec![]),
+        Expr::Lambda(params, body, _) => {
+            let mut body_vars = bound_vars.clone();
+            let mut uniq_params = vec![];
+            for p in params {
+                let idx = match bound_vars.get(p) {
+                    Some(idx) => idx+1,
+                    None => 0,
+                };
+                body_vars = body_vars.update(*p, idx);
+                uniq_params.push(get_uniq_name(*p, idx));
+            }
+            (FlatOp::Lambda(uniq_params, Box::new(anf_block(body, i, &body_vars))), vec![])
+        },
+        Expr::CallIndirect(fun, args, _) => {
+            let (f, mut binds) = anf_val(fun, i, bound_vars);
+            let mut aargs = vec![];
+            for arg in args {
+                let (e, mut ebind) = anf_val(arg, i, bound_vars);
+                binds.append(&mut ebind);
+                aargs.push(e);
+            }
+            (FlatOp::CallIndirect(Box::new(f), aargs), binds)
+        },
+        Expr::Input(_) => (FlatOp::Input, vec![]),
         Expr::Nil => (FlatOp::Nil, vec![]),
         Expr::PrintStack => (FlatOp::PrintStack, vec![]),
         Expr::Gc => (FlatOp::Gc, vec![]),
     }
 }
 
-fn anf_block(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol, u32>) -> FlatBlock {
+fn anf_block(e: &Expr, i: &mut i32, bound_vars: &HashMap<Symbol, u32>) -> FlatBlock {
     match e {
-        Expr::Let(binds, body) => {
+        Expr::Let(binds, body, _) => {
             let mut body_vars = bound_vars.clone();
-            let mut seen:HashSet<Symbol> = HashSet::new();
             for (s, _) in binds.into_iter() { // reserve names in body
-                if !seen.insert(*s) {
-                    raise_duplicate_binding(*s);
-                }
                 match body_vars.get(s) {
                     Some(idx) => body_vars = body_vars.update(s.clone(), idx+1),
                     None => body_vars = body_vars.update(s.clone(), 0),
                 }
             }
-            let mut body = anf_block(body, i, in_main, &body_vars);
+            let mut body = anf_block(body, i, &body_vars);
             let mut bind_vars = body_vars.clone();
             for (s, e) in binds.into_iter().rev() {
                 let uniq_s;
@@ -282,7 +304,7 @@ fn anf_block(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol,
                     None => panic!("shouldn't happen")
                 }
                 let e_vars = bind_vars.clone();
-                let (v, binds1) = anf_expr(e, i, in_main, &e_vars);
+                let (v, binds1) = anf_expr(e, i, &e_vars);
                 body = FlatBlock::Let(uniq_s, Box::new(v), Box::new(body));
 
                 for (name, val) in binds1.into_iter().rev() {
@@ -294,13 +316,13 @@ fn anf_block(e: &Expr, i: &mut i32, in_main: bool, bound_vars: &HashMap<Symbol,
         Expr::Block(vec) => {
             let mut blocks = vec![];
             for e in vec {
-                let e = anf_block(e, i, in_main, bound_vars);
+                let e = anf_block(e, i, bound_vars);
                 blocks.push(e);
             }
             FlatBlock::Block(blocks)
         }
         _ => {
-            let (op, binds) = anf_expr(e, i, in_main, bound_vars);
+            let (op, binds) = anf_expr(e, i, bound_vars);
             let mut block = FlatBlock::Op(Box::new(op));
             for (x, v) in binds.into_iter().rev() {
                 block = FlatBlock::Let(x, Box::new(v), Box::new(block));
@@ -318,33 +340,122 @@ fn anf_definition(e: &FunDecl) -> FlatDefinition {
         var_binds = var_binds.update(*p, 0);
         newp.push(get_uniq_name(p.clone(), 0));
     }
-    FlatDefinition { name: e.name, args: newp, body: anf_block(&e.body, &mut i, false, &var_binds) }
+    FlatDefinition { name: e.name, args: newp, body: anf_block(&e.body, &mut i, &var_binds) }
 }
 
+/// Flattens a program that passed `check::check_program`. Lambdas are lifted into definitions of
+/// their own, so the result only contains `MakeClosure`s.
 pub fn anf_program(p: &Prog) -> FlatProgram {
     let mut defs = vec![];
+    let mut lifted = vec![];
     for d in &p.funs {
-        defs.push(anf_definition(d));
+        let def = anf_definition(d);
+        let body = lift_lambdas_block(def.body, &mut lifted);
+        defs.push(FlatDefinition { body, ..def });
     }
-    check_duplicate_functions(&defs);
     let mut i = 0;
-    let main = anf_block(&p.main, &mut i, true, &HashMap::new());
+    let main = anf_block(&p.main, &mut i, &HashMap::new());
+    let main = lift_lambdas_block(main, &mut lifted);
+    defs.append(&mut lifted);
     FlatProgram { main, defs }
 }
 
-fn raise_duplicate_binding(id: Symbol) {
-    panic!("duplicate binding {id}");
+/// Replaces every lambda in `b` by a `MakeClosure` of a new definition pushed to `lifted`. The
+/// definition takes the closure itself as an extra first argument and starts by loading the free
+/// variables of the lambda out of it. Inner lambdas are lifted first, so the values they capture
+/// count as free variables of the enclosing lambda.
+fn lift_lambdas_block(b: FlatBlock, lifted: &mut Vec<FlatDefinition>) -> FlatBlock {
+    match b {
+        FlatBlock::Let(x, op, body) => {
+            let op = lift_lambdas_op(*op, lifted);
+            FlatBlock::Let(x, Box::new(op), Box::new(lift_lambdas_block(*body, lifted)))
+        }
+        FlatBlock::Block(bs) => FlatBlock::Block(bs.into_iter().map(|b| lift_lambdas_block(b, lifted)).collect()),
+        FlatBlock::Op(op) => FlatBlock::Op(Box::new(lift_lambdas_op(*op, lifted))),
+    }
+}
+
+fn lift_lambdas_op(op: FlatOp, lifted: &mut Vec<FlatDefinition>) -> FlatOp {
+    match op {
+        FlatOp::If(v, b1, b2) => FlatOp::If(v, Box::new(lift_lambdas_block(*b1, lifted)), Box::new(lift_lambdas_block(*b2, lifted))),
+        FlatOp::Loop(b) => FlatOp::Loop(Box::new(lift_lambdas_block(*b, lifted))),
+        FlatOp::Lambda(params, body) => {
+            let mut body = lift_lambdas_block(*body, lifted);
+            let mut fvs = vec![];
+            free_vars_block(&body, &params.iter().copied().collect(), &mut fvs);
+
+            let name = Symbol::new(format!("_lambda_{}", lifted.len()));
+            let env = Symbol::new("%env");
+            for (idx, fv) in fvs.iter().enumerate().rev() {
+                let get = FlatOp::ClosureGet(Box::new(FlatVal::Var(env)), idx);
+                body = FlatBlock::Let(*fv, Box::new(get), Box::new(body));
+            }
+            let arity = params.len();
+            let mut args = vec![env];
+            args.extend(params);
+            lifted.push(FlatDefinition { name, args, body });
+            FlatOp::MakeClosure(name, arity, fvs.into_iter().map(FlatVal::Var).collect())
+        }
+        op => op,
+    }
+}
+
+/// Appends the variables used in `b` but not bound in it (or in `bound`) to `fvs`, in order of
+/// first use.
+fn free_vars_block(b: &FlatBlock, bound: &HashSet<Symbol>, fvs: &mut Vec<Symbol>) {
+    match b {
+        FlatBlock::Let(x, op, body) => {
+            free_vars_op(op, bound, fvs);
+            free_vars_block(body, &bound.update(*x), fvs);
+        }
+        FlatBlock::Block(bs) => {
+            for b in bs {
+                free_vars_block(b, bound, fvs);
+            }
+        }
+        FlatBlock::Op(op) => free_vars_op(op, bound, fvs),
+    }
 }
 
-fn check_duplicate_functions(defs: &Vec<FlatDefinition>) {
-    let mut seen: HashSet<Symbol> = HashSet::new();
-    for d in defs {
-        if !seen.insert(d.name) {
-            panic!("duplicate function name {}",d.name);
+fn free_vars_op(op: &FlatOp, bound: &HashSet<Symbol>, fvs: &mut Vec<Symbol>) {
+    let vals: Vec<&FlatVal> = match op {
+        FlatOp::Add1(v, _) | FlatOp::Sub1(v, _) | FlatOp::IsNum(v) | FlatOp::IsBool(v) | FlatOp::IsVec(v) |
+        FlatOp::Print(v) | FlatOp::VecLen(v) | FlatOp::Break(v) | FlatOp::Val(v) | FlatOp::ClosureGet(v, _) => vec![v],
+        FlatOp::Plus(v1, v2, _) | FlatOp::Minus(v1, v2, _) | FlatOp::Times(v1, v2, _) | FlatOp::Divide(v1, v2, _) |
+        FlatOp::Eq(v1, v2) | FlatOp::Gt(v1, v2) | FlatOp::Ge(v1, v2) | FlatOp::Lt(v1, v2) | FlatOp::Le(v1, v2) |
+        FlatOp::MakeVec(v1, v2) | FlatOp::VecGet(v1, v2) => vec![v1, v2],
+        FlatOp::VecSet(v1, v2, v3) => vec![v1, v2, v3],
+        FlatOp::Set(x, v) => {
+            free_var(x, bound, fvs);
+            vec![v]
+        }
+        FlatOp::Call(_, vs) | FlatOp::Vec(vs) | FlatOp::MakeClosure(_, _, vs) => vs.iter().collect(),
+        FlatOp::CallIndirect(f, vs) => std::iter::once(&**f).chain(vs).collect(),
+        FlatOp::If(v, b1, b2) => {
+            free_vars_block(b1, bound, fvs);
+            free_vars_block(b2, bound, fvs);
+            vec![v]
+        }
+        FlatOp::Loop(b) => {
+            free_vars_block(b, bound, fvs);
+            vec![]
+        }
+        FlatOp::Lambda(..) => unreachable!("inner lambdas are lifted first"),
+        FlatOp::Input | FlatOp::Nil | FlatOp::PrintStack | FlatOp::Gc => vec![],
+    };
+    for v in vals {
+        if let FlatVal::Var(x) = v {
+            free_var(x, bound, fvs);
         }
     }
 }
 
+fn free_var(x: &Symbol, bound: &HashSet<Symbol>, fvs: &mut Vec<Symbol>) {
+    if !bound.contains(x) && !fvs.contains(x) {
+        fvs.push(*x);
+    }
+}
+
 /// Takes a program and returns a string of the program as an s-expression; uses
 /// helper functions expr_to_string and val_to_string
 fn block_to_string(e: &FlatBlock) -> String {
@@ -383,10 +494,10 @@ pub fn flatprogram_to_string(e: &FlatProgram) -> String {
 
 fn op_to_string(e: &FlatOp) -> String {
     match e {
-        FlatOp::Add1(e) => format!("(add1 {})", val_to_string(e)),
-        FlatOp::Sub1(e) => format!("(sub1 {})", val_to_string(e)),
-        FlatOp::Plus(e1, e2) => format!("(+ {} {})", val_to_string(e1), val_to_string(e2)),
-        FlatOp::Minus(e1, e2) => format!("(- {} {})", val_to_string(e1), val_to_string(e2)),
+        FlatOp::Add1(e, _) => format!("(add1 {})", val_to_string(e)),
+        FlatOp::Sub1(e, _) => format!("(sub1 {})", val_to_string(e)),
+        FlatOp::Plus(e1, e2, _) => format!("(+ {} {})", val_to_string(e1), val_to_string(e2)),
+        FlatOp::Minus(e1, e2, _) => format!("(- {} {})", val_to_string(e1), val_to_string(e2)),
         //FlatOp::Pair(e1, e2) => format!("(pair {} {})", val_to_string(e1), val_to_string(e2)),
         FlatOp::Print(e) => format!("(print {})", val_to_string(e)),
         // FlatOp::SetFst(e1, e2) => format!("(set-fst! {} {})", val_to_string(e1), val_to_string(e2)),
@@ -409,8 +520,8 @@ fn op_to_string(e: &FlatOp) -> String {
         ),
         FlatOp::Loop(e) => format!("(loop {})", block_to_string(e)),
         FlatOp::Val(v) => val_to_string(v),
-        FlatOp::Times(e1, e2) => format!("(* {} {})", val_to_string(e1), val_to_string(e2)),
-        FlatOp::Divide(e1, e2) => format!("(/ {} {})", val_to_string(e1), val_to_string(e2)),
+        FlatOp::Times(e1, e2, _) => format!("(* {} {})", val_to_string(e1), val_to_string(e2)),
+        FlatOp::Divide(e1, e2, _) => format!("(/ {} {})", val_to_string(e1), val_to_string(e2)),
         FlatOp::Gt(e1, e2) => format!("(> {} {})", val_to_string(e1), val_to_string(e2)),
         FlatOp::Ge(e1, e2) => format!("(>= {} {})", val_to_string(e1), val_to_string(e2)),
         FlatOp::Le(e1, e2) => format!("(<= {} {})", val_to_string(e1), val_to_string(e2)),
@@ -424,6 +535,25 @@ fn op_to_string(e: &FlatOp) -> String {
             }
             return format!("{})",s);
         },
+        FlatOp::Lambda(params, body) => {
+            let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+            format!("(fn ({}) {})", params.join(" "), block_to_string(body))
+        },
+        FlatOp::MakeClosure(nm, arity, fvs) => {
+            let mut s = format!("(make-closure {}/{}", nm, arity);
+            for fv in fvs {
+                s = format!("{} {}", s, val_to_string(fv));
+            }
+            return format!("{})",s);
+        },
+        FlatOp::CallIndirect(f, args) => {
+            let mut s = format!("(call-closure {}", val_to_string(f));
+            for arg in args {
+                s = format!("{} {}", s, val_to_string(arg));
+            }
+            return format!("{})",s);
+        },
+        FlatOp::ClosureGet(v, idx) => format!("(closure-get {} {})", val_to_string(v), idx),
         FlatOp::MakeVec(sz, v) => format!("(make-vec {} {})", val_to_string(sz), val_to_string(v)),
         FlatOp::Vec(es) => {
             let mut s = "(vec".to_string();
@@ -145,6 +145,7 @@ pub enum Instr {
     Label(String),
 
     Call(String),
+    CallReg(Reg),
     Ret,
 
     Jmp(String),
@@ -163,6 +164,8 @@ pub enum Instr {
     Jno(String), // jump if last arith operation didn't overflow
 
     Lea(Reg, MemRef),
+    /// Loads the address of a label, relative to `rip`
+    LeaLabel(Reg, String),
     Rep(StrOp),
     Cqo,
 
@@ -223,6 +226,15 @@ impl From<Loc> for Arg32 {
     }
 }
 
+impl From<Loc> for Arg64 {
+    fn from(loc: Loc) -> Self {
+        match loc {
+            Loc::Reg(r) => Arg64::Reg(r),
+            Loc::Mem(m) => Arg64::Mem(m),
+        }
+    }
+}
+
 impl From<Arg32> for Arg64 {
     fn from(arg: Arg32) -> Self {
         match arg {
@@ -339,6 +351,7 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Label(s) => format!("{}:", s),
 
         Instr::Call(s) => format!("  call {s}"),
+        Instr::CallReg(reg) => format!("  call {}", reg_to_string(*reg)),
         Instr::Ret => format!("  ret"),
         Instr::Jmp(s) => format!("  jmp {s}"),
         Instr::Je(s) => format!("  je {s}"),
@@ -381,6 +394,7 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Lea(reg, mem) => {
             format!("  lea {}, {}", reg_to_string(*reg), mem_ref_to_string(*mem))
         }
+        Instr::LeaLabel(reg, label) => format!("  lea {}, [rel {label}]", reg_to_string(*reg)),
         Instr::Rep(op) => format!("  rep {}", str_op_to_string(*op)),
         Instr::Cqo => format!("  cqo"),
     }
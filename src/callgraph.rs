@@ -0,0 +1,128 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::{
+    ir::{Block, IRExpr, Prog, Step},
+    syntax::Symbol,
+};
+
+/// The functions every function body (and the main one) refers to: the ones it calls directly
+/// and the ones it turns into closures, which may then be called from anywhere through
+/// `CallIndirect`.
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    /// Functions in the order they are defined
+    pub funs: Vec<Symbol>,
+    pub main: Refs,
+    pub refs: HashMap<Symbol, Refs>,
+}
+
+/// What one body refers to, sorted by name
+#[derive(Debug, Clone, Default)]
+pub struct Refs {
+    pub calls: Vec<Symbol>,
+    pub closures: Vec<Symbol>,
+}
+
+impl Refs {
+    fn of_block(b: &Block) -> Refs {
+        let mut refs = Refs::default();
+        for step in &b.steps {
+            match step {
+                Step::Set(_, IRExpr::Call(f, _)) | Step::Do(IRExpr::Call(f, _)) => {
+                    refs.calls.push(*f)
+                }
+                Step::Set(_, IRExpr::MakeClosure(f, _, _))
+                | Step::Do(IRExpr::MakeClosure(f, _, _)) => refs.closures.push(*f),
+                _ => (),
+            }
+        }
+        for funs in [&mut refs.calls, &mut refs.closures] {
+            funs.sort_by_key(|f| f.to_string());
+            funs.dedup();
+        }
+        refs
+    }
+}
+
+impl CallGraph {
+    pub fn new(p: &Prog) -> CallGraph {
+        CallGraph {
+            funs: p.defs.iter().map(|def| def.name).collect(),
+            main: Refs::of_block(&p.main),
+            refs: p
+                .defs
+                .iter()
+                .map(|def| (def.name, Refs::of_block(&def.body)))
+                .collect(),
+        }
+    }
+
+    /// Functions that may be called with arguments the program doesn't show, from a closure
+    pub fn escaping(&self) -> HashSet<Symbol> {
+        std::iter::once(&self.main)
+            .chain(self.refs.values())
+            .flat_map(|refs| refs.closures.iter().copied())
+            .collect()
+    }
+
+    /// Whether calling `from` can lead to a direct call to `to`
+    pub fn reaches(&self, from: Symbol, to: Symbol) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = self.refs[&from].calls.clone();
+        while let Some(f) = stack.pop() {
+            if f == to {
+                return true;
+            }
+            if seen.insert(f) {
+                stack.extend(&self.refs[&f].calls);
+            }
+        }
+        false
+    }
+
+    /// Every function, each one after the functions it calls except where they call back into
+    /// it
+    pub fn callees_first(&self) -> Vec<Symbol> {
+        let mut order = vec![];
+        let mut visited = HashSet::new();
+        for f in &self.funs {
+            self.visit(*f, &mut visited, &mut order);
+        }
+        order
+    }
+
+    fn visit(&self, f: Symbol, visited: &mut HashSet<Symbol>, order: &mut Vec<Symbol>) {
+        if !visited.insert(f) {
+            return;
+        }
+        for g in &self.refs[&f].calls {
+            self.visit(*g, visited, order);
+        }
+        order.push(f);
+    }
+}
+
+/// One line per body, e.g. `main -> fact, sq, closure adder`
+impl fmt::Display for CallGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = |f: &mut fmt::Formatter<'_>, name: &str, refs: &Refs| {
+            let targets: Vec<String> = refs
+                .calls
+                .iter()
+                .map(|g| g.to_string())
+                .chain(refs.closures.iter().map(|g| format!("closure {g}")))
+                .collect();
+            if targets.is_empty() {
+                writeln!(f, "{name} ->")
+            } else {
+                writeln!(f, "{name} -> {}", targets.join(", "))
+            }
+        };
+        line(f, "main", &self.main)?;
+        for fun in &self.funs {
+            line(f, &fun.to_string(), &self.refs[fun])?;
+        }
+        Ok(())
+    }
+}
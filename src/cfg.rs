@@ -0,0 +1,369 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ir::{Block, Def, Step},
+    syntax::Symbol,
+};
+
+/// A maximal run of steps that is only entered at the top and only left at the bottom. The first
+/// step is the label of the block (if it has one) and only the last step may be an `If` or a
+/// `Goto`.
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub steps: Vec<Step>,
+    pub succs: Vec<usize>,
+    pub preds: Vec<usize>,
+}
+
+/// Control-flow graph of a function body.
+///
+/// Blocks are kept in layout order, so a block without a jump at the end falls through to the
+/// next one. The last block is a synthetic, empty exit block every returning block flows to.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub entry: usize,
+    pub exit: usize,
+}
+
+/// A natural loop: the blocks that can reach a back edge to the header without going through
+/// it. The header dominates all of them.
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub header: usize,
+    /// Blocks of the loop, the header included
+    pub body: HashSet<usize>,
+}
+
+impl BasicBlock {
+    fn new(steps: Vec<Step>) -> BasicBlock {
+        BasicBlock {
+            steps,
+            succs: vec![],
+            preds: vec![],
+        }
+    }
+
+    pub fn label(&self) -> Option<Symbol> {
+        match self.steps.first() {
+            Some(Step::Label(l)) => Some(*l),
+            _ => None,
+        }
+    }
+}
+
+impl Cfg {
+    pub fn from_block(b: &Block) -> Cfg {
+        let mut blocks = vec![];
+        let mut current = vec![];
+        for step in &b.steps {
+            if matches!(step, Step::Label(_)) && !current.is_empty() {
+                blocks.push(BasicBlock::new(std::mem::take(&mut current)));
+            }
+            current.push(step.clone());
+            if matches!(step, Step::If(..) | Step::Goto(_)) {
+                blocks.push(BasicBlock::new(std::mem::take(&mut current)));
+            }
+        }
+        if !current.is_empty() || blocks.is_empty() {
+            blocks.push(BasicBlock::new(current));
+        }
+        blocks.push(BasicBlock::new(vec![]));
+        let mut cfg = Cfg {
+            exit: blocks.len() - 1,
+            blocks,
+            entry: 0,
+        };
+        cfg.link();
+        cfg
+    }
+
+    pub fn from_def(d: &Def) -> Cfg {
+        Cfg::from_block(&d.body)
+    }
+
+    pub fn to_block(&self) -> Block {
+        Block {
+            steps: self
+                .blocks
+                .iter()
+                .flat_map(|b| b.steps.iter().cloned())
+                .collect(),
+        }
+    }
+
+    pub fn to_def(&self, name: Symbol, args: Vec<Symbol>) -> Def {
+        Def {
+            name,
+            args,
+            body: self.to_block(),
+        }
+    }
+
+    /// Recomputes the successor and predecessor edges from the steps of the blocks, to be called
+    /// after the jumps at the end of the blocks (or the blocks themselves) were changed.
+    pub fn link(&mut self) {
+        let labels: HashMap<Symbol, usize> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, b)| b.label().map(|l| (l, i)))
+            .collect();
+        for i in 0..self.blocks.len() {
+            let succs = if i == self.exit {
+                vec![]
+            } else {
+                match self.blocks[i].steps.last() {
+                    Some(Step::Goto(l)) => vec![labels[l]],
+                    Some(Step::If(_, thn, els)) => vec![labels[thn], labels[els]],
+                    _ => vec![i + 1],
+                }
+            };
+            self.blocks[i].succs = succs;
+            self.blocks[i].preds.clear();
+        }
+        for i in 0..self.blocks.len() {
+            for s in self.blocks[i].succs.clone() {
+                if !self.blocks[s].preds.contains(&i) {
+                    self.blocks[s].preds.push(i);
+                }
+            }
+        }
+    }
+
+    /// Blocks reachable from the entry; the exit block is always kept
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = vec![self.entry];
+        while let Some(b) = stack.pop() {
+            if !seen[b] {
+                seen[b] = true;
+                stack.extend(&self.blocks[b].succs);
+            }
+        }
+        seen[self.exit] = true;
+        seen
+    }
+
+    /// Drops the blocks that can't be reached from the entry, returns whether any was removed
+    pub fn remove_unreachable(&mut self) -> bool {
+        let reachable = self.reachable();
+        if reachable.iter().all(|r| *r) {
+            return false;
+        }
+        let mut i = 0;
+        self.blocks.retain(|_| {
+            i += 1;
+            reachable[i - 1]
+        });
+        self.exit = self.blocks.len() - 1;
+        self.link();
+        true
+    }
+
+    /// Blocks in reverse postorder of a depth first walk from the entry, i.e. every block comes
+    /// before its successors except along back edges. Unreachable blocks are left out.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = vec![];
+        let mut seen = HashSet::new();
+        let mut stack = vec![(self.entry, 0)];
+        seen.insert(self.entry);
+        while let Some((b, next)) = stack.pop() {
+            match self.blocks[b].succs.get(next) {
+                Some(&s) => {
+                    stack.push((b, next + 1));
+                    if seen.insert(s) {
+                        stack.push((s, 0));
+                    }
+                }
+                None => order.push(b),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// Labels used in the body
+    pub fn labels(&self) -> HashSet<Symbol> {
+        self.blocks.iter().filter_map(BasicBlock::label).collect()
+    }
+
+    /// Natural loops of the body given the immediate dominator of every block, one per header,
+    /// inner loops before the loops around them
+    pub fn natural_loops(&self, idom: &[Option<usize>]) -> Vec<Loop> {
+        let dominates = |a: usize, mut b: usize| loop {
+            if a == b {
+                return true;
+            }
+            match idom[b] {
+                Some(d) if d != b => b = d,
+                _ => return false,
+            }
+        };
+        let mut loops: Vec<Loop> = vec![];
+        for b in self.reverse_postorder() {
+            for &h in &self.blocks[b].succs {
+                if !dominates(h, b) {
+                    continue;
+                }
+                let mut body = HashSet::from([h]);
+                let mut stack = vec![b];
+                while let Some(n) = stack.pop() {
+                    if body.insert(n) {
+                        stack.extend(&self.blocks[n].preds);
+                    }
+                }
+                match loops.iter_mut().find(|l| l.header == h) {
+                    Some(l) => l.body.extend(body),
+                    None => loops.push(Loop { header: h, body }),
+                }
+            }
+        }
+        loops.sort_by_key(|l| l.body.len());
+        loops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{step_to_string, IRExpr, Val};
+
+    fn sym(s: &str) -> Symbol {
+        Symbol::new(s)
+    }
+
+    fn label(l: &str) -> Step {
+        Step::Label(sym(l))
+    }
+
+    fn goto(l: &str) -> Step {
+        Step::Goto(sym(l))
+    }
+
+    fn branch(c: &str, thn: &str, els: &str) -> Step {
+        Step::If(Val::Var(sym(c)), sym(thn), sym(els))
+    }
+
+    fn set(x: &str, n: i64) -> Step {
+        Step::Set(sym(x), IRExpr::Val(Val::Num(n)))
+    }
+
+    fn block(steps: Vec<Step>) -> Block {
+        Block { steps }
+    }
+
+    fn edges(cfg: &Cfg) -> Vec<(Vec<usize>, Vec<usize>)> {
+        cfg.blocks
+            .iter()
+            .map(|b| (b.succs.clone(), b.preds.clone()))
+            .collect()
+    }
+
+    fn text(b: &Block) -> Vec<String> {
+        b.steps.iter().map(step_to_string).collect()
+    }
+
+    fn assert_round_trip(b: &Block) {
+        assert_eq!(text(&Cfg::from_block(b).to_block()), text(b));
+    }
+
+    #[test]
+    fn straight_line() {
+        let b = block(vec![set("x", 1), set("y", 2)]);
+        let cfg = Cfg::from_block(&b);
+        assert_eq!((cfg.entry, cfg.exit), (0, 1));
+        assert_eq!(edges(&cfg), vec![(vec![1], vec![]), (vec![], vec![0])]);
+        assert_round_trip(&b);
+    }
+
+    #[test]
+    fn empty_body() {
+        let cfg = Cfg::from_block(&block(vec![]));
+        assert_eq!(cfg.blocks.len(), 2);
+        assert_eq!(edges(&cfg), vec![(vec![1], vec![]), (vec![], vec![0])]);
+    }
+
+    #[test]
+    fn if_then_else() {
+        let b = block(vec![
+            set("c", 1),
+            branch("c", "thn", "els"),
+            label("thn"),
+            set("x", 1),
+            goto("end"),
+            label("els"),
+            set("x", 2),
+            label("end"),
+            set("y", 3),
+        ]);
+        let cfg = Cfg::from_block(&b);
+        assert_eq!(cfg.blocks.len(), 5);
+        assert_eq!(cfg.blocks[2].label(), Some(sym("els")));
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (vec![1, 2], vec![]),
+                (vec![3], vec![0]),
+                // falls through to `end`
+                (vec![3], vec![0]),
+                (vec![4], vec![1, 2]),
+                (vec![], vec![3]),
+            ]
+        );
+        assert_round_trip(&b);
+    }
+
+    #[test]
+    fn loop_back_edge() {
+        let b = block(vec![
+            set("i", 0),
+            label("loop"),
+            branch("c", "body", "done"),
+            label("body"),
+            set("i", 1),
+            goto("loop"),
+            label("done"),
+            set("r", 2),
+        ]);
+        let cfg = Cfg::from_def(&Def {
+            name: sym("f"),
+            args: vec![sym("c")],
+            body: b.clone(),
+        });
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (vec![1], vec![]),
+                (vec![2, 3], vec![0, 2]),
+                (vec![1], vec![1]),
+                (vec![4], vec![1]),
+                (vec![], vec![3]),
+            ]
+        );
+        assert_eq!(cfg.reverse_postorder()[..2], [0, 1]);
+        assert_round_trip(&b);
+    }
+
+    #[test]
+    fn removes_unreachable_blocks() {
+        let b = block(vec![
+            goto("end"),
+            label("dead"),
+            set("x", 1),
+            goto("dead"),
+            label("end"),
+            set("y", 2),
+        ]);
+        let mut cfg = Cfg::from_block(&b);
+        assert_eq!(cfg.reachable(), vec![true, false, true, true]);
+        assert!(cfg.remove_unreachable());
+        assert!(!cfg.remove_unreachable());
+        assert_eq!(cfg.exit, 2);
+        assert_eq!(
+            edges(&cfg),
+            vec![(vec![1], vec![]), (vec![2], vec![0]), (vec![], vec![1])]
+        );
+        assert_eq!(text(&cfg.to_block()), ["goto\tend", "end:", "y\t<- 2"]);
+    }
+}
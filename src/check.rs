@@ -0,0 +1,201 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use crate::{
+    error::{CompileError, Diagnostics, ErrorKind},
+    syntax::{Expr, FunDecl, Prog, Span, Symbol},
+};
+
+/// Semantic checks on a parsed program: every identifier is bound, function names are unique,
+/// calls name a defined function with the right number of arguments, `break` only appears inside
+/// a `loop`, `input` is only used in the main expression and closures do not assign to the
+/// variables they capture.
+///
+/// This runs before any lowering, so both backends (and everything between the AST and the
+/// assembly) can assume a well-formed program. All errors found are reported, not just the first.
+pub fn check_program(prg: &Prog) -> Result<(), Diagnostics> {
+    let mut checker = Checker {
+        funs: HashMap::new(),
+        diags: Diagnostics::new(),
+    };
+    for fun in &prg.funs {
+        match checker.funs.entry(fun.name) {
+            Entry::Occupied(_) => {
+                let err = CompileError::new(ErrorKind::DuplicateFunction(fun.name), fun.span);
+                checker.diags.report(err);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(fun.params.len());
+            }
+        }
+    }
+    for fun in &prg.funs {
+        checker.check_fun(fun);
+    }
+    checker.check_expr(&prg.main, &Scope::main());
+    checker.diags.finish(())
+}
+
+struct Checker {
+    /// Arity of every function, the first definition wins if a name is defined twice
+    funs: HashMap<Symbol, usize>,
+    diags: Diagnostics,
+}
+
+#[derive(Clone)]
+struct Scope {
+    vars: im::HashSet<Symbol>,
+    /// Variables bound outside of the innermost enclosing `fn`
+    captured: im::HashSet<Symbol>,
+    in_loop: bool,
+    in_fun: bool,
+}
+
+impl Scope {
+    fn main() -> Scope {
+        Scope {
+            vars: im::HashSet::new(),
+            captured: im::HashSet::new(),
+            in_loop: false,
+            in_fun: false,
+        }
+    }
+
+    fn bind(&self, x: Symbol) -> Scope {
+        Scope {
+            vars: self.vars.update(x),
+            captured: self.captured.without(&x),
+            ..self.clone()
+        }
+    }
+
+    /// The scope of a lambda body: everything visible so far is captured and the loop (if any)
+    /// is left behind, so `break` cannot escape the closure.
+    fn enter_lambda(&self, params: &[Symbol]) -> Scope {
+        let mut scope = Scope {
+            captured: self.vars.clone(),
+            in_loop: false,
+            in_fun: true,
+            ..self.clone()
+        };
+        for param in params {
+            scope = scope.bind(*param);
+        }
+        scope
+    }
+
+    fn enter_loop(&self) -> Scope {
+        Scope {
+            in_loop: true,
+            ..self.clone()
+        }
+    }
+}
+
+impl Checker {
+    fn check_fun(&mut self, fun: &FunDecl) {
+        self.check_dup_bindings(&fun.params, fun.span);
+        let scope = Scope {
+            vars: fun.params.iter().copied().collect(),
+            captured: im::HashSet::new(),
+            in_loop: false,
+            in_fun: true,
+        };
+        self.check_expr(&fun.body, &scope);
+    }
+
+    fn check_expr(&mut self, e: &Expr, scope: &Scope) {
+        match e {
+            Expr::Number(_) | Expr::Boolean(_) | Expr::Nil | Expr::PrintStack | Expr::Gc => {}
+            Expr::Var(x, span) => self.check_bound(*x, *span, scope),
+            Expr::Input(span) => {
+                if scope.in_fun {
+                    self.report(ErrorKind::InputInFunction, *span);
+                }
+            }
+            Expr::Let(bindings, body, span) => {
+                self.check_dup_bindings(bindings.iter().map(|(x, _)| x), *span);
+                let mut body_scope = scope.clone();
+                for (x, rhs) in bindings {
+                    self.check_expr(rhs, &body_scope);
+                    body_scope = body_scope.bind(*x);
+                }
+                self.check_expr(body, &body_scope);
+            }
+            Expr::Set(x, e, span) => {
+                self.check_bound(*x, *span, scope);
+                if scope.captured.contains(x) {
+                    self.report(ErrorKind::AssignToCaptured(*x), *span);
+                }
+                self.check_expr(e, scope);
+            }
+            Expr::Loop(e) => self.check_expr(e, &scope.enter_loop()),
+            Expr::Break(e, span) => {
+                if !scope.in_loop {
+                    self.report(ErrorKind::BreakOutsideLoop, *span);
+                }
+                self.check_expr(e, scope);
+            }
+            Expr::Call(fun, args, span) => {
+                match self.funs.get(fun) {
+                    None => self.report(ErrorKind::UndefinedFunction(*fun), *span),
+                    Some(&arity) if arity != args.len() => {
+                        let kind = ErrorKind::WrongNumberOfArgs {
+                            fun: *fun,
+                            expected: arity,
+                            got: args.len(),
+                        };
+                        self.report(kind, *span)
+                    }
+                    Some(_) => {}
+                }
+                for arg in args {
+                    self.check_expr(arg, scope);
+                }
+            }
+            Expr::Lambda(params, body, span) => {
+                self.check_dup_bindings(params, *span);
+                self.check_expr(body, &scope.enter_lambda(params));
+            }
+            Expr::CallIndirect(fun, args, _) => {
+                self.check_expr(fun, scope);
+                for arg in args {
+                    self.check_expr(arg, scope);
+                }
+            }
+            Expr::UnOp(_, e, _) | Expr::VecLen(e) => self.check_expr(e, scope),
+            Expr::BinOp(_, e1, e2, _) | Expr::MakeVec(e1, e2) | Expr::VecGet(e1, e2) => {
+                self.check_expr(e1, scope);
+                self.check_expr(e2, scope);
+            }
+            Expr::If(e1, e2, e3) | Expr::VecSet(e1, e2, e3) => {
+                self.check_expr(e1, scope);
+                self.check_expr(e2, scope);
+                self.check_expr(e3, scope);
+            }
+            Expr::Vec(es) | Expr::Block(es) => {
+                for e in es {
+                    self.check_expr(e, scope);
+                }
+            }
+        }
+    }
+
+    fn check_bound(&mut self, x: Symbol, span: Span, scope: &Scope) {
+        if !scope.vars.contains(&x) {
+            self.report(ErrorKind::UnboundIdentifier(x), span);
+        }
+    }
+
+    fn check_dup_bindings<'a>(&mut self, names: impl IntoIterator<Item = &'a Symbol>, span: Span) {
+        let mut seen = HashSet::new();
+        for name in names {
+            if !seen.insert(*name) {
+                self.report(ErrorKind::DuplicateBinding(*name), span);
+            }
+        }
+    }
+
+    fn report(&mut self, kind: ErrorKind, span: Span) {
+        self.diags.report(CompileError::new(kind, span));
+    }
+}
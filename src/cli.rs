@@ -0,0 +1,359 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::Diagnostics;
+use crate::iroptimizer;
+
+pub const USAGE: &str = "\
+usage: forest-flame [options] <input.snek> <output.s>
+
+options:
+  --backend <og|ir|opt>  code generator to use (default: opt)
+                           og   legacy compiler straight from the AST
+                           ir   IR compiler without optimizations
+                           opt  IR compiler after running the optimizer
+  --og, --ir, --opt      shorthands for --backend
+  --emit <kinds>         comma separated list of artifacts to write
+                         (default: asm,anf,ir)
+                           asm     generated assembly, written to <output.s>
+                           anf     flattened program, written to <output.s>.anf
+                           ir      unoptimized IR, written to <output.s>.ir
+                           opt-ir  optimized IR, written to <output.s>.opt.ir
+  --emit-dir <dir>       write intermediate artifacts into <dir> instead of
+                         next to <output.s>
+  --error-limit <n>      report at most <n> errors, 0 for no limit (default: 20)
+  -O0, -O1, -O2          optimization level (default: -O2)
+                           -O0  run no pass
+                           -O1  run the passes that stay within a function
+                           -O2  run every pass
+  --enable <passes>      comma separated passes to run whatever the level
+  --disable <passes>     comma separated passes not to run whatever the level
+                         passes: inline, fold, dce, const-prop, specialize,
+                         gvn, dead-stores, checks, bounds, overflow, licm,
+                         peephole
+  --inline-limit <n>     inline calls to functions of at most <n> IR steps,
+                         0 to turn inlining off (default: 16)
+  --max-iterations <n>   run the optimizer passes at most <n> rounds
+                         (default: 50)
+  -v, --verbose          print what every optimizer pass did to stderr
+  --verify-ir            check the IR after every optimizer pass and stop at
+                         the first one leaving it broken
+  -h, --help             print this message";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// The original compiler in `compiler.rs` working directly on the AST
+    Legacy,
+    /// ANF -> IR -> asm without running any IR passes
+    Ir,
+    /// ANF -> IR -> optimized IR -> asm
+    OptIr,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Artifact {
+    Asm,
+    Anf,
+    Ir,
+    OptIr,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub backend: Backend,
+    pub emit: Vec<Artifact>,
+    pub emit_dir: Option<PathBuf>,
+    /// Maximum number of errors to print, 0 means all of them
+    pub error_limit: usize,
+    /// Passes the optimizer and the code generators run
+    pub opt: iroptimizer::Config,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Compile(Options),
+    Help,
+}
+
+impl Options {
+    pub fn emits(&self, artifact: Artifact) -> bool {
+        self.emit.contains(&artifact)
+    }
+
+    /// Path an artifact should be written to. Assembly always goes to the output path, the
+    /// intermediate forms get an extra extension and optionally live in `emit_dir`.
+    pub fn artifact_path(&self, artifact: Artifact) -> PathBuf {
+        let ext = match artifact {
+            Artifact::Asm => return self.output.clone(),
+            Artifact::Anf => "anf",
+            Artifact::Ir => "ir",
+            Artifact::OptIr => "opt.ir",
+        };
+        let file_name = self
+            .output
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dir = match &self.emit_dir {
+            Some(dir) => dir.as_path(),
+            None => self.output.parent().unwrap_or(Path::new("")),
+        };
+        dir.join(format!("{file_name}.{ext}"))
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut backend = Backend::OptIr;
+    let mut emit = vec![Artifact::Asm, Artifact::Anf, Artifact::Ir];
+    let mut emit_dir = None;
+    let mut error_limit = Diagnostics::DEFAULT_LIMIT;
+    let mut opt = iroptimizer::Config::default();
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--og" => backend = Backend::Legacy,
+            "--ir" => backend = Backend::Ir,
+            "--opt" => backend = Backend::OptIr,
+            "--backend" => backend = parse_backend(flag_value(&mut args, arg)?)?,
+            "--emit" => emit = parse_emit(flag_value(&mut args, arg)?)?,
+            "--emit-dir" => emit_dir = Some(PathBuf::from(flag_value(&mut args, arg)?)),
+            "--error-limit" => {
+                let value = flag_value(&mut args, arg)?;
+                error_limit = value
+                    .parse()
+                    .map_err(|_| format!("invalid error limit `{value}`, expected a number"))?;
+            }
+            "-O0" => opt.level = 0,
+            "-O1" => opt.level = 1,
+            "-O2" => opt.level = 2,
+            "--enable" => opt
+                .enabled
+                .extend(parse_passes(flag_value(&mut args, arg)?)?),
+            "--disable" => opt
+                .disabled
+                .extend(parse_passes(flag_value(&mut args, arg)?)?),
+            "-v" | "--verbose" => opt.verbose = true,
+            "--verify-ir" => opt.verify = true,
+            "--inline-limit" => {
+                let value = flag_value(&mut args, arg)?;
+                opt.inline_limit = value
+                    .parse()
+                    .map_err(|_| format!("invalid inline limit `{value}`, expected a number"))?;
+            }
+            "--max-iterations" => {
+                let value = flag_value(&mut args, arg)?;
+                opt.max_iterations = value
+                    .parse()
+                    .map_err(|_| format!("invalid iteration limit `{value}`, expected a number"))?;
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("unknown option `{flag}`"))
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let [input, output] = &positional[..] else {
+        return Err(format!(
+            "expected an input and an output file, got {} positional argument(s)",
+            positional.len()
+        ));
+    };
+    Ok(Command::Compile(Options {
+        input: PathBuf::from(input),
+        output: PathBuf::from(output),
+        backend,
+        emit,
+        emit_dir,
+        error_limit,
+        opt,
+    }))
+}
+
+fn flag_value<'a>(
+    args: &mut impl Iterator<Item = &'a String>,
+    flag: &str,
+) -> Result<&'a str, String> {
+    args.next()
+        .map(|s| s.as_str())
+        .ok_or_else(|| format!("option `{flag}` requires a value"))
+}
+
+fn parse_backend(s: &str) -> Result<Backend, String> {
+    match s {
+        "og" => Ok(Backend::Legacy),
+        "ir" => Ok(Backend::Ir),
+        "opt" => Ok(Backend::OptIr),
+        _ => Err(format!(
+            "unknown backend `{s}`, expected one of og, ir, opt"
+        )),
+    }
+}
+
+fn parse_emit(s: &str) -> Result<Vec<Artifact>, String> {
+    let mut kinds = vec![];
+    for kind in s.split(',').filter(|k| !k.is_empty()) {
+        let artifact = match kind {
+            "asm" => Artifact::Asm,
+            "anf" => Artifact::Anf,
+            "ir" => Artifact::Ir,
+            "opt-ir" => Artifact::OptIr,
+            _ => {
+                return Err(format!(
+                    "unknown artifact `{kind}`, expected one of asm, anf, ir, opt-ir"
+                ))
+            }
+        };
+        if !kinds.contains(&artifact) {
+            kinds.push(artifact);
+        }
+    }
+    Ok(kinds)
+}
+
+fn parse_passes(s: &str) -> Result<Vec<String>, String> {
+    let names = iroptimizer::pass_names();
+    let mut passes = vec![];
+    for pass in s.split(',').filter(|p| !p.is_empty()) {
+        if !names.contains(&pass) {
+            return Err(format!(
+                "unknown pass `{pass}`, expected one of {}",
+                names.join(", ")
+            ));
+        }
+        passes.push(pass.to_string());
+    }
+    Ok(passes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        parse_args(&args)
+    }
+
+    fn options(args: &str) -> Options {
+        match parse(args) {
+            Ok(Command::Compile(opts)) => opts,
+            other => panic!("expected options for `{args}`, got {other:?}"),
+        }
+    }
+
+    fn error(args: &str) -> String {
+        parse(args).expect_err(args)
+    }
+
+    #[test]
+    fn defaults() {
+        let opts = options("in.snek out.s");
+        assert_eq!(opts.input, PathBuf::from("in.snek"));
+        assert_eq!(opts.output, PathBuf::from("out.s"));
+        assert_eq!(opts.backend, Backend::OptIr);
+        assert_eq!(opts.emit, vec![Artifact::Asm, Artifact::Anf, Artifact::Ir]);
+        assert_eq!(opts.error_limit, Diagnostics::DEFAULT_LIMIT);
+        assert_eq!(opts.opt.level, 2);
+        assert!(!opts.opt.verbose && !opts.opt.verify);
+    }
+
+    #[test]
+    fn help() {
+        assert!(matches!(parse("-h"), Ok(Command::Help)));
+        assert!(matches!(parse("in.snek --help"), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert!(error("").contains("got 0 positional"));
+        assert!(error("in.snek").contains("got 1 positional"));
+        assert!(error("a b c").contains("got 3 positional"));
+        assert_eq!(error("--fast in.snek out.s"), "unknown option `--fast`");
+        assert_eq!(
+            error("in.snek out.s --emit"),
+            "option `--emit` requires a value"
+        );
+        assert!(error("--backend llvm in.snek out.s").starts_with("unknown backend `llvm`"));
+        assert!(error("--error-limit many in.snek out.s").starts_with("invalid error limit"));
+        assert!(error("--inline-limit -1 in.snek out.s").starts_with("invalid inline limit"));
+        assert!(error("--max-iterations x in.snek out.s").starts_with("invalid iteration limit"));
+    }
+
+    #[test]
+    fn backends() {
+        assert_eq!(options("--og in.snek out.s").backend, Backend::Legacy);
+        assert_eq!(options("--ir in.snek out.s").backend, Backend::Ir);
+        assert_eq!(options("--og --opt in.snek out.s").backend, Backend::OptIr);
+        assert_eq!(
+            options("--backend og in.snek out.s").backend,
+            Backend::Legacy
+        );
+        assert_eq!(options("--backend ir in.snek out.s").backend, Backend::Ir);
+        // the last one wins
+        assert_eq!(
+            options("--ir --backend opt in.snek out.s").backend,
+            Backend::OptIr
+        );
+    }
+
+    #[test]
+    fn emit_lists() {
+        let opts = options("--emit opt-ir,asm,opt-ir in.snek out.s");
+        assert_eq!(opts.emit, vec![Artifact::OptIr, Artifact::Asm]);
+        assert!(opts.emits(Artifact::OptIr) && !opts.emits(Artifact::Ir));
+        assert_eq!(options("--emit , in.snek out.s").emit, vec![]);
+        assert!(error("--emit asm,bin in.snek out.s").starts_with("unknown artifact `bin`"));
+    }
+
+    #[test]
+    fn artifact_paths() {
+        let opts = options("in.snek dir/out.s");
+        assert_eq!(
+            opts.artifact_path(Artifact::Asm),
+            PathBuf::from("dir/out.s")
+        );
+        assert_eq!(
+            opts.artifact_path(Artifact::OptIr),
+            PathBuf::from("dir/out.s.opt.ir")
+        );
+        let opts = options("--emit-dir build in.snek dir/out.s");
+        assert_eq!(
+            opts.artifact_path(Artifact::Asm),
+            PathBuf::from("dir/out.s")
+        );
+        assert_eq!(
+            opts.artifact_path(Artifact::Anf),
+            PathBuf::from("build/out.s.anf")
+        );
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(options("-O0 in.snek out.s").opt.level, 0);
+        assert_eq!(options("-O1 in.snek out.s").opt.level, 1);
+        assert_eq!(options("-O0 -O2 in.snek out.s").opt.level, 2);
+        assert_eq!(error("-O3 in.snek out.s"), "unknown option `-O3`");
+    }
+
+    #[test]
+    fn enable_and_disable() {
+        let opts = options("--enable gvn,licm --disable peephole --disable inline in.snek out.s");
+        assert_eq!(opts.opt.enabled, vec!["gvn", "licm"]);
+        assert_eq!(opts.opt.disabled, vec!["peephole", "inline"]);
+        assert!(error("--enable gvn,cse in.snek out.s").starts_with("unknown pass `cse`"));
+    }
+
+    #[test]
+    fn optimizer_settings() {
+        let opts = options("--inline-limit 4 --max-iterations 3 -v --verify-ir in.snek out.s");
+        assert_eq!(opts.opt.inline_limit, 4);
+        assert_eq!(opts.opt.max_iterations, 3);
+        assert!(opts.opt.verbose && opts.opt.verify);
+        assert_eq!(options("--error-limit 0 in.snek out.s").error_limit, 0);
+    }
+}
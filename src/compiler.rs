@@ -1,5 +1,3 @@
-use std::collections::{HashMap, HashSet};
-
 use crate::{
     asm::{
         instrs_to_string, Arg32, Arg64, BinArgs, CMov, Instr, Loc, MemRef, MovArgs, Offset,
@@ -7,14 +5,16 @@ use crate::{
         Reg32,
         StrOp::Stosq,
     },
-    mref,
+    error::{CompileError, Diagnostics, ErrorKind},
+    mref, peephole,
     syntax::{Expr, FunDecl, Op1, Op2, Prog, Symbol},
 };
 
 struct Session {
     tag: u32,
     instrs: Vec<Instr>,
-    funs: HashMap<Symbol, usize>,
+    /// Constructs this backend cannot compile, reported once compilation is over
+    diags: Diagnostics,
 }
 
 const INVALID_ARG: &str = "invalid_argument";
@@ -36,7 +36,6 @@ struct Ctxt<'a> {
     env: im::HashMap<Symbol, MemRef>,
     si: u32,
     curr_lbl: Option<&'a str>,
-    in_fun: bool,
 }
 
 impl<'a> Ctxt<'a> {
@@ -45,7 +44,6 @@ impl<'a> Ctxt<'a> {
             si: 0,
             curr_lbl: None,
             env: im::HashMap::default(),
-            in_fun: false,
         }
     }
 
@@ -59,15 +57,11 @@ impl<'a> Ctxt<'a> {
             si: 0,
             curr_lbl: None,
             env,
-            in_fun: true,
         }
     }
 
     fn lookup(&self, x: Symbol) -> MemRef {
-        *self
-            .env
-            .get(&x)
-            .unwrap_or_else(|| raise_unbound_identifier(x))
+        self.env[&x]
     }
 
     fn set_curr_lbl(&self, lbl: &'a str) -> Ctxt<'a> {
@@ -96,26 +90,33 @@ impl<'a> Ctxt<'a> {
     }
 }
 
-pub fn compile(prg: &Prog) -> String {
-    match fun_arity_map(prg) {
-        Ok(funs) => {
-            let mut sess = Session::new(funs);
-            let locals = depth(&prg.main);
-            sess.compile_funs(&prg.funs);
-            sess.emit_instr(Instr::Label("our_code_starts_here".to_string()));
-            let callee_saved = [Rbp, STACK_BASE, INPUT_REG, HEAP_END, HEAP_PTR];
-            sess.fun_entry(locals, &callee_saved);
-            sess.emit_instrs([
-                Instr::Mov(MovArgs::ToReg(STACK_BASE, Arg64::Reg(Rbp))),
-                Instr::Mov(MovArgs::ToReg(INPUT_REG, Arg64::Reg(Rdi))),
-                Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rsi))),
-                Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
-            ]);
-            sess.compile_expr(&Ctxt::new(), Loc::Reg(Rax), &prg.main);
-            sess.fun_exit(locals, &callee_saved);
-
-            format!(
-                "
+/// Compiles a program that passed `check::check_program`, running the peephole rewrites on the
+/// result if `peephole` is set. Closures are only implemented by the IR backends, programs using
+/// them are rejected.
+pub fn compile(prg: &Prog, peephole: bool) -> Result<String, Diagnostics> {
+    let mut sess = Session::new();
+    let locals = depth(&prg.main);
+    sess.compile_funs(&prg.funs);
+    sess.emit_instr(Instr::Label("our_code_starts_here".to_string()));
+    let callee_saved = [Rbp, STACK_BASE, INPUT_REG, HEAP_END, HEAP_PTR];
+    sess.fun_entry(locals, &callee_saved);
+    sess.emit_instrs([
+        Instr::Mov(MovArgs::ToReg(STACK_BASE, Arg64::Reg(Rbp))),
+        Instr::Mov(MovArgs::ToReg(INPUT_REG, Arg64::Reg(Rdi))),
+        Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rsi))),
+        Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
+    ]);
+    sess.compile_expr(&Ctxt::new(), Loc::Reg(Rax), &prg.main);
+    sess.fun_exit(locals, &callee_saved);
+
+    let instrs = std::mem::take(&mut sess.instrs);
+    let instrs = if peephole {
+        peephole::optimize(&instrs)
+    } else {
+        instrs
+    };
+    let asm = format!(
+        "
 section .text
 extern snek_error
 extern snek_print
@@ -137,20 +138,22 @@ global our_code_starts_here
 {INVALID_SIZE}:
   mov edi, 4
   call snek_error
+section .data
+global snek_stack_maps
+snek_stack_maps:
+  dq 0
 ",
-                instrs_to_string(&sess.instrs)
-            )
-        }
-        Err(dup) => raise_duplicate_function(dup),
-    }
+        instrs_to_string(&instrs)
+    );
+    sess.diags.finish(asm)
 }
 
 impl Session {
-    fn new(funs: HashMap<Symbol, usize>) -> Session {
+    fn new() -> Session {
         Session {
             tag: 0,
             instrs: vec![],
-            funs,
+            diags: Diagnostics::new(),
         }
     }
 
@@ -185,7 +188,6 @@ impl Session {
     }
 
     fn compile_fun(&mut self, fun: &FunDecl) {
-        check_dup_bindings(&fun.params);
         let locals = depth(&fun.body);
         self.emit_instr(Instr::Label(fun_label(fun.name)));
         self.fun_entry(locals, &[Rbp]);
@@ -197,9 +199,8 @@ impl Session {
         match e {
             Expr::Number(n) => self.move_to(dst, n.repr64()),
             Expr::Boolean(b) => self.move_to(dst, b.repr64()),
-            Expr::Var(x) => self.move_to(dst, Arg32::Mem(cx.lookup(*x))),
-            Expr::Let(bindings, body) => {
-                check_dup_bindings(bindings.iter().map(|(id, _)| id));
+            Expr::Var(x, _) => self.move_to(dst, Arg32::Mem(cx.lookup(*x))),
+            Expr::Let(bindings, body, _) => {
                 let mut currcx = cx.clone();
                 for (var, rhs) in bindings {
                     let (nextcx, mem) = currcx.next_local();
@@ -210,8 +211,8 @@ impl Session {
                 self.memset(cx.si, bindings.len() as u32, Reg32::Imm(MEM_SET_VAL));
                 self.move_to(dst, Arg64::Reg(Rax))
             }
-            Expr::UnOp(op, e) => self.compile_un_op(cx, dst, *op, e),
-            Expr::BinOp(op, e1, e2) => self.compile_bin_op(cx, dst, *op, e1, e2),
+            Expr::UnOp(op, e, _) => self.compile_un_op(cx, dst, *op, e),
+            Expr::BinOp(op, e1, e2, _) => self.compile_bin_op(cx, dst, *op, e1, e2),
             Expr::If(e1, e2, e3) => {
                 let tag = self.next_tag();
                 let else_lbl = format!("if_else_{tag}");
@@ -237,15 +238,12 @@ impl Session {
                 self.emit_instrs([Instr::Jmp(loop_start_lbl), Instr::Label(loop_end_lbl)]);
                 self.move_to(dst, Arg64::Reg(Rax));
             }
-            Expr::Break(e) => {
-                if let Some(lbl) = cx.curr_lbl {
-                    self.compile_expr(cx, Loc::Reg(Rax), e);
-                    self.emit_instr(Instr::Jmp(lbl.to_string()));
-                } else {
-                    raise_break_outside_loop()
-                }
+            Expr::Break(e, _) => {
+                let lbl = cx.curr_lbl.expect("break outside loop");
+                self.compile_expr(cx, Loc::Reg(Rax), e);
+                self.emit_instr(Instr::Jmp(lbl.to_string()));
             }
-            Expr::Set(var, e) => {
+            Expr::Set(var, e, _) => {
                 let mem = cx.lookup(*var);
                 self.compile_expr(cx, Loc::Mem(mem), e);
                 self.move_to(dst, Arg32::Mem(mem));
@@ -256,14 +254,7 @@ impl Session {
                 }
                 self.compile_expr(cx, dst, &es[es.len() - 1]);
             }
-            Expr::Call(fun, args) => {
-                let Some(arity) = self.funs.get(fun) else {
-                    return raise_undefined_fun(*fun);
-                };
-                if args.len() != *arity {
-                    raise_wrong_number_of_args(*fun, *arity, args.len());
-                }
-
+            Expr::Call(fun, args, _) => {
                 let mut currcx = cx.clone();
                 for arg in args {
                     let (nextcx, mem) = currcx.next_local();
@@ -274,16 +265,14 @@ impl Session {
                 self.memset(cx.si, args.len() as u32, Reg32::Imm(MEM_SET_VAL));
                 self.move_to(dst, Arg64::Reg(Rax));
             }
+            Expr::Lambda(_, _, span) | Expr::CallIndirect(_, _, span) => {
+                let err = CompileError::new(ErrorKind::Unsupported("closures"), *span);
+                self.diags.report(err);
+            }
             Expr::Nil => {
                 self.move_to(dst, Arg32::Imm(NIL));
             }
-            Expr::Input => {
-                if cx.in_fun {
-                    raise_input_in_fun()
-                } else {
-                    self.move_to(dst, Arg32::Reg(INPUT_REG))
-                }
-            }
+            Expr::Input(_) => self.move_to(dst, Arg32::Reg(INPUT_REG)),
             Expr::MakeVec(size, elem) => {
                 let tag = self.next_tag();
                 let alloc_finish_lbl = format!("make_vec_alloc_finish_{tag}");
@@ -309,8 +298,11 @@ impl Session {
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
                     Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
+                    // no stack map, the GC scans the whole stack
+                    Instr::Mov(MovArgs::ToReg(R9, Arg64::Imm(0))),
                     Instr::Call("snek_try_gc".to_string()),
                     Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
+                    Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
                     Instr::Label(alloc_finish_lbl),
                     // Load size again in %rsi
                     Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Mem(size_mem))),
@@ -355,8 +347,11 @@ impl Session {
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
                     Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
+                    // no stack map, the GC scans the whole stack
+                    Instr::Mov(MovArgs::ToReg(R9, Arg64::Imm(0))),
                     Instr::Call("snek_try_gc".to_string()),
                     Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
+                    Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
                     Instr::Label(vec_alloc_finish_lbl),
                     // Write GC word in HEAP_PTR
                     Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 0), Reg32::Imm(GC_WORD_VAL))),
@@ -449,8 +444,10 @@ impl Session {
                     Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(STACK_BASE))),
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rbp))),
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rsp))),
+                    Instr::Mov(MovArgs::ToReg(R8, Arg64::Imm(0))),
                     Instr::Call("snek_gc".to_string()),
                     Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
+                    Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
                 ]);
                 self.move_to(dst, 0.repr32());
             }
@@ -706,8 +703,8 @@ fn frame_size(locals: u32, calle_saved: &[Reg]) -> u32 {
 
 fn depth(e: &Expr) -> u32 {
     match e {
-        Expr::BinOp(_, e1, e2) => depth(e1).max(depth(e2) + 1),
-        Expr::Let(bindings, e) => bindings
+        Expr::BinOp(_, e1, e2, _) => depth(e1).max(depth(e2) + 1),
+        Expr::Let(bindings, e, _) => bindings
             .iter()
             .enumerate()
             .map(|(i, (_, e))| depth(e) + (i as u32))
@@ -716,9 +713,9 @@ fn depth(e: &Expr) -> u32 {
             .max(depth(e) + bindings.len() as u32),
         Expr::If(e1, e2, e3) => depth(e1).max(depth(e2)).max(depth(e3)),
         Expr::Block(es) => es.iter().map(depth).max().unwrap_or(0),
-        Expr::UnOp(_, e) | Expr::Loop(e) | Expr::Break(e) | Expr::Set(_, e) => depth(e),
+        Expr::UnOp(_, e, _) | Expr::Loop(e) | Expr::Break(e, _) | Expr::Set(_, e, _) => depth(e),
         Expr::MakeVec(size, elem) => depth(size).max(depth(elem) + 1).max(2),
-        Expr::Call(_, es) | Expr::Vec(es) => es
+        Expr::Call(_, es, _) | Expr::Vec(es) => es
             .iter()
             .enumerate()
             .map(|(i, e)| depth(e) + (i as u32))
@@ -729,10 +726,12 @@ fn depth(e: &Expr) -> u32 {
         Expr::VecGet(vec, idx) => depth(vec).max(depth(idx) + 1),
         Expr::PrintStack
         | Expr::Gc
+        | Expr::Lambda(..)
+        | Expr::CallIndirect(..)
         | Expr::VecLen(_)
-        | Expr::Input
+        | Expr::Input(_)
         | Expr::Nil
-        | Expr::Var(_)
+        | Expr::Var(..)
         | Expr::Number(_)
         | Expr::Boolean(_) => 0,
     }
@@ -770,53 +769,6 @@ impl Repr32 for bool {
     }
 }
 
-fn fun_arity_map(prg: &Prog) -> Result<HashMap<Symbol, usize>, Symbol> {
-    let mut map = HashMap::new();
-    for fun in &prg.funs {
-        if map.insert(fun.name, fun.params.len()).is_some() {
-            return Err(fun.name);
-        }
-    }
-    Ok(map)
-}
-
-fn check_dup_bindings<'a>(bindings: impl IntoIterator<Item = &'a Symbol>) {
-    let mut seen = HashSet::new();
-    for name in bindings {
-        if !seen.insert(*name) {
-            raise_duplicate_binding(*name);
-        }
-    }
-}
-
-fn raise_duplicate_binding(id: Symbol) {
-    panic!("duplicate binding {id}");
-}
-
-fn raise_duplicate_function<T>(name: Symbol) -> T {
-    panic!("duplicate function name {name}")
-}
-
-fn raise_unbound_identifier<T>(id: Symbol) -> T {
-    panic!("unbound variable identifier {id}")
-}
-
-fn raise_break_outside_loop() {
-    panic!("break outside loop")
-}
-
-fn raise_input_in_fun<T>() -> T {
-    panic!("cannot use input inside function definition")
-}
-
-fn raise_undefined_fun(fun: Symbol) {
-    panic!("function {fun} not defined")
-}
-
-fn raise_wrong_number_of_args(fun: Symbol, expected: usize, got: usize) {
-    panic!("function {fun} takes {expected} arguments but {got} were supplied")
-}
-
 fn fun_label(fun: Symbol) -> String {
     format!("snek_fun_{}", fun.replace("-", "_"))
 }
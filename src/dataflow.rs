@@ -0,0 +1,896 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{
+    cfg::Cfg,
+    ir::{Block, CheckType, IRExpr, Step, Val},
+    syntax::Symbol,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// A dataflow problem over the steps of a function body: facts form a lattice with `bottom` as
+/// its least element and `join` as the least upper bound, and `transfer` must be monotone.
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+
+    const DIRECTION: Direction;
+
+    /// Fact on entry to the body for a forward analysis, on exit from it for a backward one
+    fn boundary(&self) -> Self::Fact;
+
+    /// Starting fact of every other block
+    fn bottom(&self) -> Self::Fact;
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact);
+
+    /// Updates `fact` across the step at index `at` of the body. Forward analyses get the fact
+    /// before the step and leave the one after it, backward analyses the other way around.
+    fn transfer(&self, at: usize, step: &Step, fact: &mut Self::Fact);
+
+    /// Forward analyses only: updates the fact at the end of a block ending in `if cond` along
+    /// the branch taken when `cond` is `taken`, before it is joined into the target block
+    fn branch(&self, _cond: &Val, _taken: bool, _fact: &mut Self::Fact) {}
+
+    /// Forward analyses only: updates the fact at the end of the block labelled `from` as it
+    /// flows into the block labelled `to`, after `branch`, e.g. to give the phis of `to` their
+    /// operand from `from`
+    fn edge(&self, _from: Symbol, _to: Symbol, _fact: &mut Self::Fact) {}
+
+    /// Forward analyses only: combines the fact previously at the top of a loop with the new
+    /// one, so lattices with infinite ascending chains can jump ahead and still terminate
+    fn widen(&self, _old: &Self::Fact, new: Self::Fact) -> Self::Fact {
+        new
+    }
+}
+
+/// Solution of an analysis, facts are in program order whatever the direction
+pub struct Results<F> {
+    /// Fact at the top of every block
+    pub block_in: Vec<F>,
+    /// Fact at the bottom of every block
+    pub block_out: Vec<F>,
+}
+
+/// Facts before and after every step of the body, in layout order
+pub struct StepFacts<F> {
+    pub before: Vec<F>,
+    pub after: Vec<F>,
+}
+
+/// Solves `a` over `cfg` with a worklist, until no block's fact changes
+pub fn solve<A: Analysis>(cfg: &Cfg, a: &A) -> Results<A::Fact> {
+    let n = cfg.blocks.len();
+    let starts = block_starts(cfg);
+    let mut block_in = vec![a.bottom(); n];
+    let mut block_out = vec![a.bottom(); n];
+
+    let mut order: Vec<usize> = (0..n).collect();
+    if A::DIRECTION == Direction::Backward {
+        order.reverse();
+    }
+    let mut queued = vec![true; n];
+    let mut worklist: VecDeque<usize> = order.into_iter().collect();
+    while let Some(b) = worklist.pop_front() {
+        queued[b] = false;
+        let bb = &cfg.blocks[b];
+        let (changed, next) = match A::DIRECTION {
+            Direction::Forward => {
+                let mut fact = if b == cfg.entry {
+                    a.boundary()
+                } else {
+                    a.bottom()
+                };
+                for &p in &bb.preds {
+                    let mut taken = block_out[p].clone();
+                    if let Some(Step::If(cond, thn, els)) = cfg.blocks[p].steps.last() {
+                        if thn != els {
+                            a.branch(cond, bb.label() == Some(*thn), &mut taken);
+                        }
+                    }
+                    if let (Some(from), Some(to)) = (cfg.blocks[p].label(), bb.label()) {
+                        a.edge(from, to, &mut taken);
+                    }
+                    a.join(&mut fact, &taken);
+                }
+                // only where a loop comes back around, elsewhere every fact is final the first
+                // time all the predecessors are
+                if bb.preds.iter().any(|&p| p >= b) {
+                    fact = a.widen(&block_in[b], fact);
+                }
+                block_in[b] = fact;
+                let mut fact = block_in[b].clone();
+                for (i, step) in bb.steps.iter().enumerate() {
+                    a.transfer(starts[b] + i, step, &mut fact);
+                }
+                let changed = fact != block_out[b];
+                block_out[b] = fact;
+                (changed, &bb.succs)
+            }
+            Direction::Backward => {
+                let mut fact = if b == cfg.exit {
+                    a.boundary()
+                } else {
+                    a.bottom()
+                };
+                for s in &bb.succs {
+                    a.join(&mut fact, &block_in[*s]);
+                }
+                block_out[b] = fact.clone();
+                for (i, step) in bb.steps.iter().enumerate().rev() {
+                    a.transfer(starts[b] + i, step, &mut fact);
+                }
+                let changed = fact != block_in[b];
+                block_in[b] = fact;
+                (changed, &bb.preds)
+            }
+        };
+        if changed {
+            for &m in next {
+                if !queued[m] {
+                    queued[m] = true;
+                    worklist.push_back(m);
+                }
+            }
+        }
+    }
+    Results {
+        block_in,
+        block_out,
+    }
+}
+
+impl<F: Clone> Results<F> {
+    /// Replays the transfer function through every block to get the facts around each step
+    pub fn steps<A: Analysis<Fact = F>>(&self, cfg: &Cfg, a: &A) -> StepFacts<F> {
+        let starts = block_starts(cfg);
+        let mut before = vec![];
+        let mut after = vec![];
+        for (b, bb) in cfg.blocks.iter().enumerate() {
+            match A::DIRECTION {
+                Direction::Forward => {
+                    let mut fact = self.block_in[b].clone();
+                    for (i, step) in bb.steps.iter().enumerate() {
+                        before.push(fact.clone());
+                        a.transfer(starts[b] + i, step, &mut fact);
+                        after.push(fact.clone());
+                    }
+                }
+                Direction::Backward => {
+                    let mut fact = self.block_out[b].clone();
+                    let mut block_before = vec![];
+                    let mut block_after = vec![];
+                    for (i, step) in bb.steps.iter().enumerate().rev() {
+                        block_after.push(fact.clone());
+                        a.transfer(starts[b] + i, step, &mut fact);
+                        block_before.push(fact.clone());
+                    }
+                    before.extend(block_before.into_iter().rev());
+                    after.extend(block_after.into_iter().rev());
+                }
+            }
+        }
+        StepFacts { before, after }
+    }
+}
+
+/// Index of the first step of every block in the body
+fn block_starts(cfg: &Cfg) -> Vec<usize> {
+    let mut starts = vec![];
+    let mut start = 0;
+    for bb in &cfg.blocks {
+        starts.push(start);
+        start += bb.steps.len();
+    }
+    starts
+}
+
+/// Live variables: the ones whose current value may still be read
+pub struct LiveVars;
+
+impl Analysis for LiveVars {
+    type Fact = HashSet<Symbol>;
+
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other.iter().copied());
+    }
+
+    fn transfer(&self, _at: usize, step: &Step, fact: &mut Self::Fact) {
+        if let Some(x) = step.def() {
+            fact.remove(&x);
+        }
+        fact.extend(step.uses());
+    }
+}
+
+/// Variables live on entry to and exit from every step of `b`
+pub struct Liveness {
+    pub live_in: Vec<HashSet<Symbol>>,
+    pub live_out: Vec<HashSet<Symbol>>,
+}
+
+/// Liveness of the variables of `b`, with an entry for every step of `b`
+pub fn liveness(b: &Block) -> Liveness {
+    let cfg = Cfg::from_block(b);
+    let facts = solve(&cfg, &LiveVars).steps(&cfg, &LiveVars);
+    Liveness {
+        live_in: facts.before,
+        live_out: facts.after,
+    }
+}
+
+/// Where a variable got its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Site {
+    /// Passed in by the caller
+    Arg,
+    /// Assigned by the step at this index of the body
+    Step(usize),
+}
+
+/// Reaching definitions: the assignments whose value a variable may still hold
+pub struct ReachingDefs<'a> {
+    pub args: &'a [Symbol],
+}
+
+impl Analysis for ReachingDefs<'_> {
+    type Fact = HashSet<(Symbol, Site)>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        self.args.iter().map(|x| (*x, Site::Arg)).collect()
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other.iter().copied());
+    }
+
+    fn transfer(&self, at: usize, step: &Step, fact: &mut Self::Fact) {
+        if let Some(x) = step.def() {
+            fact.retain(|(y, _)| *y != x);
+            fact.insert((x, Site::Step(at)));
+        }
+    }
+}
+
+/// Definitely assigned variables: the ones every path from the entry assigns. `None` stands for
+/// a block no path reaches yet, where every variable counts as assigned.
+pub struct DefinedVars<'a> {
+    pub args: &'a [Symbol],
+}
+
+impl Analysis for DefinedVars<'_> {
+    type Fact = Option<HashSet<Symbol>>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        Some(self.args.iter().copied().collect())
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        None
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        match (into.as_mut(), other) {
+            (_, None) => (),
+            (None, Some(other)) => *into = Some(other.clone()),
+            (Some(into), Some(other)) => into.retain(|x| other.contains(x)),
+        }
+    }
+
+    fn transfer(&self, _at: usize, step: &Step, fact: &mut Self::Fact) {
+        if let (Some(fact), Some(x)) = (fact, step.def()) {
+            fact.insert(x);
+        }
+    }
+}
+
+/// Lattice of constant propagation for one variable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Const {
+    /// Not assigned on any path so far
+    Undef,
+    Known(Val),
+    /// May hold different values
+    Varying,
+}
+
+impl Const {
+    fn join(self, other: Const) -> Const {
+        match (self, other) {
+            (Const::Undef, c) | (c, Const::Undef) => c,
+            (Const::Known(a), Const::Known(b)) if a == b => Const::Known(a),
+            _ => Const::Varying,
+        }
+    }
+}
+
+/// Constant (and copy) propagation: the value of every variable that is only ever assigned the
+/// same constant, or is a copy of another variable.
+pub struct ConstProp<'a> {
+    pub args: &'a [Symbol],
+    /// Variables that must not be treated as constants
+    pub is_fixed: fn(&Symbol) -> bool,
+}
+
+impl ConstProp<'_> {
+    fn value(&self, v: &Val, fact: &HashMap<Symbol, Const>) -> Const {
+        match v {
+            Val::Var(y) => match fact.get(y) {
+                Some(Const::Known(c)) => Const::Known(*c),
+                _ if (self.is_fixed)(y) => Const::Varying,
+                // in SSA form the variable keeps its value, so a copy can always be replaced by it
+                _ => Const::Known(*v),
+            },
+            c => Const::Known(*c),
+        }
+    }
+}
+
+impl Analysis for ConstProp<'_> {
+    type Fact = HashMap<Symbol, Const>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        self.args.iter().map(|x| (*x, Const::Varying)).collect()
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        HashMap::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        for (x, c) in other {
+            let joined = into.get(x).copied().unwrap_or(Const::Undef).join(*c);
+            into.insert(*x, joined);
+        }
+    }
+
+    fn transfer(&self, _at: usize, step: &Step, fact: &mut Self::Fact) {
+        let (x, c) = match step {
+            Step::Set(x, e) => match e {
+                IRExpr::Val(v) => (x, self.value(v, fact)),
+                _ => (x, Const::Varying),
+            },
+            Step::Phi(x, ops) => (
+                x,
+                ops.iter()
+                    .fold(Const::Undef, |c, (_, v)| c.join(self.value(v, fact))),
+            ),
+            _ => return,
+        };
+        let c = if (self.is_fixed)(x) {
+            Const::Varying
+        } else {
+            c
+        };
+        fact.insert(*x, c);
+    }
+}
+
+/// The kinds of values a variable may hold, as a set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ty(u8);
+
+impl Ty {
+    /// Not assigned on any path so far
+    pub const NONE: Ty = Ty(0);
+    pub const NUM: Ty = Ty(1);
+    pub const BOOL: Ty = Ty(2);
+    /// A vector, never nil
+    pub const VEC: Ty = Ty(4);
+    pub const NIL: Ty = Ty(8);
+    pub const CLOSURE: Ty = Ty(16);
+    pub const UNKNOWN: Ty = Ty(31);
+
+    pub fn union(self, other: Ty) -> Ty {
+        Ty(self.0 | other.0)
+    }
+
+    pub fn intersect(self, other: Ty) -> Ty {
+        Ty(self.0 & other.0)
+    }
+
+    /// Every value of `self` is also one of `other`
+    pub fn within(self, other: Ty) -> bool {
+        self.intersect(other) == self
+    }
+}
+
+/// What `=` accepts on both sides: two numbers, two booleans or two vectors (nil included)
+const EQ_KINDS: [Ty; 3] = [Ty::NUM, Ty::BOOL, Ty(Ty::VEC.0 | Ty::NIL.0)];
+
+/// Type analysis: what each variable may hold at every point, from the operations that produced
+/// it and the checks it already passed
+pub struct Types<'a> {
+    pub args: &'a [Symbol],
+    /// What every function may return, functions that are missing may return anything
+    pub returns: &'a HashMap<Symbol, Ty>,
+}
+
+impl Types<'_> {
+    /// The input is the same everywhere, so once a check tells what it is that holds for the
+    /// rest of the body. It is tracked under a name no variable has.
+    fn input() -> Symbol {
+        Symbol::new("input")
+    }
+
+    pub fn of_val(&self, v: &Val, fact: &HashMap<Symbol, Ty>) -> Ty {
+        match v {
+            Val::Num(_) => Ty::NUM,
+            Val::True | Val::False => Ty::BOOL,
+            Val::Nil => Ty::NIL,
+            Val::Input => fact
+                .get(&Types::input())
+                .copied()
+                .unwrap_or(Ty::NUM.union(Ty::BOOL)),
+            Val::Var(x) => fact.get(x).copied().unwrap_or(Ty::UNKNOWN),
+        }
+    }
+
+    pub fn of_expr(&self, e: &IRExpr, fact: &HashMap<Symbol, Ty>) -> Ty {
+        match e {
+            IRExpr::Val(v) | IRExpr::Print(v) => self.of_val(v, fact),
+            IRExpr::Add1(_)
+            | IRExpr::Sub1(_)
+            | IRExpr::Plus(..)
+            | IRExpr::Minus(..)
+            | IRExpr::Times(..)
+            | IRExpr::Divide(..)
+            | IRExpr::VecLen(_) => Ty::NUM,
+            IRExpr::Eq(..)
+            | IRExpr::Gt(..)
+            | IRExpr::Ge(..)
+            | IRExpr::Lt(..)
+            | IRExpr::Le(..)
+            | IRExpr::IsNum(_)
+            | IRExpr::IsBool(_)
+            | IRExpr::IsVec(_) => Ty::BOOL,
+            // vec-set! gives back the vector, which passed its bounds check
+            IRExpr::MakeVec(..) | IRExpr::Vec(_) | IRExpr::VecSet(..) => Ty::VEC,
+            IRExpr::MakeClosure(..) => Ty::CLOSURE,
+            IRExpr::Call(f, _) => self.returns.get(f).copied().unwrap_or(Ty::UNKNOWN),
+            IRExpr::CallIndirect(..)
+            | IRExpr::ClosureGet(..)
+            | IRExpr::VecGet(..)
+            | IRExpr::PrintStack
+            | IRExpr::Gc => Ty::UNKNOWN,
+        }
+    }
+
+    /// Narrows what `v` may hold to `ty`, past a check it must pass to get there
+    fn refine(&self, v: &Val, ty: Ty, fact: &mut HashMap<Symbol, Ty>) {
+        let x = match v {
+            Val::Var(x) => *x,
+            Val::Input => Types::input(),
+            _ => return,
+        };
+        let narrowed = self.of_val(v, fact).intersect(ty);
+        fact.insert(x, narrowed);
+    }
+
+    /// Whether `c` always passes when the variables hold what `fact` says
+    pub fn implied(&self, c: &CheckType, fact: &HashMap<Symbol, Ty>) -> bool {
+        let ty = |v| self.of_val(v, fact);
+        match c {
+            CheckType::CheckIsNum(v) => ty(v).within(Ty::NUM),
+            CheckType::CheckIsVec(v) => ty(v).within(Ty::VEC.union(Ty::NIL)),
+            CheckType::CheckIsNotNil(v) => ty(v).intersect(Ty::NIL) == Ty::NONE,
+            CheckType::CheckEq(v1, v2) => EQ_KINDS
+                .iter()
+                .any(|kind| ty(v1).within(*kind) && ty(v2).within(*kind)),
+            CheckType::CheckBounds(..)
+            | CheckType::CheckOverflow(_)
+            | CheckType::CheckCallable(..) => false,
+        }
+    }
+}
+
+impl Analysis for Types<'_> {
+    type Fact = HashMap<Symbol, Ty>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        // a missing variable joins as `NONE`, input has to be there for a check on one path
+        // not to make it a number on the others
+        self.args
+            .iter()
+            .map(|x| (*x, Ty::UNKNOWN))
+            .chain([(Types::input(), Ty::NUM.union(Ty::BOOL))])
+            .collect()
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        HashMap::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        for (x, ty) in other {
+            let joined = into.get(x).copied().unwrap_or(Ty::NONE).union(*ty);
+            into.insert(*x, joined);
+        }
+    }
+
+    fn transfer(&self, _at: usize, step: &Step, fact: &mut Self::Fact) {
+        match step {
+            Step::Set(x, e) => {
+                let ty = self.of_expr(e, fact);
+                fact.insert(*x, ty);
+            }
+            Step::Phi(x, ops) => {
+                // operands not assigned yet are from a back edge not taken so far
+                let ty = ops.iter().fold(Ty::NONE, |ty, (_, v)| match v {
+                    Val::Var(y) => ty.union(fact.get(y).copied().unwrap_or(Ty::NONE)),
+                    v => ty.union(self.of_val(v, fact)),
+                });
+                fact.insert(*x, ty);
+            }
+            Step::Check(c) => match c {
+                CheckType::CheckIsNum(v) => self.refine(v, Ty::NUM, fact),
+                CheckType::CheckIsVec(v) => self.refine(v, Ty::VEC.union(Ty::NIL), fact),
+                CheckType::CheckIsNotNil(v) => self.refine(v, Ty(Ty::UNKNOWN.0 & !Ty::NIL.0), fact),
+                CheckType::CheckBounds(vec, idx) => {
+                    self.refine(vec, Ty::VEC, fact);
+                    self.refine(idx, Ty::NUM, fact);
+                }
+                CheckType::CheckCallable(f, _) => self.refine(f, Ty::CLOSURE, fact),
+                CheckType::CheckEq(v1, v2) => {
+                    // both sides are of the same kind, so a side whose kind is known gives away
+                    // the kind of the other
+                    for (v, other) in [(v1, v2), (v2, v1)] {
+                        let other = self.of_val(other, fact);
+                        if other == Ty::NONE {
+                            continue;
+                        }
+                        if let Some(kind) = EQ_KINDS.iter().find(|kind| other.within(**kind)) {
+                            self.refine(v, *kind, fact);
+                        }
+                    }
+                }
+                CheckType::CheckOverflow(_) => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Bounds of a number, `None` when unbounded on that side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub lo: Option<i64>,
+    pub hi: Option<i64>,
+}
+
+impl Interval {
+    pub const ANY: Interval = Interval { lo: None, hi: None };
+
+    /// Smallest number that fits in a tagged value
+    pub const MIN_NUM: i64 = -(1 << 62);
+    /// Largest number that fits in a tagged value
+    pub const MAX_NUM: i64 = (1 << 62) - 1;
+    /// A vector has to fit in the 47 bits of user address space, 8 bytes per element
+    const MAX_VEC_LEN: i64 = 1 << 44;
+
+    pub fn constant(n: i64) -> Interval {
+        Interval {
+            lo: Some(n),
+            hi: Some(n),
+        }
+    }
+
+    /// Whether every number in the interval can be tagged, i.e. arithmetic producing it doesn't
+    /// overflow
+    pub fn fits(&self) -> bool {
+        self.lo.is_some_and(|lo| lo >= Interval::MIN_NUM)
+            && self.hi.is_some_and(|hi| hi <= Interval::MAX_NUM)
+    }
+
+    fn hull(self, other: Interval) -> Interval {
+        Interval {
+            lo: self.lo.zip(other.lo).map(|(a, b)| a.min(b)),
+            hi: self.hi.zip(other.hi).map(|(a, b)| a.max(b)),
+        }
+    }
+
+    fn add(self, other: Interval) -> Interval {
+        let add = |a: Option<i64>, b: Option<i64>| a.zip(b).and_then(|(a, b)| a.checked_add(b));
+        Interval {
+            lo: add(self.lo, other.lo),
+            hi: add(self.hi, other.hi),
+        }
+    }
+
+    fn neg(self) -> Interval {
+        Interval {
+            lo: self.hi.and_then(i64::checked_neg),
+            hi: self.lo.and_then(i64::checked_neg),
+        }
+    }
+
+    fn mul(self, other: Interval) -> Interval {
+        let (Some(a), Some(b), Some(c), Some(d)) = (self.lo, self.hi, other.lo, other.hi) else {
+            return Interval::ANY;
+        };
+        let products = [
+            a.checked_mul(c),
+            a.checked_mul(d),
+            b.checked_mul(c),
+            b.checked_mul(d),
+        ];
+        if products.iter().any(Option::is_none) {
+            return Interval::ANY;
+        }
+        let products = products.map(Option::unwrap);
+        Interval {
+            lo: products.iter().min().copied(),
+            hi: products.iter().max().copied(),
+        }
+    }
+}
+
+/// Facts of the range analysis at a point that can be reached
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RangeFact {
+    /// Bounds of the numbers held by variables, missing variables may hold anything
+    pub intervals: HashMap<Symbol, Interval>,
+    /// Pairs of variables `(x, n)` with `x < n`
+    pub less_than: HashSet<(Symbol, Symbol)>,
+}
+
+/// Variables assigned by the phis of a block, with their operands
+type Phis<'a> = Vec<(Symbol, &'a [(Symbol, Val)])>;
+
+/// Range analysis: bounds of the numbers variables hold, from the constants and arithmetic that
+/// produced them and the comparisons branched on to get there. Only sound on SSA form, where a
+/// variable never changes once assigned.
+pub struct Ranges<'a> {
+    pub args: &'a [Symbol],
+    /// The expression assigned to every variable of the body
+    pub defs: HashMap<Symbol, &'a IRExpr>,
+    /// The phis at the top of every labelled block
+    pub phis: HashMap<Symbol, Phis<'a>>,
+}
+
+impl<'a> Ranges<'a> {
+    pub fn new(b: &'a Block, args: &'a [Symbol]) -> Ranges<'a> {
+        let mut defs = HashMap::new();
+        let mut phis: HashMap<Symbol, Phis> = HashMap::new();
+        let mut label = None;
+        for step in &b.steps {
+            match step {
+                Step::Label(l) => label = Some(*l),
+                Step::Set(x, e) => {
+                    defs.insert(*x, e);
+                }
+                Step::Phi(x, ops) => {
+                    if let Some(l) = label {
+                        phis.entry(l).or_default().push((*x, &ops[..]));
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ranges { args, defs, phis }
+    }
+
+    pub fn interval(v: &Val, fact: &RangeFact) -> Interval {
+        match v {
+            Val::Num(n) => Interval::constant(*n),
+            Val::Var(x) => fact.intervals.get(x).copied().unwrap_or(Interval::ANY),
+            _ => Interval::ANY,
+        }
+    }
+
+    pub fn of_expr(e: &IRExpr, fact: &RangeFact) -> Interval {
+        let iv = |v| Ranges::interval(v, fact);
+        match e {
+            IRExpr::Val(v) => iv(v),
+            IRExpr::Add1(v) => iv(v).add(Interval::constant(1)),
+            IRExpr::Sub1(v) => iv(v).add(Interval::constant(-1)),
+            IRExpr::Plus(v1, v2) => iv(v1).add(iv(v2)),
+            IRExpr::Minus(v1, v2) => iv(v1).add(iv(v2).neg()),
+            IRExpr::Times(v1, v2) => iv(v1).mul(iv(v2)),
+            IRExpr::VecLen(_) => Interval {
+                lo: Some(0),
+                hi: Some(Interval::MAX_VEC_LEN),
+            },
+            _ => Interval::ANY,
+        }
+    }
+
+    /// The number of elements of the vector `v` when it is known statically
+    pub fn const_len(&self, v: &Val) -> Option<i64> {
+        match self.defs.get(&v.var()?)? {
+            IRExpr::Vec(elems) => Some(elems.len() as i64),
+            IRExpr::MakeVec(Val::Num(n), _) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Whether `n` holds the number of elements of the vector `v`
+    pub fn is_len_of(&self, n: Symbol, v: &Val) -> bool {
+        let Some(v) = v.var() else { return false };
+        matches!(self.defs.get(&n), Some(IRExpr::VecLen(Val::Var(w))) if *w == v)
+            || matches!(self.defs.get(&v), Some(IRExpr::MakeVec(Val::Var(m), _)) if *m == n)
+    }
+
+    /// Whether `0 <= idx < (vec-len vec)` always holds
+    pub fn in_bounds(&self, vec: &Val, idx: &Val, fact: &RangeFact) -> bool {
+        let iv = Ranges::interval(idx, fact);
+        if iv.lo.is_none_or(|lo| lo < 0) {
+            return false;
+        }
+        if let (Some(hi), Some(len)) = (iv.hi, self.const_len(vec)) {
+            if hi < len {
+                return true;
+            }
+        }
+        let Some(i) = idx.var() else { return false };
+        fact.less_than
+            .iter()
+            .any(|(x, n)| *x == i && self.is_len_of(*n, vec))
+    }
+
+    /// Narrows the facts assuming `a < b` (`strict`) or `a <= b`
+    fn assume(a: &Val, b: &Val, strict: bool, fact: &mut RangeFact) {
+        let gap = if strict { 1 } else { 0 };
+        let (ia, ib) = (Ranges::interval(a, fact), Ranges::interval(b, fact));
+        if let Val::Var(x) = a {
+            let hi = match (ia.hi, ib.hi.and_then(|hi| hi.checked_sub(gap))) {
+                (Some(h1), Some(h2)) => Some(h1.min(h2)),
+                (h1, h2) => h1.or(h2),
+            };
+            fact.intervals.insert(*x, Interval { lo: ia.lo, hi });
+        }
+        if let Val::Var(y) = b {
+            let lo = match (ib.lo, ia.lo.and_then(|lo| lo.checked_add(gap))) {
+                (Some(l1), Some(l2)) => Some(l1.max(l2)),
+                (l1, l2) => l1.or(l2),
+            };
+            fact.intervals.insert(*y, Interval { lo, hi: ib.hi });
+        }
+        if let (true, Val::Var(x), Val::Var(y)) = (strict, a, b) {
+            fact.less_than.insert((*x, *y));
+        }
+    }
+}
+
+impl Analysis for Ranges<'_> {
+    /// `None` where the code can't be reached
+    type Fact = Option<RangeFact>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        Some(RangeFact {
+            intervals: self.args.iter().map(|x| (*x, Interval::ANY)).collect(),
+            less_than: HashSet::new(),
+        })
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        None
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        let Some(other) = other else { return };
+        let Some(fact) = into else {
+            *into = Some(other.clone());
+            return;
+        };
+        // a variable assigned on one side only can't be used past the join (except by a phi)
+        for (x, iv) in &other.intervals {
+            match fact.intervals.get(x) {
+                Some(mine) => {
+                    let joined = mine.hull(*iv);
+                    fact.intervals.insert(*x, joined);
+                }
+                None => {
+                    fact.intervals.insert(*x, *iv);
+                }
+            }
+        }
+        fact.less_than.retain(|pair| other.less_than.contains(pair));
+    }
+
+    fn transfer(&self, _at: usize, step: &Step, fact: &mut Self::Fact) {
+        let Some(fact) = fact else { return };
+        // in a loop, the variable gets a new value every time around
+        if let Some(x) = step.def() {
+            fact.less_than.retain(|(y, n)| *y != x && *n != x);
+        }
+        match step {
+            Step::Set(x, e) => {
+                let iv = Ranges::of_expr(e, fact);
+                fact.intervals.insert(*x, iv);
+            }
+            // given its bounds on the way in, by `edge`
+            Step::Phi(x, _) => {
+                fact.intervals.entry(*x).or_insert(Interval::ANY);
+            }
+            _ => (),
+        }
+    }
+
+    fn branch(&self, cond: &Val, taken: bool, fact: &mut Self::Fact) {
+        let (Some(fact), Some(c)) = (fact, cond.var()) else {
+            return;
+        };
+        match (self.defs.get(&c), taken) {
+            (Some(IRExpr::Lt(a, b)), true) | (Some(IRExpr::Ge(a, b)), false) => {
+                Ranges::assume(a, b, true, fact)
+            }
+            (Some(IRExpr::Lt(a, b)), false) | (Some(IRExpr::Ge(a, b)), true) => {
+                Ranges::assume(b, a, false, fact)
+            }
+            (Some(IRExpr::Gt(a, b)), true) | (Some(IRExpr::Le(a, b)), false) => {
+                Ranges::assume(b, a, true, fact)
+            }
+            (Some(IRExpr::Gt(a, b)), false) | (Some(IRExpr::Le(a, b)), true) => {
+                Ranges::assume(a, b, false, fact)
+            }
+            _ => (),
+        }
+    }
+
+    fn edge(&self, from: Symbol, to: Symbol, fact: &mut Self::Fact) {
+        let (Some(fact), Some(phis)) = (fact, self.phis.get(&to)) else {
+            return;
+        };
+        // the operand is bounded by what is known at the end of `from`, which the join with the
+        // other predecessors would lose
+        for (x, ops) in phis {
+            match ops.iter().find(|(l, _)| *l == from) {
+                Some((_, v)) => {
+                    let iv = Ranges::interval(v, fact);
+                    fact.intervals.insert(*x, iv);
+                }
+                None => {
+                    fact.intervals.remove(x);
+                }
+            }
+        }
+    }
+
+    fn widen(&self, old: &Self::Fact, new: Self::Fact) -> Self::Fact {
+        let (Some(old), Some(mut new)) = (old, new.clone()) else {
+            return new;
+        };
+        for (x, iv) in new.intervals.iter_mut() {
+            let Some(prev) = old.intervals.get(x) else {
+                continue;
+            };
+            // a side that was given up on stays unbounded, or narrowing it again could go on
+            // forever
+            if prev.lo.is_none_or(|prev| iv.lo.is_none_or(|lo| lo < prev)) {
+                iv.lo = None;
+            }
+            if prev.hi.is_none_or(|prev| iv.hi.is_none_or(|hi| hi > prev)) {
+                iv.hi = None;
+            }
+        }
+        Some(new)
+    }
+}
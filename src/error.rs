@@ -0,0 +1,198 @@
+use std::fmt;
+
+use crate::syntax::{Span, Symbol};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    Syntax(String),
+    DuplicateBinding(Symbol),
+    DuplicateFunction(Symbol),
+    UnboundIdentifier(Symbol),
+    UndefinedFunction(Symbol),
+    WrongNumberOfArgs {
+        fun: Symbol,
+        expected: usize,
+        got: usize,
+    },
+    BreakOutsideLoop,
+    InputInFunction,
+    AssignToCaptured(Symbol),
+    /// A construct the selected backend cannot compile
+    Unsupported(&'static str),
+    /// Arithmetic on constants, found while optimizing, whose result doesn't fit in a number
+    Overflow(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub kind: ErrorKind,
+    /// Where the error was found, if the phase reporting it still has source positions
+    pub span: Option<Span>,
+}
+
+impl ErrorKind {
+    /// Stable, machine readable name of the kind, printed as `error[<code>]`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Syntax(_) => "syntax",
+            ErrorKind::DuplicateBinding(_) => "duplicate-binding",
+            ErrorKind::DuplicateFunction(_) => "duplicate-function",
+            ErrorKind::UnboundIdentifier(_) => "unbound-identifier",
+            ErrorKind::UndefinedFunction(_) => "undefined-function",
+            ErrorKind::WrongNumberOfArgs { .. } => "wrong-arity",
+            ErrorKind::BreakOutsideLoop => "break-outside-loop",
+            ErrorKind::InputInFunction => "input-in-function",
+            ErrorKind::AssignToCaptured(_) => "assign-to-captured",
+            ErrorKind::Unsupported(_) => "unsupported",
+            ErrorKind::Overflow(_) => "overflow",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Syntax(note) => write!(f, "Invalid syntax: {note}"),
+            ErrorKind::DuplicateBinding(id) => write!(f, "duplicate binding {id}"),
+            ErrorKind::DuplicateFunction(name) => write!(f, "duplicate function name {name}"),
+            ErrorKind::UnboundIdentifier(id) => write!(f, "unbound variable identifier {id}"),
+            ErrorKind::UndefinedFunction(fun) => write!(f, "function {fun} not defined"),
+            ErrorKind::WrongNumberOfArgs { fun, expected, got } => write!(
+                f,
+                "function {fun} takes {expected} arguments but {got} were supplied"
+            ),
+            ErrorKind::BreakOutsideLoop => write!(f, "break outside loop"),
+            ErrorKind::InputInFunction => write!(f, "cannot use input inside function definition"),
+            ErrorKind::AssignToCaptured(id) => write!(f, "cannot assign to captured variable {id}"),
+            ErrorKind::Unsupported(what) => write!(f, "{what} are not supported by this backend"),
+            ErrorKind::Overflow(expr) => write!(f, "`{expr}` overflows"),
+        }
+    }
+}
+
+impl CompileError {
+    pub fn new(kind: ErrorKind, span: Span) -> CompileError {
+        CompileError {
+            kind,
+            span: Some(span),
+        }
+    }
+
+    /// Formats the error together with the offending source line and a caret underneath the
+    /// span, e.g.
+    ///
+    /// ```text
+    /// error[duplicate-binding]: duplicate binding x
+    ///  --> tests/duplicate_binding.snek:1:1
+    ///   |
+    /// 1 | (let ((x 10) (x 100)) (add1 x))
+    ///   | ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+    /// ```
+    pub fn render(&self, file: &str, src: &str) -> String {
+        let mut s = format!("error[{}]: {}\n", self.kind.code(), self.kind);
+        let Some(span) = self.span else {
+            s.push_str(&format!(" --> {file}\n"));
+            return s;
+        };
+        let line_no = span.start.line.to_string();
+        let pad = " ".repeat(line_no.len());
+        let line = src.lines().nth(span.start.line - 1).unwrap_or("");
+        let width = if span.end.line == span.start.line {
+            span.end.col.saturating_sub(span.start.col)
+        } else {
+            (line.chars().count() + 1).saturating_sub(span.start.col)
+        };
+        s.push_str(&format!(
+            "{pad}--> {file}:{}:{}\n",
+            span.start.line, span.start.col
+        ));
+        s.push_str(&format!("{pad} |\n"));
+        s.push_str(&format!("{line_no} | {line}\n"));
+        s.push_str(&format!(
+            "{pad} | {}{}\n",
+            " ".repeat(span.start.col - 1),
+            "^".repeat(width.max(1))
+        ));
+        s
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}:{}: {}", span.start.line, span.start.col, self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// Collects every error found while compiling a program so that they can be reported together
+/// instead of stopping at the first one.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    errors: Vec<CompileError>,
+}
+
+impl Diagnostics {
+    /// Number of errors rendered by the driver unless told otherwise
+    pub const DEFAULT_LIMIT: usize = 20;
+
+    pub fn new() -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    pub fn report(&mut self, err: CompileError) {
+        self.errors.push(err);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// `Ok(value)` if nothing was reported, otherwise hands the collected errors back
+    pub fn finish<T>(self, value: T) -> Result<T, Diagnostics> {
+        if self.has_errors() {
+            Err(self)
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Renders at most `limit` errors (all of them if `limit` is 0) in source order, followed by
+    /// a summary line with the total count.
+    pub fn render(&self, file: &str, src: &str, limit: usize) -> String {
+        let mut errors: Vec<&CompileError> = self.errors.iter().collect();
+        errors.sort_by_key(|err| err.span.map(|span| (span.start.line, span.start.col)));
+        let shown = if limit == 0 {
+            self.len()
+        } else {
+            self.len().min(limit)
+        };
+        let mut s = String::new();
+        for err in &errors[..shown] {
+            s.push_str(&err.render(file, src));
+            s.push('\n');
+        }
+        if shown < self.len() {
+            s.push_str(&format!(
+                "note: {} more error(s) not shown, use `--error-limit` to see more\n",
+                self.len() - shown
+            ));
+        }
+        match self.len() {
+            1 => s.push_str("error: aborting due to 1 previous error\n"),
+            n => s.push_str(&format!("error: aborting due to {n} previous errors\n")),
+        }
+        s
+    }
+}
+
+impl From<CompileError> for Diagnostics {
+    fn from(err: CompileError) -> Diagnostics {
+        Diagnostics { errors: vec![err] }
+    }
+}
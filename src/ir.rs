@@ -1,9 +1,13 @@
 use crate::{
     anf::*
 };
-use crate::syntax::{Symbol};
+use crate::syntax::{Span, Symbol};
+use crate::callgraph::CallGraph;
+use crate::cfg::Cfg;
+use crate::dataflow::{self, DefinedVars};
+use std::collections::{HashMap, HashSet};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Val {
     Num(i64),
     True,
@@ -35,6 +39,10 @@ pub enum IRExpr {
 
     Call(Symbol, Vec<Val>),
 
+    MakeClosure(Symbol, usize, Vec<Val>),
+    CallIndirect(Val, Vec<Val>),
+    ClosureGet(Val, usize),
+
     MakeVec(Val, Val),
     Vec(Vec<Val>),
     VecSet(Val, Val, Val),
@@ -46,14 +54,17 @@ pub enum IRExpr {
     Gc,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CheckType {
     CheckIsNum(Val),
     CheckIsVec(Val),
     CheckIsNotNil(Val),
     CheckEq(Val, Val),
     CheckBounds(Val, Val),
-    CheckOverflow,
+    /// Whether the arithmetic just before overflowed, the span is where it is in the source
+    CheckOverflow(Span),
+    /// The value is a closure taking the given number of arguments
+    CheckCallable(Val, usize),
 
 }
 
@@ -65,23 +76,236 @@ pub enum Step {
     Do(IRExpr),
     Set(Symbol, IRExpr),
     Check(CheckType),
+    /// SSA only: the value of the operand paired with the label of the block control came from
+    Phi(Symbol, Vec<(Symbol, Val)>),
 }
 
+#[derive(Clone)]
 pub struct Block {
     pub steps: Vec<Step>,
 }
 
+#[derive(Clone)]
 pub struct Def {
     pub name: Symbol,
     pub args: Vec<Symbol>,
     pub body: Block,
 }
 
+#[derive(Clone)]
 pub struct Prog {
     pub defs: Vec<Def>,
     pub main: Block,
 }
 
+impl Val {
+    pub fn var(&self) -> Option<Symbol> {
+        match self {
+            Val::Var(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+impl IRExpr {
+    /// Operands of the expression, in evaluation order
+    pub fn vals(&self) -> Vec<&Val> {
+        match self {
+            IRExpr::Add1(v) | IRExpr::Sub1(v) | IRExpr::IsNum(v) | IRExpr::IsBool(v) | IRExpr::IsVec(v) |
+            IRExpr::Print(v) | IRExpr::ClosureGet(v, _) | IRExpr::VecLen(v) | IRExpr::Val(v) => vec![v],
+            IRExpr::Plus(v1, v2) | IRExpr::Minus(v1, v2) | IRExpr::Times(v1, v2) | IRExpr::Divide(v1, v2) |
+            IRExpr::Eq(v1, v2) | IRExpr::Gt(v1, v2) | IRExpr::Ge(v1, v2) | IRExpr::Lt(v1, v2) | IRExpr::Le(v1, v2) |
+            IRExpr::MakeVec(v1, v2) | IRExpr::VecGet(v1, v2) => vec![v1, v2],
+            IRExpr::VecSet(v1, v2, v3) => vec![v1, v2, v3],
+            IRExpr::Call(_, vs) | IRExpr::MakeClosure(_, _, vs) | IRExpr::Vec(vs) => vs.iter().collect(),
+            IRExpr::CallIndirect(f, vs) => std::iter::once(f).chain(vs).collect(),
+            IRExpr::PrintStack | IRExpr::Gc => vec![],
+        }
+    }
+
+    pub fn vals_mut(&mut self) -> Vec<&mut Val> {
+        match self {
+            IRExpr::Add1(v) | IRExpr::Sub1(v) | IRExpr::IsNum(v) | IRExpr::IsBool(v) | IRExpr::IsVec(v) |
+            IRExpr::Print(v) | IRExpr::ClosureGet(v, _) | IRExpr::VecLen(v) | IRExpr::Val(v) => vec![v],
+            IRExpr::Plus(v1, v2) | IRExpr::Minus(v1, v2) | IRExpr::Times(v1, v2) | IRExpr::Divide(v1, v2) |
+            IRExpr::Eq(v1, v2) | IRExpr::Gt(v1, v2) | IRExpr::Ge(v1, v2) | IRExpr::Lt(v1, v2) | IRExpr::Le(v1, v2) |
+            IRExpr::MakeVec(v1, v2) | IRExpr::VecGet(v1, v2) => vec![v1, v2],
+            IRExpr::VecSet(v1, v2, v3) => vec![v1, v2, v3],
+            IRExpr::Call(_, vs) | IRExpr::MakeClosure(_, _, vs) | IRExpr::Vec(vs) => vs.iter_mut().collect(),
+            IRExpr::CallIndirect(f, vs) => std::iter::once(f).chain(vs).collect(),
+            IRExpr::PrintStack | IRExpr::Gc => vec![],
+        }
+    }
+}
+
+impl CheckType {
+    pub fn vals(&self) -> Vec<&Val> {
+        match self {
+            CheckType::CheckIsNum(v) | CheckType::CheckIsVec(v) | CheckType::CheckIsNotNil(v) |
+            CheckType::CheckCallable(v, _) => vec![v],
+            CheckType::CheckEq(v1, v2) | CheckType::CheckBounds(v1, v2) => vec![v1, v2],
+            CheckType::CheckOverflow(_) => vec![],
+        }
+    }
+
+    pub fn vals_mut(&mut self) -> Vec<&mut Val> {
+        match self {
+            CheckType::CheckIsNum(v) | CheckType::CheckIsVec(v) | CheckType::CheckIsNotNil(v) |
+            CheckType::CheckCallable(v, _) => vec![v],
+            CheckType::CheckEq(v1, v2) | CheckType::CheckBounds(v1, v2) => vec![v1, v2],
+            CheckType::CheckOverflow(_) => vec![],
+        }
+    }
+}
+
+impl Step {
+    /// Variables read by the step
+    pub fn uses(&self) -> Vec<Symbol> {
+        let vals = match self {
+            Step::Label(_) | Step::Goto(_) => vec![],
+            Step::If(v, _, _) => vec![v],
+            Step::Do(e) | Step::Set(_, e) => e.vals(),
+            Step::Check(c) => c.vals(),
+            Step::Phi(_, ops) => ops.iter().map(|(_, v)| v).collect(),
+        };
+        vals.into_iter().filter_map(Val::var).collect()
+    }
+
+    /// Operands of the step, for rewriting them in place
+    pub fn vals_mut(&mut self) -> Vec<&mut Val> {
+        match self {
+            Step::Label(_) | Step::Goto(_) => vec![],
+            Step::If(v, _, _) => vec![v],
+            Step::Do(e) | Step::Set(_, e) => e.vals_mut(),
+            Step::Check(c) => c.vals_mut(),
+            Step::Phi(_, ops) => ops.iter_mut().map(|(_, v)| v).collect(),
+        }
+    }
+
+    /// Variable (or hard coded register) written by the step
+    pub fn def(&self) -> Option<Symbol> {
+        match self {
+            Step::Set(x, _) | Step::Phi(x, _) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+fn is_hard_coded_reg(x: &Symbol) -> bool {
+    matches!(x.to_string().as_str(), "rax" | "r15")
+}
+
+/// Checks the invariants the passes and the code generators rely on: jumps go to labels of the
+/// same body, phis have an operand for every predecessor of their block, variables are assigned
+/// on every path before they are read, calls pass as many arguments as the function takes, and
+/// the hard-coded registers are only written, `rax` with the result and `r15` by a collection.
+/// Returns what is wrong otherwise.
+pub fn verify(prog: &Prog) -> Result<(), String> {
+    let arities: HashMap<Symbol, usize> = prog.defs.iter().map(|def| (def.name, def.args.len())).collect();
+    verify_block(&prog.main, &[], &arities).map_err(|e| format!("in main: {e}"))?;
+    for def in &prog.defs {
+        verify_block(&def.body, &def.args, &arities).map_err(|e| format!("in {}: {e}", def.name))?;
+    }
+    return Ok(());
+}
+
+fn verify_block(b: &Block, args: &[Symbol], arities: &HashMap<Symbol, usize>) -> Result<(), String> {
+    let mut labels = HashSet::new();
+    for step in &b.steps {
+        if let Step::Label(l) = step {
+            if !labels.insert(*l) {
+                return Err(format!("label `{l}` appears twice"));
+            }
+        }
+    }
+    for x in args {
+        if is_hard_coded_reg(x) {
+            return Err(format!("`{x}` is a parameter"));
+        }
+    }
+
+    for step in &b.steps {
+        let bad = |what: String| Err(format!("`{}` {what}", step_to_string(step)));
+        let targets = match step {
+            Step::Goto(l) => vec![*l],
+            Step::If(_, thn, els) => vec![*thn, *els],
+            Step::Phi(_, ops) => ops.iter().map(|(l, _)| *l).collect(),
+            _ => vec![],
+        };
+        if let Some(l) = targets.iter().find(|l| !labels.contains(l)) {
+            return bad(format!("refers to label `{l}`, which isn't in the same body"));
+        }
+        if let Some(x) = step.uses().iter().find(|x| is_hard_coded_reg(x)) {
+            return bad(format!("reads `{x}`"));
+        }
+        match step {
+            Step::Set(x, e) if x.to_string() == "r15" && !matches!(e, IRExpr::Gc) => return bad("writes `r15`".to_string()),
+            Step::Set(x, IRExpr::Gc) if x.to_string() != "r15" => return bad("collects into a variable other than `r15`".to_string()),
+            Step::Do(IRExpr::Gc) => return bad("collects without writing `r15`".to_string()),
+            Step::Phi(x, _) if is_hard_coded_reg(x) => return bad(format!("writes `{x}`")),
+            _ => (),
+        }
+        let (f, n) = match step {
+            Step::Set(_, IRExpr::Call(f, vs)) | Step::Do(IRExpr::Call(f, vs)) => (f, vs.len()),
+            // a closure takes its environment first
+            Step::Set(_, IRExpr::MakeClosure(f, arity, _)) | Step::Do(IRExpr::MakeClosure(f, arity, _)) => (f, arity + 1),
+            _ => continue,
+        };
+        match arities.get(f) {
+            None => return bad(format!("refers to `{f}`, which isn't defined")),
+            Some(m) if *m != n => return bad(format!("passes {n} argument(s) to `{f}`, which takes {m}")),
+            Some(_) => (),
+        }
+    }
+
+    let cfg = Cfg::from_block(b);
+    // a phi has an operand for every block control can come from, and for no other block
+    let reachable = cfg.reachable();
+    let names = |mut labels: Vec<String>| {
+        labels.sort();
+        return labels.join(", ");
+    };
+    for bb in cfg.blocks.iter().zip(&reachable).filter(|(_, r)| **r).map(|(bb, _)| bb) {
+        let preds = names(bb.preds.iter().filter(|p| reachable[**p]).map(|p| match cfg.blocks[*p].label() {
+            Some(l) => format!("`{l}`"),
+            None => "a block without a label".to_string(),
+        }).collect());
+        for step in &bb.steps {
+            let Step::Phi(_, ops) = step else {
+                continue;
+            };
+            let from = names(ops.iter().map(|(l, _)| format!("`{l}`")).collect());
+            if from != preds {
+                let entered = if preds.is_empty() { "nowhere".to_string() } else { preds.clone() };
+                return Err(format!("`{}` has operands from {from}, but control comes from {entered}", step_to_string(step)));
+            }
+        }
+    }
+
+    let analysis = DefinedVars { args };
+    let results = dataflow::solve(&cfg, &analysis);
+    let block_of: HashMap<Symbol, usize> = cfg.blocks.iter().enumerate().filter_map(|(i, bb)| bb.label().map(|l| (l, i))).collect();
+    let facts = results.steps(&cfg, &analysis);
+    for (i, step) in cfg.blocks.iter().flat_map(|bb| &bb.steps).enumerate() {
+        // nothing reaches the step
+        let Some(defined) = &facts.before[i] else {
+            continue;
+        };
+        let undefined = match step {
+            // an operand must be assigned at the end of the block it comes from
+            Step::Phi(_, ops) => ops.iter().find_map(|(l, v)| match (v, &results.block_out[block_of[l]]) {
+                (Val::Var(x), Some(out)) if !out.contains(x) => Some(*x),
+                _ => None,
+            }),
+            _ => step.uses().into_iter().find(|x| !defined.contains(x)),
+        };
+        if let Some(x) = undefined {
+            return Err(format!("`{}` reads `{x}`, which some path doesn't assign", step_to_string(step)));
+        }
+    }
+    return Ok(());
+}
+
 // fn get_uniq_name(s: Symbol, idx: u32) -> Symbol{
 //     Symbol::new (format!("uniq_{s}_{idx}"))
 // }
@@ -102,7 +326,7 @@ pub fn anf_to_ir(p: &FlatProgram) -> Prog {
     Prog {
         defs: defs,
         main: Block {
-            steps: anf_to_ir_block(&p.main, &Symbol::new("rax"), &Symbol::new(""), &mut i),
+            steps: anf_to_ir_block(&p.main, &Symbol::new("rax"), &(Symbol::new(""), Symbol::new("")), &mut i),
         },
     }
 }
@@ -119,12 +343,12 @@ fn anf_to_ir_def(d: &FlatDefinition, i: &mut i32) -> Def {
         name: d.name.clone(), 
         args: args, 
         body: Block {
-            steps: anf_to_ir_block(&d.body, &Symbol::new("rax"), &Symbol::new(""), i)
+            steps: anf_to_ir_block(&d.body, &Symbol::new("rax"), &(Symbol::new(""), Symbol::new("")), i)
         }
     };
 }
 
-pub fn anf_to_ir_block(b: &FlatBlock, target: &Symbol, brake: &Symbol, i: &mut i32) -> Vec<Step> {
+pub fn anf_to_ir_block(b: &FlatBlock, target: &Symbol, brake: &(Symbol, Symbol), i: &mut i32) -> Vec<Step> {
     match b {
         FlatBlock::Let(name, op, body) => {
             // let new_bound_vars;
@@ -162,7 +386,7 @@ pub fn anf_to_ir_block(b: &FlatBlock, target: &Symbol, brake: &Symbol, i: &mut i
     }
 }
 
-pub fn anf_to_ir_expr(op: &FlatOp, target: &Symbol, brake: &Symbol, i: &mut i32) -> Vec<Step> {
+pub fn anf_to_ir_expr(op: &FlatOp, target: &Symbol, brake: &(Symbol, Symbol), i: &mut i32) -> Vec<Step> {
     match op {
         FlatOp::If(v, b1, b2) => {
             /*
@@ -208,67 +432,67 @@ pub fn anf_to_ir_expr(op: &FlatOp, target: &Symbol, brake: &Symbol, i: &mut i32)
             steps
         }
         FlatOp::Break(v) => {
-            if brake.to_string() == "" {
-                raise_break_outside_loop()
-            }
+            // check::check_program rejects programs with a break outside of a loop
+            assert!(brake.0.to_string() != "", "break outside loop");
             let v = anf_to_ir_val(v);//, bound_vars);
+            // the value of the loop, wherever the break is
             vec![
-                target_step(target, IRExpr::Val(v)),
-                Step::Goto(*brake),
+                target_step(&brake.1, IRExpr::Val(v)),
+                Step::Goto(brake.0),
             ]
         }
         FlatOp::Loop(e) => {
             let loop_label = new_label(i, "loop");
             let end_label = new_label(i, "end");
-            let mut steps = anf_to_ir_block(e, target, &end_label, i);//, bound_vars);
+            let mut steps = anf_to_ir_block(e, &Symbol::new(""), &(end_label, *target), i);//, bound_vars);
             steps.insert(0, Step::Label(loop_label.clone()));
             steps.push(Step::Goto(loop_label.clone()));
             steps.push(Step::Label(end_label.clone()));
             steps
         }
-        FlatOp::Add1(v) => {
+        FlatOp::Add1(v, span) => {
             let v = anf_to_ir_val(v);//bound_vars);
             vec![Step::Check(CheckType::CheckIsNum(v)),
                  target_step(target, IRExpr::Add1(v)),
-                 Step::Check(CheckType::CheckOverflow)]
+                 Step::Check(CheckType::CheckOverflow(*span))]
         }
-        FlatOp::Sub1(v) => {
+        FlatOp::Sub1(v, span) => {
             let v = anf_to_ir_val(v);//bound_vars);
             vec![Step::Check(CheckType::CheckIsNum(v)),
                  target_step(target, IRExpr::Sub1(v)),
-                 Step::Check(CheckType::CheckOverflow)]
+                 Step::Check(CheckType::CheckOverflow(*span))]
         }
-        FlatOp::Plus(v1, v2) => {
+        FlatOp::Plus(v1, v2, span) => {
             let v1 = anf_to_ir_val(v1);//bound_vars);
             let v2 = anf_to_ir_val(v2);//bound_vars);
             vec![Step::Check(CheckType::CheckIsNum(v1)),
                  Step::Check(CheckType::CheckIsNum(v2)),
                  target_step(target, IRExpr::Plus(v1, v2)),
-                 Step::Check(CheckType::CheckOverflow)]
+                 Step::Check(CheckType::CheckOverflow(*span))]
         }
-        FlatOp::Minus(v1, v2) => {
+        FlatOp::Minus(v1, v2, span) => {
             let v1 = anf_to_ir_val(v1);//bound_vars);
             let v2 = anf_to_ir_val(v2);//bound_vars);
             vec![Step::Check(CheckType::CheckIsNum(v1)),
                  Step::Check(CheckType::CheckIsNum(v2)),
                  target_step(target, IRExpr::Minus(v1, v2)),
-                 Step::Check(CheckType::CheckOverflow)]
+                 Step::Check(CheckType::CheckOverflow(*span))]
         }
-        FlatOp::Times(v1, v2) => {
+        FlatOp::Times(v1, v2, span) => {
             let v1 = anf_to_ir_val(v1);//bound_vars);
             let v2 = anf_to_ir_val(v2);//bound_vars);
             vec![Step::Check(CheckType::CheckIsNum(v1)),
                  Step::Check(CheckType::CheckIsNum(v2)),
                  target_step(target, IRExpr::Times(v1, v2)),
-                 Step::Check(CheckType::CheckOverflow)]
+                 Step::Check(CheckType::CheckOverflow(*span))]
         }
-        FlatOp::Divide(v1, v2) => {
+        FlatOp::Divide(v1, v2, span) => {
             let v1 = anf_to_ir_val(v1);//bound_vars);
             let v2 = anf_to_ir_val(v2);//bound_vars);
             vec![Step::Check(CheckType::CheckIsNum(v1)),
                  Step::Check(CheckType::CheckIsNum(v2)),
                  target_step(target, IRExpr::Divide(v1, v2)),
-                 Step::Check(CheckType::CheckOverflow)]
+                 Step::Check(CheckType::CheckOverflow(*span))]
         }
         FlatOp::Eq(v1, v2) => {
             let v1 = anf_to_ir_val(v1);//bound_vars);
@@ -334,6 +558,24 @@ pub fn anf_to_ir_expr(op: &FlatOp, target: &Symbol, brake: &Symbol, i: &mut i32)
             }
             vec![target_step(target, IRExpr::Call(name.clone(), argvals))]
         }
+        FlatOp::Lambda(..) => unreachable!("lambdas are lifted out by anf_program"),
+        FlatOp::MakeClosure(name, arity, fvs) => {
+            let mut vals = vec![];
+            for v in fvs {
+                vals.push(anf_to_ir_val(v));
+            }
+            vec![target_step(target, IRExpr::MakeClosure(*name, *arity, vals))]
+        }
+        FlatOp::CallIndirect(fun, args) => {
+            let f = anf_to_ir_val(fun);
+            let mut argvals = vec![];
+            for a in args {
+                argvals.push(anf_to_ir_val(a));
+            }
+            vec![Step::Check(CheckType::CheckCallable(f, args.len())),
+                 target_step(target, IRExpr::CallIndirect(f, argvals))]
+        }
+        FlatOp::ClosureGet(v, idx) => vec![target_step(target, IRExpr::ClosureGet(anf_to_ir_val(v), *idx))],
         FlatOp::MakeVec(len, val) => {
             let v1 = anf_to_ir_val(len);//, bound_vars);
             let v2 = anf_to_ir_val(val);//, bound_vars);
@@ -398,12 +640,9 @@ fn target_step(target: &Symbol, e: IRExpr) -> Step {
     }
 }
 
-fn raise_break_outside_loop() {
-    panic!("break outside loop")
-}
-
 pub fn ir_to_string(p : &Prog) -> String {
     let mut s = String::new();
+    s.push_str(&format!("calls:\n{}\n", CallGraph::new(p)));
     for def in &p.defs {
         s.push_str(&def_to_string(def));
     }
@@ -438,34 +677,40 @@ fn block_to_string(b : &Block) -> String {
             Step::Label(l) => {
                 s.push_str(&format!("\n{}:\n", l));
             }
-            Step::If(v, l, r) => {
-                s.push_str(&format!("if\t{} {} {}\n", val_to_string(v), l, r));
-            }
-            Step::Goto(l) => {
-                s.push_str(&format!("goto\t{}\n", l));
-            }
-            Step::Do(e) => {
-                s.push_str(&format!("{}\n", expr_to_string(e)));
+            _ => {
+                s.push_str(&format!("{}\n", step_to_string(step)));
             }
-            Step::Set(name, e) => {
-                s.push_str(&format!("{}\t<- {}\n", name, expr_to_string(e)));
-            }
-            Step::Check(ctype) => {
-                match ctype {
-                    CheckType::CheckIsNum(v) => s.push_str(&format!("CHECKISNUM {}\n", val_to_string(v))),
-                    CheckType::CheckIsVec(v) => s.push_str(&format!("CHECKISVEC {}\n", val_to_string(v))),
-                    CheckType::CheckIsNotNil(v) => s.push_str(&format!("CHECKISNOTNIL {}\n", val_to_string(v))),
-                    CheckType::CheckEq(v1, v2) => s.push_str(&format!("CHECKEQ {} {}\n", val_to_string(v1), val_to_string(v2))),
-                    CheckType::CheckBounds(v1, v2) => s.push_str(&format!("CHECKBOUNDS {} {}\n", val_to_string(v1), val_to_string(v2))),
-                    CheckType::CheckOverflow => s.push_str(&format!("CHECKOVERFLOW\n")),
-                }
-            },
         }
     }
     s
 }
 
-fn expr_to_string(e : &IRExpr) -> String {
+pub fn step_to_string(step: &Step) -> String {
+    match step {
+        Step::Label(l) => format!("{}:", l),
+        Step::If(v, l, r) => format!("if\t{} {} {}", val_to_string(v), l, r),
+        Step::Goto(l) => format!("goto\t{}", l),
+        Step::Do(e) => expr_to_string(e),
+        Step::Set(name, e) => format!("{}\t<- {}", name, expr_to_string(e)),
+        Step::Check(ctype) => {
+            match ctype {
+                CheckType::CheckIsNum(v) => format!("CHECKISNUM {}", val_to_string(v)),
+                CheckType::CheckIsVec(v) => format!("CHECKISVEC {}", val_to_string(v)),
+                CheckType::CheckIsNotNil(v) => format!("CHECKISNOTNIL {}", val_to_string(v)),
+                CheckType::CheckEq(v1, v2) => format!("CHECKEQ {} {}", val_to_string(v1), val_to_string(v2)),
+                CheckType::CheckBounds(v1, v2) => format!("CHECKBOUNDS {} {}", val_to_string(v1), val_to_string(v2)),
+                CheckType::CheckOverflow(_) => "CHECKOVERFLOW".to_string(),
+                CheckType::CheckCallable(v, arity) => format!("CHECKCALLABLE {} {}", val_to_string(v), arity),
+            }
+        },
+        Step::Phi(name, ops) => {
+            let ops: Vec<String> = ops.iter().map(|(l, v)| format!("{}: {}", l, val_to_string(v))).collect();
+            format!("{}\t<- phi [{}]", name, ops.join(", "))
+        }
+    }
+}
+
+pub fn expr_to_string(e : &IRExpr) -> String {
     match e {
         IRExpr::Add1(v) => format!("add1 {}", val_to_string(v)),
         IRExpr::Sub1(v) => format!("sub1 {}", val_to_string(v)),
@@ -497,6 +742,15 @@ fn expr_to_string(e : &IRExpr) -> String {
             }
             s
         },
+        IRExpr::MakeClosure(n, arity, vs) => {
+            let vs: Vec<String> = vs.iter().map(val_to_string).collect();
+            format!("make-closure {}/{} [{}]", n, arity, vs.join(","))
+        },
+        IRExpr::CallIndirect(f, args) => {
+            let args: Vec<String> = args.iter().map(val_to_string).collect();
+            format!("call-closure {}({})", val_to_string(f), args.join(","))
+        },
+        IRExpr::ClosureGet(v, idx) => format!("closure-get {} {}", val_to_string(v), idx),
         IRExpr::MakeVec(v1, v2) => format!("make-vec {} {}", val_to_string(v1), val_to_string(v2)),
         IRExpr::Vec(vs) => {
             let mut s = String::new();
@@ -529,4 +783,79 @@ fn val_to_string(v : &Val) -> String {
         Val::Input => format!("input"),
         Val::Nil => format!("nil"),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(s: &str) -> Symbol {
+        Symbol::new(s)
+    }
+
+    fn var(x: &str) -> Val {
+        Val::Var(sym(x))
+    }
+
+    fn set(x: &str, v: Val) -> Step {
+        Step::Set(sym(x), IRExpr::Val(v))
+    }
+
+    fn label(l: &str) -> Step {
+        Step::Label(sym(l))
+    }
+
+    fn goto(l: &str) -> Step {
+        Step::Goto(sym(l))
+    }
+
+    /// `main` with the given steps
+    fn prog(steps: Vec<Step>) -> Prog {
+        Prog { defs: vec![], main: Block { steps } }
+    }
+
+    /// An `if` assigning `x` in one branch and `y` in the other, joined by a phi with `ops`
+    fn join(ops: &[(&str, &str)]) -> Prog {
+        prog(vec![
+            label("start"),
+            Step::If(Val::True, sym("thn"), sym("els")),
+            label("thn"),
+            set("x", Val::Num(1)),
+            goto("end"),
+            label("els"),
+            set("y", Val::Num(2)),
+            goto("end"),
+            label("end"),
+            Step::Phi(sym("z"), ops.iter().map(|(l, x)| (sym(l), var(x))).collect()),
+            set("rax", var("z")),
+        ])
+    }
+
+    #[test]
+    fn accepts_phis_from_every_predecessor() {
+        assert_eq!(verify(&join(&[("thn", "x"), ("els", "y")])), Ok(()));
+    }
+
+    #[test]
+    fn rejects_use_before_definition() {
+        let p = prog(vec![set("x", var("y")), set("y", Val::Num(1)), set("rax", var("x"))]);
+        let err = verify(&p).unwrap_err();
+        assert!(err.contains("reads `y`, which some path doesn't assign"), "{err}");
+    }
+
+    #[test]
+    fn rejects_phi_labels_that_arent_the_predecessors() {
+        let err = verify(&join(&[("thn", "x"), ("start", "y")])).unwrap_err();
+        assert!(err.contains("has operands from `start`, `thn`, but control comes from `els`, `thn`"), "{err}");
+        let err = verify(&join(&[("thn", "x")])).unwrap_err();
+        assert!(err.contains("has operands from `thn`, but control comes from `els`, `thn`"), "{err}");
+    }
+
+    #[test]
+    fn rejects_missing_labels() {
+        let p = prog(vec![set("rax", Val::Num(1)), goto("nowhere")]);
+        let err = verify(&p).unwrap_err();
+        assert!(err.contains("refers to label `nowhere`, which isn't in the same body"), "{err}");
+        let err = verify(&join(&[("thn", "x"), ("nowhere", "y")])).unwrap_err();
+        assert!(err.contains("refers to label `nowhere`"), "{err}");
+    }
+}
@@ -1,6 +1,9 @@
-use std::collections::{HashMap as MutableMap};
+use std::collections::{HashMap as MutableMap, HashSet};
 
 use crate::ir::*;
+use crate::cfg::Cfg;
+use crate::dataflow;
+use crate::regalloc::{self, Home};
 use crate::syntax::{Symbol};
 use crate::{
     asm::{
@@ -8,13 +11,15 @@ use crate::{
         Reg::{self, *},
         Reg32,
         StrOp::Stosq,
-    }};
+    },
+    peephole};
 use crate::mref;
 
 const INVALID_ARG: &str = "invalid_argument";
 const OVERFLOW: &str = "overflow";
 const INDEX_OUT_OF_BOUNDS: &str = "index_out_of_bounds";
 const INVALID_SIZE: &str = "invalid_vec_size";
+const WRONG_ARITY: &str = "wrong_arity";
 
 const STACK_BASE: Reg = Rbx;
 const INPUT_REG: Reg = R13;
@@ -22,26 +27,63 @@ const HEAP_END: Reg = R14;
 const HEAP_PTR: Reg = R15;
 const CHECK_REG: Reg = Rdx;
 const CHECK_REG2: Reg = R10;
+/// Registers variables can be allocated to. None of them is used as a scratch register by the
+/// code generated for a step, except for `R8` and `R9` when passing arguments to the GC, which
+/// only happens after the registers have been saved.
+const ALLOC_REGS: [Reg; 4] = [R12, R9, R11, R8];
 
 const NIL: i32 = 0b001;
+/// Closures are tagged with `0b101` instead of the `0b001` of vectors. On the heap they look like
+/// a vector holding the code pointer and the arity (both as snek numbers, so the GC skips them)
+/// followed by the captured values.
+const CLOSURE_TAG: i32 = 0b101;
+const CLOSURE_CODE: i32 = 16 - CLOSURE_TAG;
+const CLOSURE_ARITY: i32 = 24 - CLOSURE_TAG;
+const CLOSURE_ENV: i32 = 32 - CLOSURE_TAG;
 const MEM_SET_VAL: i32 = NIL;
 const GC_WORD_VAL: i32 = 0;
 
 struct IRSession {
     instrs: Vec<Instr>,
-    funs: MutableMap<Symbol,usize>,
-    tag: u32
+    tag: u32,
+    /// Stack words a call to each function reserves for its arguments, see `arg_spaces`
+    arg_spaces: MutableMap<Symbol, usize>,
+    /// Stack words an indirect call reserves, enough for any function a closure may point to
+    closure_arg_space: usize,
+    /// Stack slots of the function being compiled
+    frame_size: u32,
+    /// Registers to save when calling out, for every step of the function being compiled
+    step_live_regs: Vec<Vec<Reg>>,
+    /// Registers to save when calling out from the step being compiled
+    live_regs: Vec<Reg>,
+    /// Variables live around every step of the function being compiled
+    liveness: dataflow::Liveness,
+    /// Offsets from `rbp` of the frame words holding the variables a call made by the step being
+    /// compiled returns to, i.e. the ones live after the step
+    call_roots: Vec<i32>,
+    /// Offsets from `rbp` of the frame words holding the variables the step being compiled may
+    /// still read after collecting, i.e. the ones live before it
+    gc_roots: Vec<i32>,
+    /// Label and offsets from `rbp` of the live stack words of every call that may end up in the
+    /// GC, see `stack_map`
+    stack_maps: Vec<(String, Vec<i32>)>,
 }
 
-pub fn compile_ir_prog(prg: &Prog) -> String {
-    let mut funs:MutableMap<Symbol, usize> = MutableMap::new();
-    for def in &prg.defs[..] {
-        funs.insert(def.name, def.args.len());
-    }
-    let mut sess = IRSession::new(funs);
+pub fn compile_ir_prog(prg: &Prog, peephole: bool) -> String {
+    let mut sess = IRSession::new();
+    sess.arg_spaces = arg_spaces(&prg.defs);
+    let blocks = std::iter::once(&prg.main).chain(prg.defs.iter().map(|d| &d.body));
+    sess.closure_arg_space = blocks
+        .flat_map(|b| &b.steps)
+        .filter_map(|step| match step {
+            Step::Set(_, IRExpr::MakeClosure(fun, ..)) => Some(sess.arg_spaces[fun]),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
     sess.compile_defs(&prg.defs);
     sess.emit_instr(Instr::Label("our_code_starts_here".to_string()));
-    let callee_saved = [Rbp, STACK_BASE, INPUT_REG, HEAP_END, HEAP_PTR];
+    let callee_saved = [Rbp, STACK_BASE, INPUT_REG, HEAP_END, HEAP_PTR, R12];
     let mut env = sess.fun_entry(&prg.main, &vec![], &callee_saved);
     sess.emit_instrs([
         Instr::Mov(MovArgs::ToReg(STACK_BASE, Arg64::Reg(Rbp))),
@@ -50,8 +92,10 @@ pub fn compile_ir_prog(prg: &Prog) -> String {
         Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
     ]);
     //let env = calc_env(&prg.main);
-    sess.compile_ir_block(&prg.main, &mut env, &Symbol::new("main"));
-    sess.fun_exit(&env, &callee_saved);
+    sess.compile_ir_block(&prg.main, &mut env, &Symbol::new("main"), &HashSet::new(), &callee_saved);
+    sess.fun_exit(&callee_saved);
+    let stack_maps = sess.stack_maps_to_string();
+    let instrs = if peephole { peephole::optimize(&sess.instrs) } else { sess.instrs };
     format!(
                 "
 section .text
@@ -61,6 +105,8 @@ extern snek_alloc_vec
 extern snek_print_stack
 extern snek_try_gc
 extern snek_gc
+extern snek_remember
+extern snek_nursery_start
 global our_code_starts_here
 {}
 {INVALID_ARG}:
@@ -75,7 +121,78 @@ global our_code_starts_here
 {INVALID_SIZE}:
   mov edi, 4
   call snek_error
-",                 instrs_to_string(&sess.instrs))
+{WRONG_ARITY}:
+  mov edi, 6
+  call snek_error
+{stack_maps}",                 instrs_to_string(&instrs))
+}
+
+/// Indices of the `rax <- f(...)` steps of a function body whose result is returned as is, i.e.
+/// only labels and gotos lie between the call and the end of the body.
+fn tail_calls(b: &Block) -> HashSet<usize> {
+    let cfg = Cfg::from_block(b);
+    let only_jumps = |steps: &[Step]| steps.iter().all(|step| matches!(step, Step::Label(_) | Step::Goto(_)));
+    // blocks that go straight to the exit, without doing anything on the way
+    let mut returns = vec![false; cfg.blocks.len()];
+    returns[cfg.exit] = true;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, bb) in cfg.blocks.iter().enumerate() {
+            if !returns[i] && only_jumps(&bb.steps) && bb.succs.iter().all(|s| returns[*s]) {
+                returns[i] = true;
+                changed = true;
+            }
+        }
+    }
+    let mut tail_calls = HashSet::new();
+    let mut idx = 0;
+    for bb in &cfg.blocks {
+        for (j, step) in bb.steps.iter().enumerate() {
+            let is_tail = match step {
+                Step::Set(x, IRExpr::Call(..)) => x.to_string() == "rax"
+                    && only_jumps(&bb.steps[j + 1..])
+                    && bb.succs.iter().all(|s| returns[*s]),
+                _ => false,
+            };
+            if is_tail {
+                tail_calls.insert(idx + j);
+            }
+        }
+        idx += bb.steps.len();
+    }
+    tail_calls
+}
+
+/// Stack words a call to each function reserves for its arguments: enough for its own and for
+/// those of every function it may tail call, since a tail call writes its arguments over the ones
+/// its caller was called with. Functions without tail calls only reserve their own.
+fn arg_spaces(defs: &[Def]) -> MutableMap<Symbol, usize> {
+    let tail_callees: Vec<Vec<Symbol>> = defs.iter().map(|d| {
+        tail_calls(&d.body).into_iter().filter_map(|i| match &d.body.steps[i] {
+            Step::Set(_, IRExpr::Call(fun, _)) => Some(*fun),
+            _ => None,
+        }).collect()
+    }).collect();
+    let mut spaces: MutableMap<Symbol, usize> = defs.iter().map(|d| (d.name, d.args.len())).collect();
+    // tail calls may be mutually recursive, so go on until no space grows
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (d, callees) in defs.iter().zip(&tail_callees) {
+            let space = callees.iter().filter_map(|f| spaces.get(f)).copied().fold(spaces[&d.name], usize::max);
+            if space > spaces[&d.name] {
+                spaces.insert(d.name, space);
+                changed = true;
+            }
+        }
+    }
+    spaces
+}
+
+/// Rounds a number of stack words up to keep `rsp` 16-byte aligned
+fn even(words: usize) -> usize {
+    words + words % 2
 }
 
 fn hard_coded_reg (s: &Symbol) -> bool {
@@ -94,58 +211,71 @@ fn get_hard_coded_reg (s: &Symbol) -> Reg {
 }
 
 impl IRSession {
-    fn new(funs: MutableMap<Symbol, usize>) -> IRSession {
-        IRSession { instrs: vec![], funs: funs, tag: 0 }
+    fn new() -> IRSession {
+        IRSession {
+            instrs: vec![], tag: 0, arg_spaces: MutableMap::new(), closure_arg_space: 0, frame_size: 0, step_live_regs: vec![], live_regs: vec![],
+            liveness: dataflow::Liveness { live_in: vec![], live_out: vec![] },
+            call_roots: vec![], gc_roots: vec![], stack_maps: vec![],
+        }
     }
 
-    fn fun_entry(&mut self, b: &Block, args: &Vec<Symbol>, callee_saved: &[Reg]) -> MutableMap<Symbol, i32>{
+    fn fun_entry(&mut self, b: &Block, args: &Vec<Symbol>, callee_saved: &[Reg]) -> MutableMap<Symbol, Loc>{
         let mut env = MutableMap::new();
         for reg in callee_saved {
             self.emit_instr(Instr::Push(Arg32::Reg(*reg)));
         }
-        for step in &b.steps {
-            match step {
-                Step::Set(x, _) => {
-                    if !env.contains_key(x) && !hard_coded_reg(x){
-                        let offset = (env.len()) as i32;
-                        env.insert(x.clone(), offset + 1);
-                    }
+        let alloc = regalloc::allocate(b, args, &ALLOC_REGS, hard_coded_reg);
+        let mut used_regs = vec![];
+        for (x, home) in alloc.homes {
+            let loc = match home {
+                Home::Reg(reg) => {
+                    used_regs.push(reg);
+                    Loc::Reg(reg)
                 }
-                _ => {}
-            }
+                Home::Stack(slot) => Loc::Mem(mref![Rbp - %(8 * (slot + 1))]),
+            };
+            env.insert(x, loc);
         }
         for i in 0..args.len() {
-            env.insert(args[i].clone(), (-1-(callee_saved.len() as i32))-(i as i32));
+            env.insert(args[i].clone(), Loc::Mem(mref![Rbp + %(8 * (callee_saved.len() + 1 + i) as i32)]));
         }
-        let mut size = env.len() + callee_saved.len()+1;
+        let mut size = alloc.slots as usize + callee_saved.len()+1;
         if size % 2 == 0 {
-            size = env.len();
+            size = alloc.slots as usize;
         } else {
-            size = env.len()+1
+            size = alloc.slots as usize+1
         }
+        self.frame_size = size as u32;
+        self.step_live_regs = alloc.live_regs;
+        self.liveness = dataflow::liveness(b);
         self.emit_instrs([
             Instr::Mov(MovArgs::ToReg(Rbp, Arg64::Reg(Rsp))),
             Instr::Sub(BinArgs::ToReg(Rsp, Arg32::Imm(8 * (size as i32)))),
         ]);
         self.memset(0, size as u32, Reg32::Imm(MEM_SET_VAL));
+        // like the stack slots, registers must not hold stale heap references the GC could find
+        used_regs.sort_by_key(|reg| ALLOC_REGS.iter().position(|r| r == reg));
+        used_regs.dedup();
+        for reg in used_regs {
+            self.emit_instr(Instr::Mov(MovArgs::ToReg(reg, Arg64::Imm(MEM_SET_VAL as i64))));
+        }
         env
     }
     
-    fn fun_exit(&mut self, env: &MutableMap<Symbol, i32>, callee_saved: &[Reg]) {
-        let mut size = env.len() + callee_saved.len()+1;
-        if size % 2 == 0 {
-            size = env.len();
-        } else {
-            size = env.len()+1
-        }
+    fn fun_exit(&mut self, callee_saved: &[Reg]) {
+        self.fun_teardown(callee_saved);
+        self.emit_instr(Instr::Ret);
+    }
+
+    /// Pops the frame set up by `fun_entry`, leaving the return address on top of the stack
+    fn fun_teardown(&mut self, callee_saved: &[Reg]) {
         self.emit_instrs([Instr::Add(BinArgs::ToReg(
             Rsp,
-            Arg32::Imm(8 * (size as i32)),
+            Arg32::Imm(8 * (self.frame_size as i32)),
         ))]);
         for reg in callee_saved.iter().rev() {
             self.emit_instr(Instr::Pop(Loc::Reg(*reg)));
         }
-        self.emit_instr(Instr::Ret);
     }
 
     fn compile_defs(&mut self, defs: &[Def]) {
@@ -157,17 +287,50 @@ impl IRSession {
     fn compile_ir_def(&mut self, d: &Def, callee_saved: &[Reg]) {
         self.emit_instr(Instr::Label(d.name.to_string()));
         let mut env = self.fun_entry(&d.body, &d.args, callee_saved);
-        self.compile_ir_block(&d.body, &mut env, &d.name);
-        self.fun_exit(&env, callee_saved);
+        let tail_calls = tail_calls(&d.body);
+        self.compile_ir_block(&d.body, &mut env, &d.name, &tail_calls, callee_saved);
+        self.fun_exit(callee_saved);
+    }
+
+    /// Calls `fun` by reusing the current frame: the arguments are written over our own incoming
+    /// arguments and we jump to `fun` with our return address still on the stack, so `fun` returns
+    /// straight to our caller.
+    fn compile_tail_call(&mut self, fun: &Symbol, args: &[Val], env: &mut MutableMap<Symbol, Loc>, callee_saved: &[Reg]) {
+        // the new arguments may be computed from the old ones, so stage them on the stack first
+        for arg in args {
+            self.compile_ir_val(arg, Loc::Reg(Rcx), env);
+            self.emit_instr(Instr::Push(Arg32::Reg(Rcx)));
+        }
+        for i in (0..args.len()).rev() {
+            let offset = 8 * (callee_saved.len() + 1 + i) as i32;
+            self.emit_instrs([
+                Instr::Pop(Loc::Reg(Rcx)),
+                Instr::Mov(MovArgs::ToMem(mref![Rbp + %(offset)], Reg32::Reg(Rcx))),
+            ]);
+        }
+        self.fun_teardown(callee_saved);
+        self.emit_instr(Instr::Jmp(fun.to_string()));
     }
 
-    fn compile_ir_block(&mut self, b : &Block, env: &mut MutableMap<Symbol, i32>, lbl: &Symbol) {
-        for step in &b.steps {
-            self.compile_ir_step(&step, env, lbl);
+    fn compile_ir_block(&mut self, b : &Block, env: &mut MutableMap<Symbol, Loc>, lbl: &Symbol, tail_calls: &HashSet<usize>, callee_saved: &[Reg]) {
+        for (i, step) in b.steps.iter().enumerate() {
+            self.live_regs = self.step_live_regs[i].clone();
+            let mut after = self.liveness.live_out[i].clone();
+            if let Some(x) = step.def() {
+                after.remove(&x);
+            }
+            self.call_roots = self.frame_roots(&after, env);
+            self.gc_roots = self.frame_roots(&self.liveness.live_in[i], env);
+            match step {
+                Step::Set(_, IRExpr::Call(fun, args)) if tail_calls.contains(&i) => {
+                    self.compile_tail_call(fun, args, env, callee_saved);
+                }
+                _ => self.compile_ir_step(step, env, lbl),
+            }
         }
     }
 
-    fn compile_ir_step(&mut self, s : &Step, env: &mut MutableMap<Symbol, i32>, lbl : &Symbol){
+    fn compile_ir_step(&mut self, s : &Step, env: &mut MutableMap<Symbol, Loc>, lbl : &Symbol){
         match s {
             Step::Label(l) => self.emit_instr(Instr::Label(format!("{lbl}_{l}"))),
             Step::If(v, thn, els) => {
@@ -179,20 +342,21 @@ impl IRSession {
                 ]);
             }
             Step::Goto(l) => self.emit_instr(Instr::Jmp(format!("{lbl}_{l}"))),
+            Step::Phi(..) => unreachable!("phi nodes are removed before code generation"),
             Step::Do(e) => self.compile_ir_expr(e, env),
             Step::Set(x, e) => {
                 if hard_coded_reg(x){
                     self.compile_ir_expr(e, env);
                     self.emit_instr(Instr::Mov(MovArgs::ToReg(get_hard_coded_reg(x), Arg64::Reg(Rax))));
                 } else {
-                    let offset = match env.get(x) {
-                        Some(offset) => (*offset) * 8,
+                    let loc = match env.get(x) {
+                        Some(loc) => *loc,
                         None => {
                             panic!("Unbound identifier {x}")
                         }
                     };
                     self.compile_ir_expr(e, env);
-                    self.emit_instr(Instr::Mov(MovArgs::ToMem(mref![Rbp - %(offset)], Reg32::Reg(Rax))));
+                    self.move_to(loc, Arg64::Reg(Rax));
                 }
             }
             Step::Check(ctype) => {
@@ -202,7 +366,7 @@ impl IRSession {
                             Val::Num(_) => return,
                             Val::Input => {
                                 self.emit_instrs([
-                                    Instr::Test(BinArgs::ToReg(Rdi, Arg32::Imm(0b001))),
+                                    Instr::Test(BinArgs::ToReg(INPUT_REG, Arg32::Imm(0b001))),
                                     Instr::Jnz(INVALID_ARG.to_string()),
                                 ]);
                             },
@@ -223,8 +387,8 @@ impl IRSession {
                                 self.emit_instrs([
                                     Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b001))),
                                     Instr::Jz(INVALID_ARG.to_string()), // jump if is num
-                                    Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b010))),
-                                    Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool
+                                    Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b110))),
+                                    Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool or closure
                                 ]);
                             },
                             Val::Nil => return,
@@ -290,13 +454,13 @@ impl IRSession {
                                 let check_eq_finish_lbl = format!("check_eq_finish_{tag}");
                                 self.compile_ir_var(var.clone(), Loc::Reg(CHECK_REG), env);
                                 self.emit_instrs([
-                                    Instr::Xor(BinArgs::ToReg(CHECK_REG, Arg32::Reg(Rdi))),
+                                    Instr::Xor(BinArgs::ToReg(CHECK_REG, Arg32::Reg(INPUT_REG))),
                                     Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b11))),
                                     Instr::Jz(check_eq_finish_lbl.to_string()),
                                 ]);
                                 self.compile_ir_var(var.clone(), Loc::Reg(CHECK_REG), env);
                                 self.emit_instrs([
-                                    Instr::Or(BinArgs::ToReg(CHECK_REG, Arg32::Reg(Rdi))),
+                                    Instr::Or(BinArgs::ToReg(CHECK_REG, Arg32::Reg(INPUT_REG))),
                                     Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b01))),
                                     Instr::Jnz(INVALID_ARG.to_string()),
                                     Instr::Label(check_eq_finish_lbl.to_string()),
@@ -305,7 +469,7 @@ impl IRSession {
                             (Val::Input, Val::Num(_)) |
                             (Val::Num(_), Val::Input) => {
                                 self.emit_instrs([
-                                    Instr::Test(BinArgs::ToReg(Rdi, Arg32::Imm(0b001))),
+                                    Instr::Test(BinArgs::ToReg(INPUT_REG, Arg32::Imm(0b001))),
                                     Instr::Jnz(INVALID_ARG.to_string()),
                                 ]);                            
                             },
@@ -314,7 +478,7 @@ impl IRSession {
                             (Val::Input, Val::True) |
                             (Val::Input, Val::False) => {
                                 self.emit_instrs([
-                                    Instr::Mov(MovArgs::ToReg(CHECK_REG, Arg64::Reg(Rdi))),
+                                    Instr::Mov(MovArgs::ToReg(CHECK_REG, Arg64::Reg(INPUT_REG))),
                                     Instr::And(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b011))),
                                     Instr::Cmp(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b011))),
                                     Instr::Jnz(INVALID_ARG.to_string()),
@@ -359,8 +523,8 @@ impl IRSession {
                                     self.emit_instrs([
                                         Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b001))),
                                         Instr::Jz(INVALID_ARG.to_string()), // jump if is num
-                                        Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b010))),
-                                        Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool
+                                        Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b110))),
+                                        Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool or closure
                                         Instr::Cmp(BinArgs::ToReg(CHECK_REG, Arg32::Imm(NIL))),
                                         Instr::Jz(INVALID_ARG.to_string()), // jump if exactly equal to 1
                                         Instr::Sub(BinArgs::ToReg(CHECK_REG, Arg32::Imm(1))),
@@ -382,8 +546,8 @@ impl IRSession {
                                 self.emit_instrs([
                                     Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b001))),
                                     Instr::Jz(INVALID_ARG.to_string()), // jump if is num
-                                    Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b010))),
-                                    Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool
+                                    Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b110))),
+                                    Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool or closure
                                     Instr::Cmp(BinArgs::ToReg(CHECK_REG, Arg32::Imm(NIL))),
                                     Instr::Jz(INVALID_ARG.to_string()), // jump if exactly equal to 1
                                     Instr::Sub(BinArgs::ToReg(CHECK_REG, Arg32::Imm(1))),
@@ -397,16 +561,16 @@ impl IRSession {
                             }
                             (Val::Var(var), Val::Input) => {
                                 self.emit_instrs([ // test input is a num
-                                    Instr::Test(BinArgs::ToReg(Rdi, Arg32::Imm(0b001))),
+                                    Instr::Test(BinArgs::ToReg(INPUT_REG, Arg32::Imm(0b001))),
                                     Instr::Jnz(INVALID_ARG.to_string()),
-                                    Instr::Mov(MovArgs::ToReg(CHECK_REG2, Arg64::Reg(Rdi))),
+                                    Instr::Mov(MovArgs::ToReg(CHECK_REG2, Arg64::Reg(INPUT_REG))),
                                 ]);
                                 self.compile_ir_var(var.clone(), Loc::Reg(CHECK_REG), env);
                                 self.emit_instrs([
                                     Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b001))),
                                     Instr::Jz(INVALID_ARG.to_string()), // jump if is num
-                                    Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b010))),
-                                    Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool
+                                    Instr::Test(BinArgs::ToReg(CHECK_REG, Arg32::Imm(0b110))),
+                                    Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool or closure
                                     Instr::Cmp(BinArgs::ToReg(CHECK_REG, Arg32::Imm(NIL))),
                                     Instr::Jz(INVALID_ARG.to_string()), // jump if exactly equal to 1
                                     Instr::Sub(BinArgs::ToReg(CHECK_REG, Arg32::Imm(1))),
@@ -420,13 +584,29 @@ impl IRSession {
                             }
                         }
                     },
-                    CheckType::CheckOverflow => self.emit_instr(Instr::Jo(OVERFLOW.to_string())),
+                    CheckType::CheckOverflow(_) => self.emit_instr(Instr::Jo(OVERFLOW.to_string())),
+                    CheckType::CheckCallable(v, arity) => {
+                        match v {
+                            Val::Var(var) => {
+                                self.compile_ir_var(var.clone(), Loc::Reg(CHECK_REG), env);
+                                self.emit_instrs([
+                                    Instr::Mov(MovArgs::ToReg(CHECK_REG2, Arg64::Reg(CHECK_REG))),
+                                    Instr::And(BinArgs::ToReg(CHECK_REG2, Arg32::Imm(0b111))),
+                                    Instr::Cmp(BinArgs::ToReg(CHECK_REG2, Arg32::Imm(CLOSURE_TAG))),
+                                    Instr::Jne(INVALID_ARG.to_string()), // jump if not a closure
+                                    Instr::Cmp(BinArgs::ToMem(mref![CHECK_REG + %(CLOSURE_ARITY)], Reg32::Imm((*arity as i32) << 1))),
+                                    Instr::Jne(WRONG_ARITY.to_string()),
+                                ]);
+                            }
+                            _ => self.emit_instr(Instr::Jmp(INVALID_ARG.to_string())),
+                        }
+                    },
                 }
             },
         }
     }
 
-    fn compile_ir_expr(&mut self, e : &IRExpr, env: &mut MutableMap<Symbol, i32>){
+    fn compile_ir_expr(&mut self, e : &IRExpr, env: &mut MutableMap<Symbol, Loc>){
         match e {
             IRExpr::Add1(e) => {
                 self.compile_ir_val(&e, Loc::Reg(Rax), env);
@@ -538,38 +718,112 @@ impl IRSession {
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Imm(3))),
                     Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b01))),
                     Instr::CMov(CMov::Z(Rax, Arg64::Reg(Rcx))),
-                    Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b10))),
+                    Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b110))),
                     Instr::CMov(CMov::NZ(Rax, Arg64::Reg(Rcx))),
                 ]);
             },
             IRExpr::Print(v) => {
                 self.compile_ir_val(&v, Loc::Reg(Rax), env);
+                self.save_live_regs();
                 self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(Rax))),
                     Instr::Call("snek_print".to_string())
                 ]);
+                self.restore_live_regs();
             },
             IRExpr::Call(fun, args) => {
-                let Some(arity) = self.funs.get(fun) else {
-                    return raise_undefined_fun(*fun);
-                };
-                if args.len() != *arity {
-                    raise_wrong_number_of_args(*fun, *arity, args.len());
-                }
-                let mut argspace = args.len();
-                if args.len() % 2 != 0 {
+                self.save_live_regs();
+                let argspace = even(self.arg_spaces.get(fun).map_or(args.len(), |space| args.len().max(*space)));
+                for _ in args.len()..argspace {
                     self.emit_instr(Instr::Push(Arg32::Imm(MEM_SET_VAL)));
-                    argspace += 1;
                 }
                 for arg in args.iter().rev() {
                     self.compile_ir_val(arg, Loc::Reg(Rcx), env);
                     self.emit_instr(Instr::Push(Arg32::Reg(Rcx)));
                 }
 
+                let (_, ret) = self.stack_map(self.call_roots.clone());
                 self.emit_instrs([
                     Instr::Call(fun.to_string()),
+                    Instr::Label(ret),
                     Instr::Add(BinArgs::ToReg(Rsp, Arg32::Imm(8 * argspace as i32))),
                 ]);
+                self.restore_live_regs();
+            },
+            IRExpr::MakeClosure(fun, arity, fvs) => {
+                let tag = self.next_tag();
+                let alloc_finish_lbl = format!("closure_alloc_finish_{tag}");
+
+                let size: i32 = (fvs.len() + 2).try_into().unwrap();
+
+                self.emit_instrs([
+                    Instr::Lea(Rax, mref![HEAP_PTR + %(8 * (size + 2))]),
+                    Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Reg(HEAP_END))),
+                    Instr::Jle(alloc_finish_lbl.clone()),
+                ]);
+                self.save_live_regs();
+                self.emit_instrs([
+                    Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Imm(size as i64 + 2))),
+                    Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(HEAP_PTR))),
+                    Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
+                    Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
+                    Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
+                ]);
+                self.call_gc("snek_try_gc", R9);
+                self.restore_live_regs();
+                self.emit_instrs([
+                    Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
+                    Instr::Label(alloc_finish_lbl),
+                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 0), Reg32::Imm(GC_WORD_VAL))),
+                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 8), Reg32::Imm(size))),
+                    // Code pointer and arity
+                    Instr::LeaLabel(Rcx, fun.to_string()),
+                    Instr::Sal(BinArgs::ToReg(Rcx, Arg32::Imm(1))),
+                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 16), Reg32::Reg(Rcx))),
+                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 24), Reg32::Imm((*arity as i32) << 1))),
+                ]);
+
+                for (i, fv) in fvs.iter().enumerate() {
+                    self.compile_ir_val(fv, Loc::Reg(Rcx), env);
+                    self.move_to(
+                        Loc::Mem(mref!(HEAP_PTR + %(8 * (i + 4)))),
+                        Arg64::Reg(Rcx),
+                    )
+                }
+
+                self.emit_instrs([
+                    Instr::Lea(Rax, mref!(HEAP_PTR + %(CLOSURE_TAG))),
+                    Instr::Lea(HEAP_PTR, mref!(HEAP_PTR + %(8 * (size + 2)))),
+                ]);
+            },
+            IRExpr::CallIndirect(fun, args) => {
+                self.save_live_regs();
+                // the closure is passed as a hidden first argument
+                let argspace = even((args.len() + 1).max(self.closure_arg_space));
+                for _ in args.len() + 1..argspace {
+                    self.emit_instr(Instr::Push(Arg32::Imm(MEM_SET_VAL)));
+                }
+                for arg in args.iter().rev() {
+                    self.compile_ir_val(arg, Loc::Reg(Rcx), env);
+                    self.emit_instr(Instr::Push(Arg32::Reg(Rcx)));
+                }
+                self.compile_ir_val(fun, Loc::Reg(Rax), env);
+                self.emit_instrs([
+                    Instr::Push(Arg32::Reg(Rax)),
+                    Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mref![Rax + %(CLOSURE_CODE)]))),
+                    Instr::Sar(BinArgs::ToReg(Rax, Arg32::Imm(1))),
+                ]);
+                let (_, ret) = self.stack_map(self.call_roots.clone());
+                self.emit_instrs([
+                    Instr::CallReg(Rax),
+                    Instr::Label(ret),
+                    Instr::Add(BinArgs::ToReg(Rsp, Arg32::Imm(8 * argspace as i32))),
+                ]);
+                self.restore_live_regs();
+            },
+            IRExpr::ClosureGet(v, idx) => {
+                self.compile_ir_val(v, Loc::Reg(Rax), env);
+                self.emit_instr(Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mref![Rax + %(CLOSURE_ENV + 8 * (*idx as i32))]))));
             },
             IRExpr::MakeVec(sz, elm) => {
                 let tag = self.next_tag();
@@ -586,11 +840,17 @@ impl IRSession {
                     // Call try_gc to ensure we can allocate `size + 2` quad words
                     // (1 extra for the size of the vector + 1 extra for the GC metadata)
                     Instr::Add(BinArgs::ToReg(Rdi, Arg32::Imm(2))),
+                ]);
+                self.save_live_regs();
+                self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(HEAP_PTR))),
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
                     Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
-                    Instr::Call("snek_try_gc".to_string()),
+                ]);
+                self.call_gc("snek_try_gc", R9);
+                self.restore_live_regs();
+                self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
                     Instr::Label(alloc_finish_lbl),
                 ]);
@@ -625,6 +885,9 @@ impl IRSession {
                     Instr::Lea(Rax, mref![HEAP_PTR + %(8 * (size + 2))]),
                     Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Reg(HEAP_END))),
                     Instr::Jle(vec_alloc_finish_lbl.clone()),
+                ]);
+                self.save_live_regs();
+                self.emit_instrs([
                     // Call try_gc to ensure we can allocate `size + 2` quad words
                     // (1 extra for the size of the vector + 1 extra for the GC metadata)
                     Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Imm(size as i64 + 2))),
@@ -632,7 +895,10 @@ impl IRSession {
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
                     Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
-                    Instr::Call("snek_try_gc".to_string()),
+                ]);
+                self.call_gc("snek_try_gc", R9);
+                self.restore_live_regs();
+                self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
                     Instr::Label(vec_alloc_finish_lbl),
                     // Write GC word in HEAP_PTR
@@ -662,8 +928,10 @@ impl IRSession {
                 self.compile_ir_val(idx, Loc::Reg(Rdi), env);
                 self.emit_instrs([
                     Instr::Sar(BinArgs::ToReg(Rdi, Arg32::Imm(1))),
-                    Instr::Mov(MovArgs::ToMem(mref![Rax + 8 * Rdi + 16], Reg32::Reg(Rcx)))
+                    Instr::Mov(MovArgs::ToMem(mref![Rax + 8 * Rdi + 15], Reg32::Reg(Rcx)))
                 ]);
+                self.write_barrier();
+                self.compile_ir_val(vec, Loc::Reg(Rax), env);
             },
             IRExpr::VecGet(v, ix) => {
                 self.compile_ir_val(v, Loc::Reg(Rax), env);
@@ -684,27 +952,31 @@ impl IRSession {
             },
             IRExpr::Val(v) => self.compile_ir_val(v, Loc::Reg(Rax), env),
             IRExpr::PrintStack => {
+                self.save_live_regs();
                 self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(STACK_BASE))),
                     Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(Rbp))),
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rsp))),
                     Instr::Call("snek_print_stack".to_string()),
                 ]);
+                self.restore_live_regs();
             },
             IRExpr::Gc => {
+                self.save_live_regs();
                 self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(HEAP_PTR))),
                     Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(STACK_BASE))),
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rbp))),
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rsp))),
-                    Instr::Call("snek_gc".to_string()),
                 ]);
+                self.call_gc("snek_gc", R8);
+                self.restore_live_regs();
             },
         }
     }
 
     /// target is assumed to be a *register*
-    fn compile_ir_val(&mut self, v : &Val, target: Loc, env: &mut MutableMap<Symbol, i32>){
+    fn compile_ir_val(&mut self, v : &Val, target: Loc, env: &mut MutableMap<Symbol, Loc>){
         match v {
             Val::Num(n) => self.move_to(target, Arg64::Imm(*n << 1)),//format!("mov {target}, {}", *n << 1),
             Val::True => self.move_to(target, Arg64::Imm(7)),//format!("mov {target}, 7"),
@@ -712,26 +984,26 @@ impl IRSession {
             Val::Input => self.move_to(target, Arg32::Reg(INPUT_REG)),//format!("mov {target}, rdi"),
             Val::Nil => self.move_to(target, Arg64::Imm(1)),//format!("mov {target}, 1"),
             Val::Var(x) => {
-                let offset = match env.get(x) {
-                    Some(offset) => -(*offset) * 8,
+                let loc = match env.get(x) {
+                    Some(loc) => *loc,
                     None => {
                         panic!("Unbound identifier {x}")
                     }
                 };
-                self.move_to(target, Arg64::Mem(MemRef { reg: Rbp, offset: Offset::Constant(offset) }));
+                self.move_to(target, Arg64::from(loc));
                 //format!("mov {target}, [rsp - {offset}] ; {x}")
             }
         }
     }
 
-    fn compile_ir_var(&mut self, var: Symbol, target: Loc, env: &mut MutableMap<Symbol, i32>){
-        let offset = match env.get(&var) {
-            Some(offset) => -(*offset) * 8,
+    fn compile_ir_var(&mut self, var: Symbol, target: Loc, env: &mut MutableMap<Symbol, Loc>){
+        let loc = match env.get(&var) {
+            Some(loc) => *loc,
             None => {
                 panic!("Unbound identifier {var}")
             }
         };
-        self.move_to(target, Arg64::Mem(MemRef { reg: Rbp, offset: Offset::Constant(offset) }));
+        self.move_to(target, Arg64::from(loc));
     }
 
     fn move_to(&mut self, dst: Loc, src: impl Into<Arg64>) {
@@ -761,6 +1033,108 @@ impl IRSession {
         }
     }
 
+    /// Pushes the registers holding live variables before calling out. The stack stays 16 byte
+    /// aligned and, since the pushed values sit between `rsp` and the stack base, the GC sees them
+    /// like any other stack slot.
+    fn save_live_regs(&mut self) {
+        if self.live_regs.len() % 2 != 0 {
+            self.emit_instr(Instr::Push(Arg32::Imm(MEM_SET_VAL)));
+        }
+        for reg in self.live_regs.clone() {
+            self.emit_instr(Instr::Push(Arg32::Reg(reg)));
+        }
+    }
+
+    /// Offsets from `rbp` of the frame words holding `vars` while the step being compiled calls
+    /// out: the home of spilled variables and arguments, and where `save_live_regs` pushed the
+    /// register of the others.
+    fn frame_roots(&self, vars: &HashSet<Symbol>, env: &MutableMap<Symbol, Loc>) -> Vec<i32> {
+        let pad = self.live_regs.len() % 2;
+        let mut offsets: Vec<i32> = vars.iter().filter_map(|x| match env.get(x)? {
+            Loc::Mem(MemRef { offset: Offset::Constant(offset), .. }) => Some(*offset),
+            Loc::Reg(reg) => {
+                let k = self.live_regs.iter().position(|r| r == reg)?;
+                Some(-8 * (self.frame_size as i32 + (pad + k + 1) as i32))
+            }
+            Loc::Mem(_) => None,
+        }).collect();
+        offsets.sort();
+        offsets
+    }
+
+    /// Records `roots`, the words of the frame holding live values while the step being compiled
+    /// calls out. Returns the label of the record and the label to put right after the call,
+    /// whose address is the return address the GC finds on the stack.
+    fn stack_map(&mut self, roots: Vec<i32>) -> (String, String) {
+        let n = self.stack_maps.len();
+        let map = format!("stack_map_{n}");
+        let ret = format!("{map}_ret");
+        self.stack_maps.push((map.clone(), roots));
+        (map, ret)
+    }
+
+    /// Calls the collector `fun`, passing the stack map of the call in `map_reg`. The collector
+    /// returns the new heap pointer in `rax`, left for the caller, and the end of the heap, which
+    /// moves when the heap grows, in `rdx`.
+    fn call_gc(&mut self, fun: &str, map_reg: Reg) {
+        let (map, ret) = self.stack_map(self.gc_roots.clone());
+        self.emit_instrs([
+            Instr::LeaLabel(map_reg, map),
+            Instr::Call(fun.to_string()),
+            Instr::Label(ret),
+            Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
+        ]);
+    }
+
+    /// After storing `rcx` into the vector `rax`, remembers the vector if that made an old object
+    /// point into the nursery, which starts at `snek_nursery_start`: the vector is below it, the
+    /// element is an odd value at or above it (so a heap reference, as `true`, `false` and `nil`
+    /// are far below the heap) and the gc word of the vector isn't already set.
+    fn write_barrier(&mut self) {
+        let tag = self.next_tag();
+        let done = format!("write_barrier_done_{tag}");
+        self.emit_instrs([
+            Instr::LeaLabel(CHECK_REG, "snek_nursery_start".to_string()),
+            Instr::Mov(MovArgs::ToReg(CHECK_REG, Arg64::Mem(mref!(CHECK_REG + 0)))),
+            Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Reg(CHECK_REG))),
+            Instr::Jge(done.clone()),
+            Instr::Cmp(BinArgs::ToReg(Rcx, Arg32::Reg(CHECK_REG))),
+            Instr::Jl(done.clone()),
+            Instr::Test(BinArgs::ToReg(Rcx, Arg32::Imm(1))),
+            Instr::Jz(done.clone()),
+            Instr::Cmp(BinArgs::ToMem(mref!(Rax - %(1)), Reg32::Imm(GC_WORD_VAL))),
+            Instr::Jne(done.clone()),
+        ]);
+        self.save_live_regs();
+        self.emit_instrs([
+            Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(Rax))),
+            Instr::Call("snek_remember".to_string()),
+        ]);
+        self.restore_live_regs();
+        self.emit_instr(Instr::Label(done));
+    }
+
+    /// The stack maps, for the runtime to find as `snek_stack_maps`: their number followed by,
+    /// for every map, the return address of its call, the number of offsets and the offsets
+    fn stack_maps_to_string(&self) -> String {
+        let mut s = format!("section .data\nalign 8\nglobal snek_stack_maps\nsnek_stack_maps:\n  dq {}\n", self.stack_maps.len());
+        for (map, offsets) in &self.stack_maps {
+            let mut words = vec![format!("{map}_ret"), offsets.len().to_string()];
+            words.extend(offsets.iter().map(|offset| offset.to_string()));
+            s.push_str(&format!("{map}: dq {}\n", words.join(", ")));
+        }
+        s
+    }
+
+    fn restore_live_regs(&mut self) {
+        for reg in self.live_regs.clone().into_iter().rev() {
+            self.emit_instr(Instr::Pop(Loc::Reg(reg)));
+        }
+        if self.live_regs.len() % 2 != 0 {
+            self.emit_instr(Instr::Add(BinArgs::ToReg(Rsp, Arg32::Imm(8))));
+        }
+    }
+
     fn memset(&mut self, start: u32, count: u32, elem: Reg32) {
         for mem in locals(start, count) {
             self.emit_instr(Instr::Mov(MovArgs::ToMem(mem, elem)));
@@ -781,7 +1155,7 @@ impl IRSession {
 }
 
 // /// target is assumed to be a *register*
-// fn compile_ir_val(v : &Val, target: &str, env: &mut MutableMap<Symbol, i32>) -> String {
+// fn compile_ir_val(v : &Val, target: &str, env: &mut MutableMap<Symbol, Loc>) -> String {
 //     match v {
 //         Val::Num(n) => format!("mov {target}, {}", *n << 1),
 //         Val::True => format!("mov {target}, 7"),
@@ -800,7 +1174,7 @@ impl IRSession {
 //     }
 // }
 
-// fn compile_ir_expr(e : &IRExpr, env: &mut MutableMap<Symbol, i32>) -> String {
+// fn compile_ir_expr(e : &IRExpr, env: &mut MutableMap<Symbol, Loc>) -> String {
 //     match e {
 //         IRExpr::Add1(e) => {
 //             let e_is = compile_ir_val(&e, "rax", env);
@@ -1007,7 +1381,7 @@ impl IRSession {
 //     }
 // }
 
-// fn compile_ir_step(s : &Step, env: &mut MutableMap<Symbol, i32>, lbl : &Symbol) -> String {
+// fn compile_ir_step(s : &Step, env: &mut MutableMap<Symbol, Loc>, lbl : &Symbol) -> String {
 //     match s {
 //         Step::Label(l) => format!("{lbl}_{l}:\n"),
 //         Step::If(v, thn, els) => {
@@ -1044,7 +1418,7 @@ impl IRSession {
 // /// The argument for env is deliberately a *mutable* hashmap, so that we can
 // /// incrementally add bindings. There is no depth, so in some ways we've made
 // /// things _worse_ until we can register allocate reasonably.
-// fn compile_ir_block(b : &Block, env: &mut MutableMap<Symbol, i32>, lbl: &Symbol) -> String {
+// fn compile_ir_block(b : &Block, env: &mut MutableMap<Symbol, Loc>, lbl: &Symbol) -> String {
 //     let mut steps: String = String::new();
 //     for step in &b.steps {
 //         steps.push_str(&compile_ir_step(&step, env, lbl));
@@ -1067,15 +1441,7 @@ impl IRSession {
 //     "
 //     );
 // }
-fn raise_undefined_fun(fun: Symbol) {
-    panic!("function {fun} not defined")
-}
-
-fn raise_wrong_number_of_args(fun: Symbol, expected: usize, got: usize) {
-    panic!("function {fun} takes {expected} arguments but {got} were supplied")
-}
-
-// fn calc_env(b : &Block) -> MutableMap<Symbol, i32> {
+// fn calc_env(b : &Block) -> MutableMap<Symbol, Loc> {
 //     let mut env = MutableMap::new();
 //     for step in &b.steps {
 //         match step {
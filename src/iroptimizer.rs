@@ -1,28 +1,336 @@
-use std::collections::VecDeque;
-
-use im::HashMap;
 use std::collections::HashMap as MutMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
 
 use crate::syntax::{Symbol};
 use crate::ir::*;
+use crate::cfg::Cfg;
+use crate::callgraph::CallGraph;
+use crate::dataflow::{self, Const, ConstProp, Interval, Ranges, ReachingDefs, Site, Ty, Types};
+use crate::error::{CompileError, Diagnostics, ErrorKind};
+use crate::ssa;
+
+/// Largest body, in steps, of the functions copied into their callers unless told otherwise
+pub const DEFAULT_INLINE_LIMIT: usize = 16;
+
+/// Most rounds of the pass pipeline run while looking for a fixed point unless told otherwise
+pub const DEFAULT_MAX_ITERATIONS: usize = 50;
+
+/// Name of the pass copying small functions into their callers, which runs once before the
+/// others
+pub const INLINE: &str = "inline";
+
+/// Name of the constant folding pass, which also reports the arithmetic that always overflows
+const FOLD: &str = "fold";
+
+/// Name of the rewrites on the generated instructions, see `peephole.rs`
+pub const PEEPHOLE: &str = "peephole";
+
+/// What the optimizer runs
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// 0 runs no pass, 1 the ones that only look inside a function, 2 all of them
+    pub level: u8,
+    /// Passes turned on by name whatever the level
+    pub enabled: Vec<String>,
+    /// Passes turned off by name whatever the level
+    pub disabled: Vec<String>,
+    /// Size, in IR steps, of the largest function inlined (0 turns inlining off)
+    pub inline_limit: usize,
+    /// Most rounds of the pipeline before settling for what it has
+    pub max_iterations: usize,
+    /// Print the [`Stats`] to stderr
+    pub verbose: bool,
+    /// Check the program with `ir::verify` after every pass
+    pub verify: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config {
+            level: 2,
+            enabled: vec![],
+            disabled: vec![],
+            inline_limit: DEFAULT_INLINE_LIMIT,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            verbose: false,
+            verify: false,
+        };
+    }
+}
+
+impl Config {
+    /// Whether the pass called `name` runs
+    pub fn runs(&self, name: &str) -> bool {
+        if self.disabled.iter().any(|p| p == name) {
+            return false;
+        }
+        return self.enabled.iter().any(|p| p == name) || self.level >= pass_level(name);
+    }
+}
+
+/// A pass of the pipeline, returning the new program and whether it left it as it was
+struct Pass {
+    name: &'static str,
+    /// Lowest optimization level running it
+    level: u8,
+    run: fn(&Prog) -> (Prog, bool),
+}
+
+/// The pipeline, in the order it runs every round. Folding comes first; once the rounds reach a
+/// fixed point, the arithmetic it couldn't fold because it always overflows is reported.
+const PASSES: &[Pass] = &[
+    Pass { name: FOLD, level: 1, run: fold_constants },
+    Pass { name: "dce", level: 1, run: dead_code_elim },
+    Pass { name: "const-prop", level: 1, run: propogate_constants },
+    Pass { name: "specialize", level: 2, run: specialize_arguments },
+    Pass { name: "gvn", level: 2, run: number_values },
+    Pass { name: "dead-stores", level: 1, run: remove_dead_stores },
+    Pass { name: "checks", level: 1, run: remove_redundant_checks },
+    Pass { name: "bounds", level: 2, run: remove_bounds_checks },
+    Pass { name: "overflow", level: 2, run: remove_overflow_checks },
+    Pass { name: "licm", level: 2, run: hoist_invariants },
+];
+
+fn pass_level(name: &str) -> u8 {
+    if name == INLINE {
+        return 2;
+    }
+    if name == PEEPHOLE {
+        return 1;
+    }
+    return PASSES.iter().find(|pass| pass.name == name).map_or(u8::MAX, |pass| pass.level);
+}
+
+/// Names of every pass that can be turned on or off
+pub fn pass_names() -> Vec<&'static str> {
+    let mut names = vec![INLINE];
+    names.extend(PASSES.iter().map(|pass| pass.name));
+    names.push(PEEPHOLE);
+    return names;
+}
+
+/// What one pass did over all the rounds
+#[derive(Debug, Clone, Default)]
+pub struct PassStats {
+    pub runs: usize,
+    /// Runs that changed the program
+    pub changes: usize,
+    /// Steps in the program after the pass minus steps before
+    pub steps: i64,
+    pub time: Duration,
+}
+
+/// What the optimizer did
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub rounds: usize,
+    /// Whether the last round left the program as it was
+    pub fixed_point: bool,
+    /// The passes that ran, by name, in the order they run
+    pub passes: Vec<(&'static str, PassStats)>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fixed_point = if self.fixed_point { "reached a fixed point" } else { "stopped before a fixed point" };
+        writeln!(f, "optimizer: {} round(s), {fixed_point}", self.rounds)?;
+        writeln!(f, "{:<12} {:>5} {:>8} {:>7} {:>10}", "pass", "runs", "changed", "steps", "time")?;
+        for (name, stats) in &self.passes {
+            writeln!(f, "{:<12} {:>5} {:>8} {:>+7} {:>8}us", name, stats.runs, stats.changes, stats.steps, stats.time.as_micros())?;
+        }
+        return Ok(());
+    }
+}
+
+fn count_steps(prog: &Prog) -> i64 {
+    let steps = prog.main.steps.len() + prog.defs.iter().map(|def| def.body.steps.len()).sum::<usize>();
+    return steps as i64;
+}
+
+/// Optimizes the program with the passes `config` selects, running them in rounds until none of
+/// them changes anything or `config.max_iterations` rounds ran, and says what they did.
+pub fn optimize_ir(prog: &Prog, config: &Config) -> Result<(Prog, Stats), Diagnostics> {
+    verify_after(config, "lowering", prog);
+    if config.level == 0 && config.enabled.is_empty() {
+        return Ok((prog.clone(), Stats::default()));
+    }
+    let mut stats: Vec<PassStats> = PASSES.iter().map(|_| PassStats::default()).collect();
+    let mut inline_stats = PassStats::default();
+    let mut new_prog = prog.clone();
+    if config.runs(INLINE) {
+        let start = Instant::now();
+        let before = count_steps(&new_prog);
+        new_prog = inline_calls(&new_prog, config.inline_limit);
+        let steps = count_steps(&new_prog) - before;
+        inline_stats = PassStats { runs: 1, changes: usize::from(steps != 0), steps, time: start.elapsed() };
+        verify_after(config, INLINE, &new_prog);
+    }
+    new_prog = ssa::to_ssa(&new_prog);
+    verify_after(config, "ssa construction", &new_prog);
+
+    let mut rounds = 0;
+    let mut done = false;
+    while !done && rounds < config.max_iterations {
+        rounds += 1;
+        done = true;
+        for (pass, stats) in PASSES.iter().zip(&mut stats) {
+            if !config.runs(pass.name) {
+                continue;
+            }
+            let start = Instant::now();
+            let before = count_steps(&new_prog);
+            let (next, pass_done) = (pass.run)(&new_prog);
+            stats.runs += 1;
+            stats.time += start.elapsed();
+            stats.steps += count_steps(&next) - before;
+            if !pass_done {
+                stats.changes += 1;
+                done = false;
+            }
+            new_prog = next;
+            verify_after(config, pass.name, &new_prog);
+        }
+    }
+
+    let passes = std::iter::once((INLINE, inline_stats)).chain(PASSES.iter().map(|pass| pass.name).zip(stats));
+    let stats = Stats { rounds, fixed_point: done, passes: passes.filter(|(_, stats)| stats.runs > 0).collect() };
+    if config.verbose {
+        eprint!("{stats}");
+    }
+    // before a fixed point, the branch an overflow is in may still turn out to be dead
+    if done && config.runs(FOLD) {
+        report_overflows(&new_prog)?;
+    }
+    new_prog = ssa::from_ssa(&new_prog);
+    verify_after(config, "ssa destruction", &new_prog);
+    return Ok((new_prog, stats));
+}
+
+/// Reports the arithmetic on constants that overflows in the blocks of `main` dominating its
+/// exit, which fails on every run that would have finished. Elsewhere it may never run, so it
+/// keeps its check and only fails at runtime if it does.
+fn report_overflows(prog: &Prog) -> Result<(), Diagnostics> {
+    let mut diags = Diagnostics::new();
+    let cfg = Cfg::from_block(&prog.main);
+    let idom = ssa::dominators(&cfg);
+    let mut always_run = vec![];
+    let mut b = cfg.exit;
+    while let Some(d) = idom[b].filter(|d| *d != b) {
+        always_run.push(d);
+        b = d;
+    }
+    always_run.sort();
+    for b in always_run {
+        let steps = &cfg.blocks[b].steps;
+        for (step, next) in steps.iter().zip(steps.iter().skip(1)) {
+            if let (Step::Set(_, e) | Step::Do(e), Step::Check(CheckType::CheckOverflow(span))) = (step, next) {
+                if eval_arith(e) == Some(None) {
+                    diags.report(CompileError::new(ErrorKind::Overflow(expr_to_string(e)), *span));
+                }
+            }
+        }
+    }
+    return diags.finish(());
+}
+
+/// In verify mode, stops the compiler with what `stage` broke in the program it left
+fn verify_after(config: &Config, stage: &str, prog: &Prog) {
+    if !config.verify {
+        return;
+    }
+    if let Err(e) = verify(prog) {
+        panic!("invalid IR after {stage}: {e}");
+    }
+}
 
-pub fn optimize_ir(prog: &Prog) -> Prog {
-    let (mut new_prog, mut fold_done) = fold_constants(prog);
-    let mut dead_done = false;
-    let mut cons_done = false;
-    (new_prog, dead_done) = dead_code_elim(&new_prog);
-    (new_prog, cons_done) = propogate_constants(&new_prog);
+/// Replaces the calls to small functions that never end up calling themselves by a copy of
+/// their body, so the other passes can see through the call. Functions are inlined into before
+/// they are inlined anywhere else, and are small if that leaves at most `limit` steps in their
+/// body, not counting labels.
+fn inline_calls(prog: &Prog, limit: usize) -> Prog {
+    let graph = CallGraph::new(prog);
+    let mut inliner = Inliner { bodies: MutMap::new(), count: 0 };
+    let mut bodies = MutMap::new();
+    for f in graph.callees_first() {
+        let def = prog.defs.iter().find(|def| def.name == f).unwrap();
+        let body = inliner.inline_block(&def.body);
+        let size = body.steps.iter().filter(|step| !matches!(step, Step::Label(_))).count();
+        if size <= limit && !graph.reaches(f, f) {
+            inliner.bodies.insert(f, (def.args.clone(), Block { steps: body.steps.clone() }));
+        }
+        bodies.insert(f, body);
+    }
+    let defs = prog.defs.iter().map(|def| {
+        Def { name: def.name, args: def.args.clone(), body: bodies.remove(&def.name).unwrap() }
+    }).collect();
+    return Prog { defs, main: inliner.inline_block(&prog.main) };
+}
+
+struct Inliner {
+    /// Parameters and body of the functions to inline
+    bodies: MutMap<Symbol, (Vec<Symbol>, Block)>,
+    /// Number of calls inlined so far, to give every copy its own names
+    count: usize,
+}
 
-    while !fold_done || !dead_done || !cons_done{
-        (new_prog, fold_done) = fold_constants(&new_prog);
-        (new_prog, dead_done) = dead_code_elim(&new_prog);
-        (new_prog, cons_done) = propogate_constants(&new_prog);
-        //print!("{}", ir_to_string(&new_prog));
+impl Inliner {
+    fn inline_block(&mut self, block: &Block) -> Block {
+        let mut steps = vec![];
+        for step in &block.steps {
+            match step {
+                Step::Set(x, IRExpr::Call(f, args)) if self.bodies.contains_key(f) => self.inline_call(*f, args, Some(*x), &mut steps),
+                Step::Do(IRExpr::Call(f, args)) if self.bodies.contains_key(f) => self.inline_call(*f, args, None, &mut steps),
+                _ => steps.push(step.clone()),
+            }
+        }
+        return Block { steps };
+    }
+
+    /// Copies the body of `f` with its parameters bound to `args`, storing what it returns in
+    /// `dest`. The body falls through at the end, where a function returns.
+    fn inline_call(&mut self, f: Symbol, args: &[Val], dest: Option<Symbol>, steps: &mut Vec<Step>) {
+        self.count += 1;
+        let k = self.count;
+        let var = |x: Symbol| {
+            if x.to_string() == "rax" {
+                dest
+            } else if is_hard_coded_reg(&x) {
+                Some(x)
+            } else {
+                Some(Symbol::new(format!("{x}%inl{k}")))
+            }
+        };
+        let label = |l: Symbol| Symbol::new(format!("{l}_inl{k}"));
+
+        let (params, body) = &self.bodies[&f];
+        for (x, v) in params.iter().zip(args) {
+            steps.push(Step::Set(var(*x).unwrap(), IRExpr::Val(*v)));
+        }
+        for step in &body.steps {
+            let mut step = match step {
+                Step::Label(l) => Step::Label(label(*l)),
+                Step::Goto(l) => Step::Goto(label(*l)),
+                Step::If(v, thn, els) => Step::If(*v, label(*thn), label(*els)),
+                Step::Set(x, e) => match var(*x) {
+                    Some(x) => Step::Set(x, e.clone()),
+                    None => Step::Do(e.clone()),
+                },
+                _ => step.clone(),
+            };
+            for v in step.vals_mut() {
+                if let Val::Var(x) = v {
+                    *x = var(*x).unwrap();
+                }
+            }
+            steps.push(step);
+        }
     }
-    //print!("{}", ir_to_string(&new_prog));
-    return new_prog;
 }
 
+/// Folds the operations on constants. Arithmetic whose result doesn't fit in a number is left
+/// with its check, see `report_overflows`.
 fn fold_constants(prog: &Prog) -> (Prog, bool) {
     let mut done = true;
     let (new_defs, isdone) = fold_constants_defs(&prog.defs);
@@ -38,7 +346,7 @@ fn fold_constants_defs(defs: &[Def]) -> (Vec<Def>, bool){
     let mut done = true;
     let mut new_defs = vec![];
     for def in defs {
-        let (folded, isdone) = fold_constants_def(&def);
+        let (folded, isdone) = fold_constants_def(def);
         if !isdone {
             done = false;
         }
@@ -60,82 +368,54 @@ fn fold_constants_block(block: &Block) -> (Block, bool) {
 fn fold_constants_steps(steps: &[Step]) -> (Vec<Step>, bool) {
     let mut new_steps = vec![];
     let mut isdone = true;
+    // a constant doesn't set the overflow flag the check after the arithmetic reads
+    let mut folded = false;
     for step in steps {
         match step {
             Step::Set(x, e) => {
                 let (newe, tdone) = fold_constants_expr(&e);
                 if !tdone {
-                    //println!("folded a step");
                     isdone = false;
                 }
                 new_steps.push(Step::Set(x.clone(), newe));
+                folded = !tdone;
+            },
+            Step::Check(CheckType::CheckOverflow(_)) if folded => {
+                folded = false;
+            },
+            _ => {
+                new_steps.push(step.clone());
+                folded = false;
             },
-            _ => new_steps.push(step.clone()),
         }
     }
     return (new_steps, isdone);
 }
 
+/// Evaluates arithmetic on constants: `None` if an operand isn't a constant number,
+/// `Some(None)` if the result doesn't fit in a number
+fn eval_arith(e: &IRExpr) -> Option<Option<i64>> {
+    let res = match e {
+        IRExpr::Add1(Val::Num(n)) => n.checked_add(1),
+        IRExpr::Sub1(Val::Num(n)) => n.checked_sub(1),
+        IRExpr::Plus(Val::Num(n1), Val::Num(n2)) => n1.checked_add(*n2),
+        IRExpr::Minus(Val::Num(n1), Val::Num(n2)) => n1.checked_sub(*n2),
+        IRExpr::Times(Val::Num(n1), Val::Num(n2)) => n1.checked_mul(*n2),
+        _ => return None,
+    };
+    Some(res.filter(|m| (Interval::MIN_NUM..=Interval::MAX_NUM).contains(m)))
+}
+
 fn fold_constants_expr(e: &IRExpr) -> (IRExpr, bool) {
     match e {
-        IRExpr::Add1(v) => {
-            match v {
-                Val::Num(n) => {
-                    let res = n.checked_add(1);
-                    match res {
-                        Some(m) if m <= 4611686018427387903 => (IRExpr::Val(Val::Num(m)), false),
-                        _ => (e.clone(), true), // just let overflow happen and get caught for now
-                    }
-                },
-                _ => (e.clone(), true)
-            }
-        },
-        IRExpr::Sub1(v) => {
-            match v {
-                Val::Num(n) => {
-                    let res = n.checked_sub(1);
-                    match res {
-                        Some(m) if m >= -4611686018427387904  => (IRExpr::Val(Val::Num(m)), false),
-                        _ => (e.clone(), true), // just let overflow happen and get caught for now
-                    }
-                },
-                _ => (e.clone(), true)
-            }
-        }
-        IRExpr::Plus(v1, v2) => {
-            match (v1, v2) {
-                (Val::Num(n1), Val::Num(n2)) => {
-                    let res = n1.checked_add(*n2);
-                    match res {
-                        Some(m) if m <= 4611686018427387903 => (IRExpr::Val(Val::Num(m)), false),
-                        _ => (e.clone(), true), // just let overflow happen and get caught for now
-                    }
-                }
-                _ => (e.clone(), true)
-            }
-        },
-        IRExpr::Minus(v1, v2) => {
-            match (v1, v2) {
-                (Val::Num(n1), Val::Num(n2)) => {
-                    let res = n1.checked_sub(*n2);
-                    match res {
-                        Some(m) if m >= -4611686018427387904 => (IRExpr::Val(Val::Num(m)), false),
-                        _ => (e.clone(), true), // just let overflow happen and get caught for now
-                    }
-                }
-                _ => (e.clone(), true)
-            }
-        }
-        IRExpr::Times(v1, v2) => {
-            match (v1, v2) {
-                (Val::Num(n1), Val::Num(n2)) => {
-                    let res = n1.checked_mul(*n2);
-                    match res {
-                        Some(m) if m <= 4611686018427387903 => (IRExpr::Val(Val::Num(m)), false),
-                        _ => (e.clone(), true), // just let overflow happen and get caught for now
-                    }
-                }
-                _ => (e.clone(), true)
+        IRExpr::Add1(_) |
+        IRExpr::Sub1(_) |
+        IRExpr::Plus(..) |
+        IRExpr::Minus(..) |
+        IRExpr::Times(..) => {
+            match eval_arith(e) {
+                Some(Some(m)) => (IRExpr::Val(Val::Num(m)), false),
+                _ => (e.clone(), true),
             }
         }
         IRExpr::Divide(v1, v2) => {
@@ -235,214 +515,85 @@ fn fold_constants_expr(e: &IRExpr) -> (IRExpr, bool) {
     }
 }
 
+/// Removes the code that can't run: branches of `if`s on constants, blocks that can't be reached
+/// and the functions that are never called (or turned into closures) from the code left.
 fn dead_code_elim(prog: &Prog) -> (Prog, bool) {
-    let label_map = generate_label_map(prog);
-    let mut to_visit = VecDeque::new(); // queue of pairs, (func, IR step) --- main is 0, defs start 1,2,...
-    let mut visited:Vec<Vec<bool>> = Vec::new();
     let mut done = true;
-    visited.push(Vec::new());
-    visited.last_mut().unwrap().resize(prog.main.steps.len(), false);
-    for def in prog.defs.as_slice() {
-        visited.push(Vec::new());
-        visited.last_mut().unwrap().resize(def.body.steps.len(), false);
-    }
-    to_visit.push_back((0,0));
-    while !to_visit.is_empty() {
-        let (nxt_fn, nxt_idx) = to_visit.pop_front().unwrap();
-        let steps;
-        if nxt_fn == 0 {
-            steps = prog.main.steps.clone();
-        } else {
-            steps = prog.defs.get(nxt_fn-1).unwrap().body.steps.clone();
-        }
-        for st_num in nxt_idx..steps.len() { // visit next step
-            let t_visited = visited.get_mut(nxt_fn).unwrap();
-            let _ = std::mem::replace(&mut t_visited[st_num], true);
-            match &steps[st_num]{
-                Step::If(v, l1, l2) => {
-                    match v {
-                        Val::Var(_) |
-                        Val::Input => {
-
-                            match label_map.get(&l1) {
-                                Some((j,k)) => {
-                                    if !visited[*j][*k]{
-                                        to_visit.push_back((*j,*k));
-                                    }
-                                }
-                                None => panic!("unknown label"),
-                            }
-                            match label_map.get(&l2) {
-                                Some((j,k)) => {
-                                    if !visited[*j][*k]{
-                                        to_visit.push_back((*j,*k));
-                                    }
-                                }
-                                None => panic!("unknown label"),
-                            }
-                            if nxt_fn == 0{
-                                println!("visiting both branches");
-                                println!("{},{}", l1, l2);
-                                println!("{:?}",to_visit);
-                            }
-                            break;
-                        },
-                        Val::False => {
-                            let _ = std::mem::replace(&mut t_visited[st_num], false);
-                            match label_map.get(&l2) {
-                                Some((j,k)) => {
-                                    if !visited[*j][*k]{
-                                        to_visit.push_back((*j,*k));
-                                    }
-                                }
-                                None => panic!("unknown label"),
-                            }
-                            break;
-                        },
-                        _ => {
-                            let _ = std::mem::replace(&mut t_visited[st_num], false);
-                            match label_map.get(&l1) {
-                                Some((j,k)) => {
-                                    if !visited[*j][*k]{
-                                        to_visit.push_back((*j,*k));
-                                    }
-                                }
-                                None => panic!("unknown label"),
-                            }
-                            break;
-                        },
+    let mut called = vec![];
+    let mut main = Cfg::from_block(&prog.main);
+    done &= dead_code_elim_cfg(&mut main, &mut called);
 
-                    }
-                }
-                Step::Goto(lbl) => {
-                    match label_map.get(&lbl) {
-                        Some((j,k)) => {
-                            if !visited[*j][*k]{
-                                to_visit.push_back((*j,*k));
-                            }
-                        }
-                        None => panic!("unknown label"),
-                    }
-                    break;
-                }
-                Step::Do(e) => {
-                    match e {
-                        IRExpr::Call(n,_) => {
-                            match label_map.get(n) {
-                                Some((j,k)) => {
-                                    if !visited[*j][*k]{
-                                        to_visit.push_back((*j,*k));
-                                    }
-                                }
-                                None => panic!("unknown label"),
-                            }
-                        },
-                        _ => (),
-                    }
-                }
-                Step::Set(_, e) => {
-                    match e {
-                        IRExpr::Call(n,_) => {
-                            match label_map.get(&n) {
-                                Some((j,k)) => {
-                                    if !visited[*j][*k] {
-                                        to_visit.push_back((*j,*k));
-                                    }
-                                }
-                                None => panic!("unknown label"),
-                            }
-                        },
-                        _ => (),
-                    }
-                },
-                _ => ()
-            }
+    let mut cfgs: MutMap<Symbol, Cfg> = MutMap::new();
+    while let Some(name) = called.pop() {
+        if cfgs.contains_key(&name) {
+            continue;
         }
+        let def = prog.defs.iter().find(|d| d.name == name).expect("call to unknown function");
+        let mut cfg = Cfg::from_def(def);
+        done &= dead_code_elim_cfg(&mut cfg, &mut called);
+        cfgs.insert(name, cfg);
     }
+    let new_defs: Vec<Def> = prog.defs.iter().filter_map(|def| {
+        cfgs.get(&def.name).map(|cfg| cfg.to_def(def.name, def.args.clone()))
+    }).collect();
+    done &= new_defs.len() == prog.defs.len();
 
-    let mut new_main = vec![];
-    let mut idx = 0;
-    let main_visited = &visited[0];
-    for step in prog.main.steps.clone() {
-        if main_visited[idx] {
-            match step {
-                Step::Goto(x) => {
-                    match label_map.get(&x) {
-                        Some((j,k)) => {
-                            if visited[*j][*k] {
-                                new_main.push(step.clone())
-                            } else {
-                                done = false;
-                            }
-                        }
-                        None => todo!(),
-                    }
-                },
-                _ =>  new_main.push(step.clone()),
-            }
-        }
-        idx += 1;
+    return (Prog{defs: new_defs, main: main.to_block()}, done);
+}
+
+/// Dead code elimination within one function body, the functions it refers to are added to
+/// `called`. Returns whether nothing changed.
+fn dead_code_elim_cfg(cfg: &mut Cfg, called: &mut Vec<Symbol>) -> bool {
+    let mut done = true;
+    for bb in cfg.blocks.iter_mut() {
+        let taken = match bb.steps.last() {
+            Some(Step::If(Val::Var(_) | Val::Input, _, _)) => continue,
+            Some(Step::If(Val::False, _, els)) => *els,
+            Some(Step::If(_, thn, _)) => *thn,
+            _ => continue,
+        };
+        *bb.steps.last_mut().unwrap() = Step::Goto(taken);
+        done = false;
     }
-    let mut new_defs = vec![];
-    let didx = 1;
-    idx = 0;
-    for def in prog.defs.as_slice() {
-        let mut new_def_steps = vec![];
-        let def_visited = &visited[didx];
-        for step in def.body.steps.as_slice() {
-            if def_visited[idx] {
-                match step {
-                    Step::Goto(x) => {
-                        match label_map.get(&x) {
-                            Some((j,k)) => {
-                                if visited[*j][*k] {
-                                    new_def_steps.push(step.clone())
-                                } else {
-                                    done = false;
-                                }
-                            }
-                            None => todo!(),
-                        }
-                    },
-                    _ =>  new_def_steps.push(step.clone()),
-                }
+    cfg.link();
+    if cfg.remove_unreachable() {
+        done = false;
+    }
+    // phis only keep the operands of the predecessors that are left
+    for i in 0..cfg.blocks.len() {
+        let preds: Vec<Symbol> = cfg.blocks[i].preds.iter().filter_map(|p| cfg.blocks[*p].label()).collect();
+        for step in cfg.blocks[i].steps.iter_mut() {
+            let Step::Phi(x, ops) = step else { continue };
+            let before = ops.len();
+            ops.retain(|(l, _)| preds.contains(l));
+            if ops.len() != before {
+                done = false;
+            }
+            if ops.len() == 1 {
+                *step = Step::Set(*x, IRExpr::Val(ops[0].1));
+                done = false;
             }
         }
-        new_defs.push(Def{name: def.name.clone(), args: def.args.clone(), body: Block{steps: new_def_steps}});
     }
-
-    return (Prog{defs: new_defs, main: Block{steps: new_main}}, done);
-}
-
-fn generate_label_map(prog: &Prog) -> HashMap<Symbol, (usize,usize)> {
-    let mut points = find_labels_block(&prog.main, &HashMap::new(), 0);
-    points = find_labels_defs(&points, &prog.defs);
-    return points;
-}
-
-fn find_labels_defs(points: &HashMap<Symbol,(usize,usize)>, defs: &[Def]) -> HashMap<Symbol, (usize, usize)>{
-    let mut dnum = 1;
-    let mut acc = points.clone();
-    for def in defs {
-        acc = find_labels_block(&def.body, &acc, dnum).update(def.name.clone(), (dnum, 0));
-        dnum += 1;
+    // a jump to the very next block is a fall through
+    for i in 0..cfg.exit {
+        let next = cfg.blocks[i + 1].label();
+        let bb = &mut cfg.blocks[i];
+        if matches!(bb.steps.last(), Some(Step::Goto(l)) if Some(*l) == next) {
+            bb.steps.pop();
+            done = false;
+        }
     }
-    return acc;
-}
-
-fn find_labels_block(block: &Block, curr_pts: &HashMap<Symbol, (usize, usize)>, bnum: usize) -> HashMap<Symbol, (usize,usize)> {
-    let mut b_labels = curr_pts.clone();
-    for i in 0..block.steps.len() {
-        let s = &block.steps[i];
-        match s {
-            Step::Label(l) => {
-                b_labels = b_labels.update(l.clone(), (bnum, i));
-            },
+    for step in cfg.blocks.iter().flat_map(|bb| &bb.steps) {
+        match step {
+            Step::Do(IRExpr::Call(n, _) | IRExpr::MakeClosure(n, _, _)) |
+            Step::Set(_, IRExpr::Call(n, _) | IRExpr::MakeClosure(n, _, _)) => called.push(*n),
             _ => (),
         }
     }
-    return b_labels;
+    return done;
 }
+
 fn is_hard_coded_reg (s: &Symbol) -> bool{
     match s.to_string().as_str() {
         "rax"|
@@ -470,248 +621,522 @@ fn propogate_constants_defs(defs: &[Def]) -> (Vec<Def>, bool) {
     return (new_defs, done);
 }
 
-fn propogate_constants_block(block: &Block, args: &[Symbol]) -> (Block, bool){
-    let mut var_map:MutMap<Symbol, Val> = MutMap::new();
-    let mut done = true;
-    let mut to_rm = vec![];
-    for step in block.steps.as_slice() {
-        match step {
-            Step::Set(x, v) => {
-                if !is_hard_coded_reg(x){
-                    match v {
-                        IRExpr::Val(val) => {
-                            match var_map.get(&x){
-                                Some(v) => if ! (*v == *val) {
-                                    to_rm.push(x.clone());
-                                    // let t = var_map.remove_entry(&x);
-                                    // println!("removed {:?}",t.unwrap());
-                                    // println!("{:?}",var_map);
-                                },
-                                None => _ = var_map.insert(x.clone(), *val),
-                            }
-                        },
-                        _ => {
-                            match var_map.get(&x){
-                                Some(_) => {
-                                    to_rm.push(x.clone());
-                                    //_ = var_map.remove_entry(&x);
-                                },
-                                None => to_rm.push(x.clone()),
-
-                            }
-                        },
-                    }
-                }
+/// The function making every call to a function (`None` for the main body), with its arguments
+type CallSites<'a> = Vec<(Option<Symbol>, &'a [Val])>;
+
+/// Passes the arguments that are the same constant at every call to a function as a constant in
+/// its body instead, and drops them from the calls. A recursive call passing the parameter on
+/// unchanged agrees with any constant. Functions turned into closures are left alone, they can be
+/// called with anything.
+fn specialize_arguments(prog: &Prog) -> (Prog, bool) {
+    let escaping = CallGraph::new(prog).escaping();
+    let mut sites: MutMap<Symbol, CallSites> = MutMap::new();
+    let bodies = std::iter::once((None, &prog.main)).chain(prog.defs.iter().map(|def| (Some(def.name), &def.body)));
+    for (caller, body) in bodies {
+        for step in &body.steps {
+            if let Step::Set(_, IRExpr::Call(f, args)) | Step::Do(IRExpr::Call(f, args)) = step {
+                sites.entry(*f).or_default().push((caller, args));
             }
-            _ => (),
         }
     }
-    println!("{:?}", to_rm);
-    for s in to_rm {
-        _ = var_map.remove(&s);
+
+    // the constant every specialized parameter is replaced by
+    let mut consts: MutMap<Symbol, Vec<Option<Val>>> = MutMap::new();
+    for def in &prog.defs {
+        if escaping.contains(&def.name) || !sites.contains_key(&def.name) {
+            continue;
+        }
+        let params: Vec<Option<Val>> = def.args.iter().enumerate().map(|(i, x)| {
+            let mut known = None;
+            for (caller, args) in &sites[&def.name] {
+                match args[i] {
+                    Val::Var(y) if y == *x && *caller == Some(def.name) => (),
+                    v @ (Val::Num(_) | Val::True | Val::False | Val::Nil) if known.is_none_or(|k| k == v) => known = Some(v),
+                    _ => return None,
+                }
+            }
+            known
+        }).collect();
+        if params.iter().any(Option::is_some) {
+            consts.insert(def.name, params);
+        }
     }
-    for a in args {
-        _ = var_map.remove(&a);
+    if consts.is_empty() {
+        return (Prog { defs: prog.defs.iter().map(copy_def).collect(), main: Block { steps: prog.main.steps.clone() } }, true);
     }
-    println!("varmap: {:?}", var_map);
-    let mut new_steps = vec![];
-    for step in block.steps.as_slice() {
-        match step {
-            Step::Label(_)|
-            Step::Goto(_) => new_steps.push(step.clone()),
-            Step::If(v, l1, l2) => {
-                match v {
-                    Val::Var(x) => {
-                        match var_map.get(&x){
-                            Some(v) => {
-                                done = false;
-                                new_steps.push(Step::If(v.clone(),l1.clone(),l2.clone()))
-                            }
-                            None => new_steps.push(step.clone()),
-                        }
 
-                    }
-                    _ => new_steps.push(step.clone()),
+    let drop_args = |steps: &[Step]| -> Vec<Step> {
+        steps.iter().map(|step| {
+            let mut step = step.clone();
+            if let Step::Set(_, IRExpr::Call(f, args)) | Step::Do(IRExpr::Call(f, args)) = &mut step {
+                if let Some(params) = consts.get(f) {
+                    let mut i = 0;
+                    args.retain(|_| { i += 1; params[i - 1].is_none() });
                 }
-            },
-            Step::Do(e) => {
-                let (new_exp, tdone) = propogate_constants_expr(&e, &var_map);
-                new_steps.push(Step::Do(new_exp));
-                done = done && tdone;
             }
-            Step::Set(x, e) => {
-                if var_map.contains_key(&x) {
-                    done = false;
-                    continue;
-                } else {
-                    let (new_exp, tdone) = propogate_constants_expr(&e, &var_map);
-                    new_steps.push(Step::Set(x.clone(), new_exp));
-                    done = done && tdone;
+            step
+        }).collect()
+    };
+    let defs = prog.defs.iter().map(|def| {
+        let mut steps = drop_args(&def.body.steps);
+        let Some(params) = consts.get(&def.name) else {
+            return Def { name: def.name, args: def.args.clone(), body: Block { steps } };
+        };
+        // parameters keep their name as the version they have on entry
+        for step in steps.iter_mut() {
+            for v in step.vals_mut() {
+                if let Some(c) = v.var().and_then(|x| def.args.iter().position(|y| *y == x)).and_then(|i| params[i]) {
+                    *v = c;
                 }
             }
-            Step::Check(t) => {
-                match t {
-                    CheckType::CheckIsNum(v) => {
-                        let (new_v, tdone) = propogate_constants_val(&v, &var_map);
-                        new_steps.push(Step::Check(CheckType::CheckIsNum(new_v)));
-                        done = done && tdone;
-                    }
-                    CheckType::CheckIsVec(v) => {
-                        let (new_v, tdone) = propogate_constants_val(&v, &var_map);
-                        new_steps.push(Step::Check(CheckType::CheckIsVec(new_v)));
-                        done = done && tdone;
-                    }
-                    CheckType::CheckIsNotNil(v) => {
-                        let (new_v, tdone) = propogate_constants_val(&v, &var_map);
-                        new_steps.push(Step::Check(CheckType::CheckIsNotNil(new_v)));
-                        done = done && tdone;
-                    }
-                    CheckType::CheckEq(v1,v2) => {
-                        let (new_v1, tdone1) = propogate_constants_val(&v1, &var_map);
-                        let (new_v2, tdone2) = propogate_constants_val(&v2, &var_map);
-                        done = done && tdone1 && tdone2;
-                        new_steps.push(Step::Check(CheckType::CheckEq(new_v1,new_v2)));
-                    }
-                    CheckType::CheckBounds(v1,v2) => {
-                        let (new_v1, tdone1) = propogate_constants_val(&v1, &var_map);
-                        let (new_v2, tdone2) = propogate_constants_val(&v2, &var_map);
-                        done = done && tdone1 && tdone2;
-                        new_steps.push(Step::Check(CheckType::CheckBounds(new_v1,new_v2)));
-                    }
-                    CheckType::CheckOverflow => new_steps.push(Step::Check(CheckType::CheckOverflow)),
-                }
-            },
         }
-    }
-    return (Block{steps: new_steps}, done);
+        let args = def.args.iter().zip(params).filter(|(_, c)| c.is_none()).map(|(x, _)| *x).collect();
+        Def { name: def.name, args, body: Block { steps } }
+    }).collect();
+    return (Prog { defs, main: Block { steps: drop_args(&prog.main.steps) } }, false);
 }
 
-fn propogate_constants_expr(expr: &IRExpr, var_map: &MutMap<Symbol, Val>) -> (IRExpr,bool) {
-    match expr{
-        IRExpr::Add1(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::Add1(new_v), tdone)
-        }
-        IRExpr::Sub1(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::Sub1(new_v), tdone)
+fn copy_def(def: &Def) -> Def {
+    return Def { name: def.name, args: def.args.clone(), body: Block { steps: def.body.steps.clone() } };
+}
+
+/// Replaces the variables that are known to hold a constant by the constant, and drops their
+/// assignments. The IR is in SSA form so a variable holds the same value wherever it is read.
+fn propogate_constants_block(block: &Block, args: &[Symbol]) -> (Block, bool){
+    let cfg = Cfg::from_block(block);
+    let analysis = ConstProp { args, is_fixed: is_hard_coded_reg };
+    let facts = dataflow::solve(&cfg, &analysis);
+    let mut var_map: MutMap<Symbol, Val> = MutMap::new();
+    for fact in &facts.block_out {
+        for (x, c) in fact {
+            if let Const::Known(v) = c {
+                var_map.insert(*x, *v);
+            }
         }
-        IRExpr::Plus(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Plus(new_v1,new_v2), (tdone1 && tdone2))
+    }
+
+    let mut done = true;
+    let mut new_steps = vec![];
+    for step in &block.steps {
+        if step.def().is_some_and(|x| var_map.contains_key(&x)) {
+            done = false;
+            continue;
         }
-        IRExpr::Minus(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Minus(new_v1,new_v2), (tdone1 && tdone2))
+        let mut new_step = step.clone();
+        for v in new_step.vals_mut() {
+            if let Some(c) = v.var().and_then(|x| var_map.get(&x)) {
+                *v = *c;
+                done = false;
+            }
         }
-        IRExpr::Times(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Times(new_v1,new_v2), (tdone1 && tdone2))
+        new_steps.push(new_step);
+    }
+    return (Block{steps: new_steps}, done);
+}
+
+/// Removes the assignments (and evaluations) without side effects whose value is never read.
+fn remove_dead_stores(prog: &Prog) -> (Prog, bool) {
+    let mut done = true;
+    let defs = prog.defs.iter().map(|def| {
+        let (body, tdone) = remove_dead_stores_block(&def.body, &def.args);
+        done &= tdone;
+        Def { name: def.name, args: def.args.clone(), body }
+    }).collect();
+    let (main, tdone) = remove_dead_stores_block(&prog.main, &[]);
+    return (Prog { defs, main }, done && tdone);
+}
+
+fn remove_dead_stores_block(block: &Block, args: &[Symbol]) -> (Block, bool) {
+    let cfg = Cfg::from_block(block);
+    let analysis = ReachingDefs { args };
+    let reaching = dataflow::solve(&cfg, &analysis).steps(&cfg, &analysis).before;
+    let mut read = HashSet::new();
+    for (i, step) in block.steps.iter().enumerate() {
+        for x in step.uses() {
+            read.extend(reaching[i].iter().filter(|(y, _)| *y == x).map(|(_, site)| *site));
         }
-        IRExpr::Divide(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Divide(new_v1,new_v2), (tdone1 && tdone2))
+    }
+
+    let mut done = true;
+    let mut new_steps = vec![];
+    for (i, step) in block.steps.iter().enumerate() {
+        // arithmetic can still fail with an overflow
+        let checked = matches!(block.steps.get(i + 1), Some(Step::Check(CheckType::CheckOverflow(_))));
+        let dead = match step {
+            Step::Set(x, e) => !is_hard_coded_reg(x) && !read.contains(&Site::Step(i)) && is_pure(e) && !checked,
+            Step::Phi(..) => !read.contains(&Site::Step(i)),
+            Step::Do(e) => is_pure(e) && !checked,
+            _ => false,
+        };
+        if dead {
+            done = false;
+        } else {
+            new_steps.push(step.clone());
         }
-        IRExpr::Eq(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Eq(new_v1,new_v2), (tdone1 && tdone2))
+    }
+    return (Block { steps: new_steps }, done);
+}
+
+/// Removes the type checks that always pass, given what the variables are known to hold at that
+/// point: the result of `+` is a number, a variable that passed `CHECKISNUM` once is a number in
+/// the code it dominates, and so on.
+fn remove_redundant_checks(prog: &Prog) -> (Prog, bool) {
+    let returns = return_types(prog);
+    let mut done = true;
+    let defs = prog.defs.iter().map(|def| {
+        let (body, tdone) = remove_redundant_checks_block(&def.body, Types { args: &def.args, returns: &returns });
+        done &= tdone;
+        Def { name: def.name, args: def.args.clone(), body }
+    }).collect();
+    let (main, tdone) = remove_redundant_checks_block(&prog.main, Types { args: &[], returns: &returns });
+    return (Prog { defs, main }, done && tdone);
+}
+
+fn remove_redundant_checks_block(block: &Block, analysis: Types) -> (Block, bool) {
+    let cfg = Cfg::from_block(block);
+    let types = dataflow::solve(&cfg, &analysis).steps(&cfg, &analysis).before;
+    let mut done = true;
+    let mut new_steps = vec![];
+    for (i, step) in block.steps.iter().enumerate() {
+        match step {
+            Step::Check(c) if analysis.implied(c, &types[i]) => done = false,
+            _ => new_steps.push(step.clone()),
         }
-        IRExpr::Gt(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Gt(new_v1,new_v2), (tdone1 && tdone2))
+    }
+    return (Block { steps: new_steps }, done);
+}
+
+/// Removes the bounds checks whose index is proven to be within the vector by the range
+/// analysis, e.g. in a loop from 0 while the index is below `(vec-len v)`. The checks that the
+/// vector is a vector and the index a number stay, for the type checks to get rid of.
+fn remove_bounds_checks(prog: &Prog) -> (Prog, bool) {
+    let mut done = true;
+    let defs = prog.defs.iter().map(|def| {
+        let (body, tdone) = remove_bounds_checks_block(&def.body, &def.args);
+        done &= tdone;
+        Def { name: def.name, args: def.args.clone(), body }
+    }).collect();
+    let (main, tdone) = remove_bounds_checks_block(&prog.main, &[]);
+    return (Prog { defs, main }, done && tdone);
+}
+
+fn remove_bounds_checks_block(block: &Block, args: &[Symbol]) -> (Block, bool) {
+    let cfg = Cfg::from_block(block);
+    let analysis = Ranges::new(block, args);
+    let ranges = dataflow::solve(&cfg, &analysis).steps(&cfg, &analysis).before;
+    let mut done = true;
+    let mut new_steps = vec![];
+    for (i, step) in block.steps.iter().enumerate() {
+        match (step, &ranges[i]) {
+            (Step::Check(CheckType::CheckBounds(vec, idx)), Some(fact)) if analysis.in_bounds(vec, idx, fact) => {
+                new_steps.push(Step::Check(CheckType::CheckIsVec(*vec)));
+                new_steps.push(Step::Check(CheckType::CheckIsNotNil(*vec)));
+                new_steps.push(Step::Check(CheckType::CheckIsNum(*idx)));
+                done = false;
+            }
+            _ => new_steps.push(step.clone()),
         }
-        IRExpr::Ge(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Ge(new_v1,new_v2), (tdone1 && tdone2))
+    }
+    return (Block { steps: new_steps }, done);
+}
+
+/// Removes the overflow checks after arithmetic whose result is proven to fit in a number by the
+/// range analysis, e.g. incrementing a loop index that stays below the length of a vector.
+fn remove_overflow_checks(prog: &Prog) -> (Prog, bool) {
+    let mut done = true;
+    let defs = prog.defs.iter().map(|def| {
+        let (body, tdone) = remove_overflow_checks_block(&def.body, &def.args);
+        done &= tdone;
+        Def { name: def.name, args: def.args.clone(), body }
+    }).collect();
+    let (main, tdone) = remove_overflow_checks_block(&prog.main, &[]);
+    return (Prog { defs, main }, done && tdone);
+}
+
+fn remove_overflow_checks_block(block: &Block, args: &[Symbol]) -> (Block, bool) {
+    let cfg = Cfg::from_block(block);
+    let analysis = Ranges::new(block, args);
+    let ranges = dataflow::solve(&cfg, &analysis).steps(&cfg, &analysis).before;
+    let mut done = true;
+    let mut new_steps = vec![];
+    for (i, step) in block.steps.iter().enumerate() {
+        let fits = match (i.checked_sub(1).map(|j| (&block.steps[j], &ranges[j])), step) {
+            (Some((Step::Set(_, e) | Step::Do(e), Some(fact))), Step::Check(CheckType::CheckOverflow(_))) => {
+                Ranges::of_expr(e, fact).fits()
+            }
+            _ => false,
+        };
+        if fits {
+            done = false;
+        } else {
+            new_steps.push(step.clone());
         }
-        IRExpr::Lt(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Lt(new_v1,new_v2), (tdone1 && tdone2))
+    }
+    return (Block { steps: new_steps }, done);
+}
+
+/// Moves the steps computing the same thing on every trip around a loop to the end of the block
+/// the loop is entered from, the SSA form makes a step invariant when the variables it reads are
+/// assigned outside the loop (or by steps moved out already). Steps that can't fail or do
+/// anything visible are moved from anywhere in the loop. Checks, and the arithmetic and vector
+/// reads that can fail, are only moved from the top of the loop before anything else that can
+/// fail or be seen, where the first trip would have run them anyway.
+fn hoist_invariants(prog: &Prog) -> (Prog, bool) {
+    let mut done = true;
+    let defs = prog.defs.iter().map(|def| {
+        let (body, tdone) = hoist_invariants_block(&def.body);
+        done &= tdone;
+        Def { name: def.name, args: def.args.clone(), body }
+    }).collect();
+    let (main, tdone) = hoist_invariants_block(&prog.main);
+    return (Prog { defs, main }, done && tdone);
+}
+
+fn hoist_invariants_block(block: &Block) -> (Block, bool) {
+    let mut cfg = Cfg::from_block(block);
+    let idom = ssa::dominators(&cfg);
+    let rpo = cfg.reverse_postorder();
+    let mut done = true;
+    for lp in cfg.natural_loops(&idom) {
+        // a block entering the loop and going nowhere else works as its preheader
+        let outside: Vec<usize> = cfg.blocks[lp.header].preds.iter().filter(|p| !lp.body.contains(p)).copied().collect();
+        let [pre] = outside[..] else { continue };
+        if cfg.blocks[pre].succs.len() != 1 {
+            continue;
         }
-        IRExpr::Le(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::Le(new_v1,new_v2), (tdone1 && tdone2))
+        let assigned: HashSet<Symbol> = lp.body.iter().flat_map(|b| cfg.blocks[*b].steps.iter().filter_map(Step::def)).collect();
+        let mut hoisted_vars = HashSet::new();
+        let mut hoisted = vec![];
+        for b in rpo.iter().filter(|b| lp.body.contains(b)) {
+            let steps = std::mem::take(&mut cfg.blocks[*b].steps);
+            let mut in_order = *b == lp.header;
+            let mut i = 0;
+            while i < steps.len() {
+                let step = &steps[i];
+                let checked = matches!(steps.get(i + 1), Some(Step::Check(CheckType::CheckOverflow(_))));
+                let invariant = step.uses().iter().all(|x| !assigned.contains(x) || hoisted_vars.contains(x))
+                    && !step.def().is_some_and(|x| is_hard_coded_reg(&x));
+                // whether the step can be moved, whether it can be run when the loop wouldn't have
+                // run it, and whether it can't fail or be seen where it is
+                let (movable, speculative, quiet) = match step {
+                    Step::Set(_, e) | Step::Do(e) if checked => (is_pure(e), false, false),
+                    Step::Set(_, IRExpr::VecLen(_)) => (true, false, true),
+                    Step::Set(_, IRExpr::Divide(..)) => (true, false, false),
+                    Step::Set(_, IRExpr::VecGet(..) | IRExpr::ClosureGet(..)) => (false, false, true),
+                    // the check may be gone because of what the branches into the loop body tell
+                    // about the operands, which doesn't hold where the loop is entered
+                    Step::Set(_, IRExpr::Add1(_) | IRExpr::Sub1(_) | IRExpr::Plus(..) | IRExpr::Minus(..) | IRExpr::Times(..)) => (true, false, true),
+                    Step::Set(_, e) => (is_pure(e), true, is_pure(e)),
+                    Step::Do(e) => (false, false, is_pure(e)),
+                    Step::Check(CheckType::CheckOverflow(_)) => (false, false, false),
+                    Step::Check(_) => (true, false, false),
+                    Step::Label(_) | Step::Phi(..) => (false, false, true),
+                    _ => (false, false, false),
+                };
+                let movable = movable && matches!(step, Step::Set(..) | Step::Check(_));
+                let len = if checked { 2 } else { 1 };
+                if movable && invariant && (speculative || in_order) {
+                    hoisted_vars.extend(step.def());
+                    hoisted.extend_from_slice(&steps[i..i + len]);
+                    done = false;
+                } else {
+                    in_order &= quiet;
+                    cfg.blocks[*b].steps.extend_from_slice(&steps[i..i + len]);
+                }
+                i += len;
+            }
         }
-        IRExpr::IsNum(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::IsNum(new_v), tdone)
+        let pre = &mut cfg.blocks[pre].steps;
+        let at = match pre.last() {
+            Some(Step::Goto(_) | Step::If(..)) => pre.len() - 1,
+            _ => pre.len(),
+        };
+        pre.splice(at..at, hoisted);
+    }
+    return (cfg.to_block(), done);
+}
+
+/// Gives every computation a number so that a computation of a value already computed in a
+/// block dominating it (global value numbering over the dominator tree) becomes a copy of it,
+/// which constant propagation then gets rid of. The same goes for a check that passed already.
+/// Vector reads are only reused within a block (local value numbering), and not past anything
+/// that could change a vector: `vec-set!`, calls and collections.
+fn number_values(prog: &Prog) -> (Prog, bool) {
+    let mut done = true;
+    let defs = prog.defs.iter().map(|def| {
+        let (body, tdone) = number_values_block(&def.body);
+        done &= tdone;
+        Def { name: def.name, args: def.args.clone(), body }
+    }).collect();
+    let (main, tdone) = number_values_block(&prog.main);
+    return (Prog { defs, main }, done && tdone);
+}
+
+fn number_values_block(block: &Block) -> (Block, bool) {
+    let mut cfg = Cfg::from_block(block);
+    let idom = ssa::dominators(&cfg);
+    let mut children = vec![vec![]; cfg.blocks.len()];
+    for (b, d) in idom.iter().enumerate() {
+        match d {
+            Some(d) if *d != b => children[*d].push(b),
+            _ => (),
         }
-        IRExpr::IsBool(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::IsBool(new_v), tdone)
-        }
-        IRExpr::IsVec(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::IsVec(new_v), tdone)
-        }
-        IRExpr::Print(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::Print(new_v), tdone)
-        }
-        IRExpr::Call(fun, args) => {
-            let mut new_args = vec![];
-            let mut done = true;
-            for arg in args {
-                let (targ, tdone) = propogate_constants_val(arg, var_map);
-                new_args.push(targ);
-                done = done && tdone;
-            }
-            (IRExpr::Call(fun.clone(), new_args), done)
-        }
-        IRExpr::MakeVec(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::MakeVec(new_v1,new_v2), (tdone1 && tdone2))
-        }
-        IRExpr::Vec(vs) => {
-            let mut new_v = vec![];
-            let mut done = true;
-            for v in vs {
-                let (tnew, tdone) = propogate_constants_val(v, var_map);
-                new_v.push(tnew);
-                done = done && tdone;
+    }
+    let mut numbering = ValueNumbering { children, values: MutMap::new(), checks: HashSet::new(), done: true };
+    numbering.visit(cfg.entry, &mut cfg);
+    return (cfg.to_block(), numbering.done);
+}
+
+/// An operation and its operands, the same for any two expressions computing the same value
+type ValueKey = (&'static str, Vec<Val>);
+
+struct ValueNumbering {
+    /// Children of every block in the dominator tree
+    children: Vec<Vec<usize>>,
+    /// Variable holding the value of every computation in the blocks dominating the current one
+    values: MutMap<ValueKey, Symbol>,
+    /// Checks passed in the blocks dominating the current one
+    checks: HashSet<CheckType>,
+    done: bool,
+}
+
+impl ValueNumbering {
+    fn visit(&mut self, b: usize, cfg: &mut Cfg) {
+        let steps = std::mem::take(&mut cfg.blocks[b].steps);
+        let mut new_steps = vec![];
+        let mut added_values = vec![];
+        let mut added_checks = vec![];
+        let mut reads: MutMap<ValueKey, Symbol> = MutMap::new();
+        let mut stale_check = false;
+        for step in steps {
+            match &step {
+                Step::Set(x, IRExpr::VecGet(v, i)) if !is_hard_coded_reg(x) => {
+                    let key = ("vec-get", vec![*v, *i]);
+                    match reads.get(&key) {
+                        Some(y) => {
+                            new_steps.push(Step::Set(*x, IRExpr::Val(Val::Var(*y))));
+                            self.done = false;
+                        }
+                        None => {
+                            reads.insert(key, *x);
+                            new_steps.push(step);
+                        }
+                    }
+                }
+                Step::Set(x, e) if value_key(e).is_some() => {
+                    let key = value_key(e).unwrap();
+                    match self.values.get(&key) {
+                        // the value was computed (and checked for overflow) already
+                        Some(y) => {
+                            new_steps.push(Step::Set(*x, IRExpr::Val(Val::Var(*y))));
+                            stale_check = true;
+                            self.done = false;
+                            continue;
+                        }
+                        None if !is_hard_coded_reg(x) => {
+                            self.values.insert(key.clone(), *x);
+                            added_values.push(key);
+                        }
+                        None => (),
+                    }
+                    new_steps.push(step);
+                }
+                Step::Check(CheckType::CheckOverflow(_)) if stale_check => (),
+                Step::Check(c) if !matches!(c, CheckType::CheckOverflow(_)) => {
+                    if self.checks.contains(c) {
+                        self.done = false;
+                    } else {
+                        self.checks.insert(c.clone());
+                        added_checks.push(c.clone());
+                        new_steps.push(step);
+                    }
+                }
+                Step::Set(_, e) | Step::Do(e) => {
+                    if matches!(e, IRExpr::VecSet(..) | IRExpr::Call(..) | IRExpr::CallIndirect(..) | IRExpr::Gc) {
+                        reads.clear();
+                    }
+                    new_steps.push(step);
+                }
+                _ => new_steps.push(step),
             }
-            (IRExpr::Vec(new_v), done)
-        }
-        IRExpr::VecSet(v1, v2, v3) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            let (new_v3, tdone3) = propogate_constants_val(v3, var_map);
-            (IRExpr::VecSet(new_v1,new_v2,new_v3), (tdone1 && tdone2 && tdone3))
+            stale_check = false;
         }
-        IRExpr::VecGet(v1, v2) => {
-            let (new_v1, tdone1) = propogate_constants_val(v1, var_map);
-            let (new_v2, tdone2) = propogate_constants_val(v2, var_map);
-            (IRExpr::VecGet(new_v1,new_v2), (tdone1 && tdone2))
+        cfg.blocks[b].steps = new_steps;
+
+        for c in self.children[b].clone() {
+            self.visit(c, cfg);
         }
-        IRExpr::VecLen(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::VecLen(new_v), tdone)
+        for key in added_values {
+            self.values.remove(&key);
         }
-        IRExpr::Val(v) => {
-            let (new_v, tdone) = propogate_constants_val(v, var_map);
-            (IRExpr::Val(new_v), tdone)
+        for c in added_checks {
+            self.checks.remove(&c);
         }
-        _ => (expr.clone(),true)
     }
 }
-fn propogate_constants_val(val: &Val, var_map: &MutMap<Symbol, Val>) -> (Val,bool) {
-    match val {
-        Val::Var(x) => { 
-            match var_map.get(&x){
-                Some(v) => (v.clone(),false),
-                None => (val.clone(),true),
+
+/// The key of the expressions that always compute the same value from the same operands, and
+/// don't read memory that can change. The operands of commutative operations are put in order.
+fn value_key(e: &IRExpr) -> Option<ValueKey> {
+    let (op, mut vals) = match e {
+        IRExpr::Add1(v) => ("add1", vec![*v]),
+        IRExpr::Sub1(v) => ("sub1", vec![*v]),
+        IRExpr::Plus(v1, v2) => ("+", vec![*v1, *v2]),
+        IRExpr::Minus(v1, v2) => ("-", vec![*v1, *v2]),
+        IRExpr::Times(v1, v2) => ("*", vec![*v1, *v2]),
+        IRExpr::Divide(v1, v2) => ("/", vec![*v1, *v2]),
+        IRExpr::Eq(v1, v2) => ("==", vec![*v1, *v2]),
+        IRExpr::Gt(v1, v2) => (">", vec![*v1, *v2]),
+        IRExpr::Ge(v1, v2) => (">=", vec![*v1, *v2]),
+        IRExpr::Lt(v1, v2) => ("<", vec![*v1, *v2]),
+        IRExpr::Le(v1, v2) => ("<=", vec![*v1, *v2]),
+        IRExpr::IsNum(v) => ("isnum", vec![*v]),
+        IRExpr::IsBool(v) => ("isbool", vec![*v]),
+        IRExpr::IsVec(v) => ("isvec", vec![*v]),
+        IRExpr::VecLen(v) => ("vec-len", vec![*v]),
+        IRExpr::ClosureGet(v, i) => ("closure-get", vec![*v, Val::Num(*i as i64)]),
+        _ => return None,
+    };
+    if matches!(op, "+" | "*" | "==") {
+        vals.sort_by_key(|v| format!("{v:?}"));
+    }
+    return Some((op, vals));
+}
+
+/// What every function may return: anything that ends up in rax in its body. Recursive
+/// functions are solved together, starting from nothing until no return type grows.
+fn return_types(prog: &Prog) -> MutMap<Symbol, Ty> {
+    let mut returns: MutMap<Symbol, Ty> = prog.defs.iter().map(|def| (def.name, Ty::NONE)).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for def in &prog.defs {
+            let cfg = Cfg::from_def(def);
+            let analysis = Types { args: &def.args, returns: &returns };
+            let types = dataflow::solve(&cfg, &analysis).steps(&cfg, &analysis).before;
+            let mut ty = Ty::NONE;
+            for (i, step) in def.body.steps.iter().enumerate() {
+                match step {
+                    Step::Set(x, e) if x.to_string() == "rax" => ty = ty.union(analysis.of_expr(e, &types[i])),
+                    Step::Do(e) => ty = ty.union(analysis.of_expr(e, &types[i])),
+                    _ => (),
+                }
             }
-        },
-        _ => (val.clone(),true),
+            if returns[&def.name] != ty {
+                returns.insert(def.name, ty);
+                changed = true;
+            }
+        }
     }
-}
\ No newline at end of file
+    returns
+}
+
+/// Expressions that can be dropped when their value isn't needed: they don't fail (the checks
+/// of their operands are separate steps), print, or allocate
+fn is_pure(e: &IRExpr) -> bool {
+    matches!(e,
+        IRExpr::Val(_) | IRExpr::Add1(_) | IRExpr::Sub1(_) | IRExpr::Plus(..) | IRExpr::Minus(..) |
+        IRExpr::Times(..) | IRExpr::Eq(..) | IRExpr::Gt(..) | IRExpr::Ge(..) | IRExpr::Lt(..) |
+        IRExpr::Le(..) | IRExpr::IsNum(_) | IRExpr::IsBool(_) | IRExpr::IsVec(_) |
+        IRExpr::VecGet(..) | IRExpr::VecLen(_) | IRExpr::ClosureGet(..))
+}
@@ -1,57 +1,313 @@
 use std::{
     env,
-    fs::File,
+    fs::{self, File},
     io::{self, Read, Write},
+    path::Path,
+    process,
 };
-// use anf::*;
-// use ir::*;
-// use ircompiler::*;
-// use iroptimizer::*;
+
+use cli::{Artifact, Backend, Command, Options};
+use error::Diagnostics;
 
 mod asm;
+mod check;
+mod cli;
 mod compiler;
+mod error;
 mod parser;
+mod peephole;
 mod syntax;
 mod anf;
 mod ir;
+mod cfg;
+mod callgraph;
+mod dataflow;
 mod ircompiler;
 mod iroptimizer;
+mod regalloc;
+mod ssa;
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let in_name = &args[1];
-    let out_name = &args[2];
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = match cli::parse_args(&args) {
+        Ok(Command::Compile(opts)) => opts,
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Err(msg) => {
+            eprintln!("error: {msg}\n\n{}", cli::USAGE);
+            process::exit(2);
+        }
+    };
+
     let mut in_contents = String::new();
-    let mut in_file = File::open(in_name)?;
+    let mut in_file = File::open(&opts.input)?;
     in_file.read_to_string(&mut in_contents)?;
-    let expr = parser::parse(&in_contents);
-    let anf_prog = anf::anf_program(&expr);
-    let anf_str_prog = anf::flatprogram_to_string(&anf_prog);
-    let ir_prog = ir::anf_to_ir(&anf_prog);
-    let ir_str_prog = ir::ir_to_string(&ir_prog);
-    let opt_ir_prog = iroptimizer::optimize_ir(&ir_prog);
-    //print!("OPTIMIZED");
-    let opt_ir_asm = ircompiler::compile_ir_prog(&opt_ir_prog);
-
-    // let ir_asm = ircompiler::compile_ir_prog(&ir_prog);
-    let asm = compiler::compile(&expr);
-    // if args.len() < 4 || &args[3] == "--og" {
-    //     let mut out_file = File::create(out_name)?;
-    //     out_file.write_all(asm.as_bytes())?;
-    // } else if &args[3] == "--ir" {
-    //     let mut out_file = File::create(out_name)?;
-    //     out_file.write_all(ir_asm.as_bytes())?;
-    // } else if &args[3] == "--opt"{
-        let mut out_file = File::create(out_name)?;
-        out_file.write_all(opt_ir_asm.as_bytes())?;
-    // } else {
-        // let mut out_file = File::create(out_name)?;
-        // out_file.write_all(asm.as_bytes())?;
-    // }
-
-    let mut ir_outfile = File::create(out_name.to_owned()+".ir")?;
-    ir_outfile.write_all(ir_str_prog.as_bytes())?;
-    let mut anf_outfile = File::create(out_name.to_owned()+".anf")?;
-    anf_outfile.write_all(anf_str_prog.as_bytes())?;
-    Ok(())
+    let file = opts.input.display().to_string();
+    match compile(&opts, &in_contents) {
+        Ok(Compiled { warnings: None }) => Ok(()),
+        Ok(Compiled { warnings: Some(diags) }) => {
+            let path = opts.artifact_path(Artifact::OptIr);
+            eprintln!("warning: the optimizer failed, {} was not written", path.display());
+            eprint!("{}", diags.render(&file, &in_contents, opts.error_limit));
+            Ok(())
+        }
+        Err(CompileFailure::Program(diags)) => {
+            eprint!("{}", diags.render(&file, &in_contents, opts.error_limit));
+            process::exit(1);
+        }
+        Err(CompileFailure::Io(e)) => Err(e),
+    }
+}
+
+/// A compilation that wrote every requested artifact
+struct Compiled {
+    /// Errors of the optimizer when only `--emit opt-ir` needed it, whose file wasn't written
+    warnings: Option<Diagnostics>,
+}
+
+/// Why a compilation stopped
+enum CompileFailure {
+    Io(io::Error),
+    /// Errors in the program being compiled
+    Program(Diagnostics),
+}
+
+impl From<io::Error> for CompileFailure {
+    fn from(e: io::Error) -> CompileFailure {
+        CompileFailure::Io(e)
+    }
+}
+
+/// Runs the pipeline selected by `opts`, writing every requested artifact. The optimizer runs
+/// for `--emit opt-ir` whatever the backend, and when the backend doesn't need its output its
+/// errors don't stop the compilation: they come back as warnings, without the opt-ir file.
+fn compile(opts: &Options, src: &str) -> Result<Compiled, CompileFailure> {
+    let expr = parser::parse(src).map_err(CompileFailure::Program)?;
+    check::check_program(&expr).map_err(CompileFailure::Program)?;
+
+    if let Some(dir) = &opts.emit_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let needs_ir = opts.backend != Backend::Legacy
+        || opts.emits(Artifact::Anf)
+        || opts.emits(Artifact::Ir)
+        || opts.emits(Artifact::OptIr);
+    let peephole = opts.opt.runs(iroptimizer::PEEPHOLE);
+    let mut asm = None;
+    let mut opt_ir_errors = None;
+    if needs_ir {
+        let anf_prog = anf::anf_program(&expr);
+        if opts.emits(Artifact::Anf) {
+            write_artifact(opts, Artifact::Anf, &anf::flatprogram_to_string(&anf_prog))?;
+        }
+        let ir_prog = ir::anf_to_ir(&anf_prog);
+        if opts.emits(Artifact::Ir) {
+            write_artifact(opts, Artifact::Ir, &ir::ir_to_string(&ir_prog))?;
+        }
+        if opts.backend == Backend::Ir {
+            asm = Some(ircompiler::compile_ir_prog(&ir_prog, peephole));
+        }
+        if opts.backend == Backend::OptIr || opts.emits(Artifact::OptIr) {
+            match iroptimizer::optimize_ir(&ir_prog, &opts.opt) {
+                Ok((opt_ir_prog, _)) => {
+                    if opts.emits(Artifact::OptIr) {
+                        write_artifact(opts, Artifact::OptIr, &ir::ir_to_string(&opt_ir_prog))?;
+                    }
+                    if opts.backend == Backend::OptIr {
+                        asm = Some(ircompiler::compile_ir_prog(&opt_ir_prog, peephole));
+                    }
+                }
+                Err(diags) if opts.backend == Backend::OptIr => {
+                    return Err(CompileFailure::Program(diags));
+                }
+                Err(diags) => opt_ir_errors = Some(diags),
+            }
+        }
+    }
+    let asm = match asm {
+        Some(asm) => asm,
+        None => compiler::compile(&expr, peephole).map_err(CompileFailure::Program)?,
+    };
+    if opts.emits(Artifact::Asm) {
+        write_artifact(opts, Artifact::Asm, &asm)?;
+    }
+    Ok(Compiled { warnings: opt_ir_errors })
+}
+
+fn write_artifact(opts: &Options, artifact: Artifact, contents: &str) -> io::Result<()> {
+    let path = opts.artifact_path(artifact);
+    let mut out_file = File::create(&path).map_err(|e| annotate(e, &path))?;
+    out_file.write_all(contents.as_bytes())
+}
+
+fn annotate(e: io::Error, path: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Compiles `src` with the command-line `flags` into a fresh directory named after `test`
+    fn compile_with(
+        test: &str,
+        flags: &str,
+        src: &str,
+    ) -> (PathBuf, Result<Compiled, Diagnostics>) {
+        let dir = env::temp_dir().join(format!("forest-flame-{test}-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.s");
+        let mut args: Vec<String> = flags.split_whitespace().map(String::from).collect();
+        args.extend(["in.snek".to_string(), output.display().to_string()]);
+        let Ok(Command::Compile(opts)) = cli::parse_args(&args) else {
+            panic!("invalid flags `{flags}`");
+        };
+        let result = match compile(&opts, src) {
+            Ok(compiled) => Ok(compiled),
+            Err(CompileFailure::Program(diags)) => Err(diags),
+            Err(CompileFailure::Io(e)) => panic!("{e}"),
+        };
+        (dir, result)
+    }
+
+    #[test]
+    fn emits_opt_ir_with_the_ir_backend() {
+        let (dir, result) = compile_with("ir-opt-ir", "--ir --emit asm,opt-ir", "(+ 1 2)");
+        assert!(matches!(result, Ok(Compiled { warnings: None })));
+        let opt_ir = fs::read_to_string(dir.join("out.s.opt.ir")).unwrap();
+        assert!(!opt_ir.is_empty());
+        assert!(dir.join("out.s").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn optimizer_errors_dont_stop_other_backends() {
+        let src = "(* 4611686018427387903 4)";
+        for backend in ["--og", "--ir"] {
+            let flags = format!("{backend} --emit asm,opt-ir");
+            let (dir, result) = compile_with(&backend[2..], &flags, src);
+            assert!(matches!(result, Ok(Compiled { warnings: Some(_) })), "{backend}");
+            assert!(dir.join("out.s").exists(), "{backend}");
+            assert!(!dir.join("out.s.opt.ir").exists(), "{backend}");
+            fs::remove_dir_all(dir).unwrap();
+        }
+        let (dir, result) = compile_with("opt", "--opt --emit asm,opt-ir", src);
+        assert!(result.is_err());
+        assert!(!dir.join("out.s").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// The optimized IR of `src` compiled with `flags`
+    fn opt_ir(test: &str, flags: &str, src: &str) -> String {
+        let (dir, result) = compile_with(test, &format!("{flags} --emit opt-ir"), src);
+        assert!(matches!(result, Ok(Compiled { warnings: None })), "{flags}");
+        let opt_ir = fs::read_to_string(dir.join("out.s.opt.ir")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        opt_ir
+    }
+
+    const CALL: &str = "(fun (f v i) (vec-get v i))\n(f (vec 1 2 3) 1)";
+
+    #[test]
+    fn o0_is_the_unoptimized_ir() {
+        let src = "(let ((x input)) (+ x (* 2 3)))";
+        let (dir, result) = compile_with("o0", "-O0 --emit asm,ir,opt-ir", src);
+        assert!(matches!(result, Ok(Compiled { warnings: None })));
+        let ir = fs::read_to_string(dir.join("out.s.ir")).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("out.s.opt.ir")).unwrap(), ir);
+        let asm = fs::read_to_string(dir.join("out.s")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        let (dir, result) = compile_with("o0-ir", "--ir -O0 --emit asm", src);
+        assert!(matches!(result, Ok(Compiled { warnings: None })));
+        assert_eq!(fs::read_to_string(dir.join("out.s")).unwrap(), asm);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disabled_passes_keep_their_checks() {
+        assert!(!opt_ir("bounds", "", CALL).contains("CHECKBOUNDS"));
+        assert!(opt_ir("no-bounds", "--disable bounds", CALL).contains("CHECKBOUNDS"));
+        let src = "(let ((i 0)) (loop (if (< i 10) (set! i (add1 i)) (break i))))";
+        assert!(!opt_ir("overflow", "", src).contains("CHECKOVERFLOW"));
+        assert!(opt_ir("no-overflow", "--disable overflow", src).contains("CHECKOVERFLOW"));
+    }
+
+    #[test]
+    fn levels_pick_the_passes() {
+        assert!(!opt_ir("o2", "-O2", CALL).contains("<- f("));
+        assert!(opt_ir("o1", "-O1", CALL).contains("<- f("));
+        assert!(!opt_ir("o0-inline", "-O0 --enable inline", CALL).contains("<- f("));
+        assert!(opt_ir("o2-no-inline", "--disable inline", CALL).contains("<- f("));
+        let src = "(+ 1 2)";
+        assert!(opt_ir("o0-fold", "-O0", src).contains("1 + 2"));
+        assert!(!opt_ir("o0-enable-fold", "-O0 --enable fold", src).contains("1 + 2"));
+    }
+
+    #[test]
+    fn inline_limit_bounds_the_inlined_bodies() {
+        assert!(opt_ir("limit-0", "--inline-limit 0", CALL).contains("<- f("));
+        assert!(opt_ir("limit-1", "--inline-limit 1", CALL).contains("<- f("));
+        assert!(!opt_ir("limit-2", "--inline-limit 2", CALL).contains("<- f("));
+    }
+
+    /// What the optimizer did to `src` compiled with `flags`
+    fn optimizer_stats(flags: &str, src: &str) -> iroptimizer::Stats {
+        let mut args: Vec<String> = flags.split_whitespace().map(String::from).collect();
+        args.extend(["in.snek".to_string(), "out.s".to_string()]);
+        let Ok(Command::Compile(opts)) = cli::parse_args(&args) else {
+            panic!("invalid flags `{flags}`");
+        };
+        let ir_prog = ir::anf_to_ir(&anf::anf_program(&parser::parse(src).unwrap()));
+        iroptimizer::optimize_ir(&ir_prog, &opts.opt).unwrap().1
+    }
+
+    fn ran(stats: &iroptimizer::Stats) -> Vec<&str> {
+        stats.passes.iter().map(|(name, _)| *name).collect()
+    }
+
+    #[test]
+    fn statistics_cover_every_pass() {
+        let stats = optimizer_stats("", CALL);
+        assert!(stats.fixed_point);
+        let mut passes = iroptimizer::pass_names();
+        passes.retain(|name| *name != iroptimizer::PEEPHOLE);
+        assert_eq!(ran(&stats), passes);
+        for (name, pass) in &stats.passes[1..] {
+            assert_eq!(pass.runs, stats.rounds, "{name}");
+            assert!(pass.changes < pass.runs, "{name}");
+        }
+    }
+
+    #[test]
+    fn statistics_count_the_inlined_steps() {
+        let stats = optimizer_stats("", CALL);
+        let (name, inline) = &stats.passes[0];
+        assert_eq!(*name, iroptimizer::INLINE);
+        assert_eq!((inline.runs, inline.changes), (1, 1));
+        assert!(inline.steps > 0);
+        let stats = optimizer_stats("--inline-limit 1", CALL);
+        assert_eq!(stats.passes[0].1.changes, 0);
+    }
+
+    #[test]
+    fn max_iterations_stops_before_a_fixed_point() {
+        let stats = optimizer_stats("--max-iterations 1", CALL);
+        assert_eq!(stats.rounds, 1);
+        assert!(!stats.fixed_point);
+    }
+
+    #[test]
+    fn statistics_follow_the_level() {
+        let o1 = optimizer_stats("-O1", CALL);
+        assert!(ran(&o1).contains(&"fold"));
+        assert!(!ran(&o1).contains(&iroptimizer::INLINE));
+        assert!(!ran(&o1).contains(&"licm"));
+        let o0 = optimizer_stats("-O0", CALL);
+        assert_eq!(o0.rounds, 0);
+        assert!(o0.passes.is_empty());
+    }
 }
@@ -1,57 +1,99 @@
+use std::collections::HashMap;
+
 use regex::Regex;
 use sexp::{Atom::*, Sexp};
 
-use crate::syntax::{Expr, FunDecl, Op1, Op2, Prog, Symbol};
+use crate::error::{CompileError, Diagnostics, ErrorKind};
+use crate::syntax::{Expr, FunDecl, Op1, Op2, Pos, Prog, Span, Symbol};
+
+type ParseResult<T> = Result<T, CompileError>;
 
-pub fn parse(s: &str) -> Prog {
-    let s = format!("({})", s);
-    let s = sexp::parse(&s).unwrap_or_else(|_| syntax_error("invalid s-expr"));
-    Parser::new().parse_prog(&s)
+/// Parses a whole program. Syntax errors are recovered from at the level of top-level forms, so
+/// every malformed function definition is reported along with a malformed main expression.
+pub fn parse(s: &str) -> Result<Prog, Diagnostics> {
+    let wrapped = format!("({})", s);
+    let sexp = match sexp::parse(&wrapped) {
+        Ok(sexp) => sexp,
+        Err(err) => {
+            let pos = SourceMap::new(s).pos(err.index.saturating_sub(1));
+            let span = Span {
+                start: pos,
+                end: Pos {
+                    line: pos.line,
+                    col: pos.col + 1,
+                },
+            };
+            let kind = syntax(format!("invalid s-expr: {}", err.message));
+            return Err(CompileError::new(kind, span).into());
+        }
+    };
+    let spans = SourceMap::new(s).spans(&wrapped, &sexp);
+    Parser::new(spans).parse_prog(&sexp)
 }
 
 struct Parser {
     id_regex: Regex,
+    /// Source span of every node in the tree returned by `sexp::parse`, keyed by address
+    spans: HashMap<*const Sexp, Span>,
 }
 
 impl Parser {
-    fn new() -> Parser {
+    fn new(spans: HashMap<*const Sexp, Span>) -> Parser {
         Parser {
             id_regex: Regex::new(r"^[a-zA-Z][a-zA-Z0-9_-]*$").unwrap(),
+            spans,
         }
     }
 
-    fn parse_prog(&self, e: &Sexp) -> Prog {
-        let Sexp::List(es) = e else{
-            syntax_error("expected a list")
+    fn span(&self, e: &Sexp) -> Span {
+        self.spans[&(e as *const Sexp)]
+    }
+
+    fn parse_prog(&self, e: &Sexp) -> Result<Prog, Diagnostics> {
+        let Sexp::List(es) = e else {
+            return Err(CompileError::new(syntax("expected a list"), self.span(e)).into());
         };
-        if let [funcs @ .., main] = &es[..] {
-            let funcs = funcs.iter().map(|e| self.parse_func(e)).collect();
-            let main = self.parse_expr(main);
-            Prog { funs: funcs, main }
-        } else {
-            syntax_error("program must contain a main expression")
+        let [funcs @ .., main] = &es[..] else {
+            let kind = syntax("program must contain a main expression");
+            return Err(CompileError::new(kind, self.span(e)).into());
+        };
+        let mut diags = Diagnostics::new();
+        let mut funs = vec![];
+        for e in funcs {
+            match self.parse_func(e) {
+                Ok(fun) => funs.push(fun),
+                Err(err) => diags.report(err),
+            }
+        }
+        match self.parse_expr(main) {
+            Ok(main) => diags.finish(Prog { funs, main }),
+            Err(err) => {
+                diags.report(err);
+                Err(diags)
+            }
         }
     }
 
-    fn parse_expr(&self, e: &Sexp) -> Expr {
-        match e {
+    fn parse_expr(&self, e: &Sexp) -> ParseResult<Expr> {
+        let span = self.span(e);
+        let expr = match e {
             &Sexp::Atom(I(n)) => {
-                if n >= -4611686018427387904 && n < 4611686018427387904 {
+                if (-4611686018427387904..4611686018427387904).contains(&n) {
                     Expr::Number(n)
                 } else {
-                    syntax_error("integer literal overflow")
+                    return syntax_error("integer literal overflow", span);
                 }
             }
             Sexp::Atom(S(id)) => match id.as_str() {
                 "true" => Expr::Boolean(true),
                 "false" => Expr::Boolean(false),
-                "input" => Expr::Input,
+                "input" => Expr::Input(span),
                 "nil" => Expr::Nil,
                 _ => {
                     if is_keyword(id) {
-                        syntax_error("invalid use of keyword `{id}`")
+                        return syntax_error(format!("invalid use of keyword `{id}`"), span);
                     } else {
-                        Expr::Var(Symbol::new(id))
+                        Expr::Var(Symbol::new(id), span)
                     }
                 }
             },
@@ -59,100 +101,133 @@ impl Parser {
                 // (snek-printstack)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "snek-printstack" => {
                     if !es.is_empty() {
-                        return syntax_error("snek-prinstack doesn't take any arguments");
+                        return syntax_error("snek-prinstack doesn't take any arguments", span);
                     }
                     Expr::PrintStack
                 }
                 // (gc)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "gc" => {
                     if !es.is_empty() {
-                        return syntax_error("gc doesn't take any arguments");
+                        return syntax_error("gc doesn't take any arguments", span);
                     }
                     Expr::Gc
                 }
                 // (make-vec size elem)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "make-vec" => {
                     let [size, elem] = &es[..] else {
-                        return syntax_error("malformed vec");
+                        return syntax_error("malformed vec", span);
                     };
-                    let size = self.parse_expr(size);
-                    let elem = self.parse_expr(elem);
+                    let size = self.parse_expr(size)?;
+                    let elem = self.parse_expr(elem)?;
                     Expr::MakeVec(Box::new(size), Box::new(elem))
                 }
                 // (vec elem*)
-                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec" => {
-                    Expr::Vec(es.iter().map(|e| self.parse_expr(e)).collect())
-                }
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec" => Expr::Vec(
+                    es.iter()
+                        .map(|e| self.parse_expr(e))
+                        .collect::<ParseResult<_>>()?,
+                ),
                 // (vec-set! idx elem)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec-set!" => {
                     let [vec, size, elem] = &es[..] else {
-                        return syntax_error("malformed vec-set!");
+                        return syntax_error("malformed vec-set!", span);
                     };
-                    let vec = self.parse_expr(vec);
-                    let idx = self.parse_expr(size);
-                    let elem = self.parse_expr(elem);
+                    let vec = self.parse_expr(vec)?;
+                    let idx = self.parse_expr(size)?;
+                    let elem = self.parse_expr(elem)?;
                     Expr::VecSet(Box::new(vec), Box::new(idx), Box::new(elem))
                 }
                 // (vec-get idx elem)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec-get" => {
                     let [vec, idx] = &es[..] else {
-                        return syntax_error("malformed vec-get");
+                        return syntax_error("malformed vec-get", span);
                     };
-                    let vec = self.parse_expr(vec);
-                    let idx = self.parse_expr(idx);
+                    let vec = self.parse_expr(vec)?;
+                    let idx = self.parse_expr(idx)?;
                     Expr::VecGet(Box::new(vec), Box::new(idx))
                 }
                 // (vec-len vec)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec-len" => {
                     let [vec] = &es[..] else {
-                        return syntax_error("malformed vec-len");
+                        return syntax_error("malformed vec-len", span);
                     };
-                    let vec = self.parse_expr(vec);
+                    let vec = self.parse_expr(vec)?;
                     Expr::VecLen(Box::new(vec))
                 }
                 // Block
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "block" => {
-                    let es: Vec<_> = es.iter().map(|e| self.parse_expr(e)).collect();
+                    let es: Vec<_> = es
+                        .iter()
+                        .map(|e| self.parse_expr(e))
+                        .collect::<ParseResult<_>>()?;
                     if !es.is_empty() {
                         Expr::Block(es)
                     } else {
-                        syntax_error("blocks must contain at least one expression")
+                        return syntax_error("blocks must contain at least one expression", span);
                     }
                 }
 
                 // (let <bindings> <expr>)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "let" => {
                     let [e1, e2] = &es[..] else {
-                        return syntax_error("malformed let");
+                        return syntax_error("malformed let", span);
                     };
                     match e1 {
                         Sexp::List(bindings) => {
                             if bindings.is_empty() {
-                                return syntax_error("empty bindings");
+                                return syntax_error("empty bindings", self.span(e1));
                             }
-                            let bindings: Vec<_> =
-                                bindings.iter().map(|e| self.parse_binding(e)).collect();
-                            let body = self.parse_expr(e2);
-                            Expr::Let(bindings, Box::new(body))
+                            let bindings: Vec<_> = bindings
+                                .iter()
+                                .map(|e| self.parse_binding(e))
+                                .collect::<ParseResult<_>>()?;
+                            let body = self.parse_expr(e2)?;
+                            Expr::Let(bindings, Box::new(body), span)
                         }
-                        _ => syntax_error("invalid let expr"),
+                        _ => return syntax_error("invalid let expr", self.span(e1)),
                     }
                 }
 
                 // set! <name> <expr> => Set
                 [Sexp::Atom(S(keyword)), Sexp::Atom(S(id)), e] if keyword == "set!" => {
-                    let e = self.parse_expr(e);
-                    Expr::Set(Symbol::new(id), Box::new(e))
+                    let e = self.parse_expr(e)?;
+                    Expr::Set(Symbol::new(id), Box::new(e), span)
+                }
+
+                // (fn (<params>) <expr>) => Lambda
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "fn" => {
+                    let [Sexp::List(params), body] = &es[..] else {
+                        return syntax_error("malformed fn", span);
+                    };
+                    let params = params
+                        .iter()
+                        .map(|e| self.parse_identifier(e))
+                        .collect::<ParseResult<_>>()?;
+                    let body = self.parse_expr(body)?;
+                    Expr::Lambda(params, Box::new(body), span)
+                }
+
+                // (call <expr> <expr>*) => CallIndirect
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "call" => {
+                    let [fun, args @ ..] = &es[..] else {
+                        return syntax_error("call expects a closure to call", span);
+                    };
+                    let fun = self.parse_expr(fun)?;
+                    let args: Vec<_> = args
+                        .iter()
+                        .map(|e| self.parse_expr(e))
+                        .collect::<ParseResult<_>>()?;
+                    Expr::CallIndirect(Box::new(fun), args, span)
                 }
 
                 // if <expr> <expr> <expr> => If
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "if" => {
                     let [e1, e2, e3] = &es[..] else {
-                        syntax_error("malformed if")
+                        return syntax_error("malformed if", span);
                     };
-                    let e1 = self.parse_expr(e1);
-                    let e2 = self.parse_expr(e2);
-                    let e3 = self.parse_expr(e3);
+                    let e1 = self.parse_expr(e1)?;
+                    let e2 = self.parse_expr(e2)?;
+                    let e3 = self.parse_expr(e3)?;
 
                     Expr::If(Box::new(e1), Box::new(e2), Box::new(e3))
                 }
@@ -164,19 +239,19 @@ impl Parser {
                     ) =>
                 {
                     let [e] = es else {
-                        return syntax_error("expected a single expression after keyword");
+                        return syntax_error("expected a single expression after keyword", span);
                     };
-                    let e_expr = self.parse_expr(e);
+                    let e_expr = self.parse_expr(e)?;
 
                     match keyword.as_str() {
                         "loop" => Expr::Loop(Box::new(e_expr)),
-                        "break" => Expr::Break(Box::new(e_expr)),
-                        "print" => Expr::UnOp(Op1::Print, Box::new(e_expr)),
-                        "add1" => Expr::UnOp(Op1::Add1, Box::new(e_expr)),
-                        "sub1" => Expr::UnOp(Op1::Sub1, Box::new(e_expr)),
-                        "isnum" => Expr::UnOp(Op1::IsNum, Box::new(e_expr)),
-                        "isbool" => Expr::UnOp(Op1::IsBool, Box::new(e_expr)),
-                        "isvec" => Expr::UnOp(Op1::IsVec, Box::new(e_expr)),
+                        "break" => Expr::Break(Box::new(e_expr), span),
+                        "print" => Expr::UnOp(Op1::Print, Box::new(e_expr), span),
+                        "add1" => Expr::UnOp(Op1::Add1, Box::new(e_expr), span),
+                        "sub1" => Expr::UnOp(Op1::Sub1, Box::new(e_expr), span),
+                        "isnum" => Expr::UnOp(Op1::IsNum, Box::new(e_expr), span),
+                        "isbool" => Expr::UnOp(Op1::IsBool, Box::new(e_expr), span),
+                        "isvec" => Expr::UnOp(Op1::IsVec, Box::new(e_expr), span),
                         _ => unreachable!(),
                     }
                 }
@@ -188,7 +263,7 @@ impl Parser {
                     ) =>
                 {
                     let [e1, e2] = es else {
-                        return syntax_error("expected two expressions after operator");
+                        return syntax_error("expected two expressions after operator", span);
                     };
                     let expr_op = match op.as_str() {
                         "+" => Op2::Plus,
@@ -203,72 +278,192 @@ impl Parser {
                         _ => unreachable!(),
                     };
 
-                    let e1_instrs = self.parse_expr(e1);
-                    let e2_instrs = self.parse_expr(e2);
+                    let e1_instrs = self.parse_expr(e1)?;
+                    let e2_instrs = self.parse_expr(e2)?;
 
-                    Expr::BinOp(expr_op, Box::new(e1_instrs), Box::new(e2_instrs))
+                    Expr::BinOp(expr_op, Box::new(e1_instrs), Box::new(e2_instrs), span)
                 }
 
                 [func, args @ ..] => {
-                    let func = self.parse_identifier(func);
-                    let exprs: Vec<_> = args.iter().map(|e| self.parse_expr(e)).collect();
-                    Expr::Call(Symbol::new(func), exprs)
+                    let func = self.parse_identifier(func)?;
+                    let exprs: Vec<_> = args
+                        .iter()
+                        .map(|e| self.parse_expr(e))
+                        .collect::<ParseResult<_>>()?;
+                    Expr::Call(func, exprs, span)
                 }
-                _ => syntax_error("unexpected s-expr"),
+                _ => return syntax_error("unexpected s-expr", span),
             },
 
-            _ => syntax_error("unexpected s-expr"),
-        }
+            _ => return syntax_error("unexpected s-expr", span),
+        };
+        Ok(expr)
     }
 
-    fn parse_binding(&self, e: &Sexp) -> (Symbol, Expr) {
+    fn parse_binding(&self, e: &Sexp) -> ParseResult<(Symbol, Expr)> {
         let Sexp::List(es) = e else {
-            return syntax_error("expected a list")
+            return syntax_error("expected a list", self.span(e));
         };
         if let [name, expr] = &es[..] {
-            (self.parse_identifier(name), self.parse_expr(expr))
+            Ok((self.parse_identifier(name)?, self.parse_expr(expr)?))
         } else {
-            syntax_error("malformed binding")
+            syntax_error("malformed binding", self.span(e))
         }
     }
 
-    fn parse_func(&self, e: &Sexp) -> FunDecl {
+    fn parse_func(&self, e: &Sexp) -> ParseResult<FunDecl> {
+        let span = self.span(e);
         let Sexp::List(es) = e else {
-            return syntax_error("expected a list");
+            return syntax_error("expected a list", span);
         };
         match &es[..] {
             [Sexp::Atom(S(keyword)), Sexp::List(es), body] if keyword == "fun" => {
                 let [name, params @ ..] = &es[..] else {
-                    return syntax_error("missing function name");
+                    return syntax_error("missing function name", span);
                 };
-                let params = params.iter().map(|e| self.parse_identifier(e)).collect();
-                let body = self.parse_expr(body);
-                let name = self.parse_identifier(name);
-                FunDecl {
-                    name: Symbol::new(name),
+                let params = params
+                    .iter()
+                    .map(|e| self.parse_identifier(e))
+                    .collect::<ParseResult<_>>()?;
+                let body = self.parse_expr(body)?;
+                let name = self.parse_identifier(name)?;
+                Ok(FunDecl {
+                    name,
                     params,
                     body,
-                }
+                    span,
+                })
             }
-            _ => syntax_error("malformed function"),
+            _ => syntax_error("malformed function", span),
         }
     }
 
-    fn parse_identifier(&self, e: &Sexp) -> Symbol {
-        let Sexp::Atom(S(s)) = e  else {
-            return syntax_error("expected an identifier");
+    fn parse_identifier(&self, e: &Sexp) -> ParseResult<Symbol> {
+        let span = self.span(e);
+        let Sexp::Atom(S(s)) = e else {
+            return syntax_error("expected an identifier", span);
         };
 
         if is_keyword(s) {
-            syntax_error(format!("cannot use keyword `{s}` as identifier"))
+            syntax_error(format!("cannot use keyword `{s}` as identifier"), span)
         } else if self.id_regex.is_match(s) {
-            Symbol::new(s)
+            Ok(Symbol::new(s))
         } else {
-            syntax_error("invalid identifier")
+            syntax_error("invalid identifier", span)
         }
     }
 }
 
+/// Maps byte offsets of the original source to line/column positions. `sexp::parse` does not
+/// keep positions, so [`SourceMap::spans`] re-scans the text with the same lexical rules
+/// (whitespace, `;` comments, quoted and unquoted atoms) and pairs the nodes up in pre-order.
+struct SourceMap<'a> {
+    src: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    fn new(src: &'a str) -> SourceMap<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(src.match_indices('\n').map(|(i, _)| i + 1));
+        SourceMap { src, line_starts }
+    }
+
+    fn pos(&self, offset: usize) -> Pos {
+        let offset = offset.min(self.src.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let col = self.src[self.line_starts[line]..offset].chars().count();
+        Pos {
+            line: line + 1,
+            col: col + 1,
+        }
+    }
+
+    /// `wrapped` is the source surrounded by one pair of parentheses, as handed to `sexp::parse`
+    fn spans(&self, wrapped: &str, root: &Sexp) -> HashMap<*const Sexp, Span> {
+        let mut ranges = vec![];
+        scan_node(wrapped.as_bytes(), &mut 0, &mut ranges);
+        let mut ranges = ranges.into_iter();
+        let mut spans = HashMap::new();
+        self.assign(root, &mut ranges, &mut spans);
+        spans
+    }
+
+    fn assign(
+        &self,
+        e: &Sexp,
+        ranges: &mut impl Iterator<Item = (usize, usize)>,
+        spans: &mut HashMap<*const Sexp, Span>,
+    ) {
+        let (lo, hi) = ranges.next().unwrap_or((0, 0));
+        // undo the shift introduced by the wrapping parenthesis
+        let span = Span {
+            start: self.pos(lo.saturating_sub(1)),
+            end: self.pos(hi.saturating_sub(1)),
+        };
+        spans.insert(e as *const Sexp, span);
+        if let Sexp::List(es) = e {
+            for e in es {
+                self.assign(e, ranges, spans);
+            }
+        }
+    }
+}
+
+fn skip_space(s: &[u8], pos: &mut usize) {
+    while *pos < s.len() {
+        if s[*pos] == b';' {
+            while *pos < s.len() && s[*pos] != b'\n' {
+                *pos += 1;
+            }
+        } else if s[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        } else {
+            return;
+        }
+    }
+}
+
+/// Records the byte range of the node starting at `pos` and all of its children in pre-order
+fn scan_node(s: &[u8], pos: &mut usize, ranges: &mut Vec<(usize, usize)>) {
+    skip_space(s, pos);
+    let start = *pos;
+    let idx = ranges.len();
+    ranges.push((start, start));
+    match s.get(*pos) {
+        Some(b'(') => {
+            *pos += 1;
+            loop {
+                skip_space(s, pos);
+                match s.get(*pos) {
+                    Some(b')') => {
+                        *pos += 1;
+                        break;
+                    }
+                    None => break,
+                    Some(_) => scan_node(s, pos, ranges),
+                }
+            }
+        }
+        Some(b'"') => {
+            *pos += 1;
+            while *pos < s.len() && s[*pos] != b'"' {
+                *pos += if s[*pos] == b'\\' { 2 } else { 1 };
+            }
+            *pos = (*pos + 1).min(s.len());
+        }
+        _ => {
+            while *pos < s.len()
+                && !matches!(s[*pos], b'(' | b')' | b';')
+                && !s[*pos].is_ascii_whitespace()
+            {
+                *pos += 1;
+            }
+        }
+    }
+    ranges[idx] = (start, *pos);
+}
+
 fn is_keyword(s: &str) -> bool {
     matches!(
         s,
@@ -287,6 +482,8 @@ fn is_keyword(s: &str) -> bool {
             | "input"
             | "nil"
             | "fun"
+            | "fn"
+            | "call"
             | "make-vec"
             | "vec"
             | "vec-set!"
@@ -297,6 +494,10 @@ fn is_keyword(s: &str) -> bool {
     )
 }
 
-fn syntax_error<T>(note: impl ToString) -> T {
-    panic!("Invalid syntax: {}", note.to_string())
+fn syntax_error<T>(note: impl ToString, span: Span) -> ParseResult<T> {
+    Err(CompileError::new(syntax(note), span))
+}
+
+fn syntax(note: impl ToString) -> ErrorKind {
+    ErrorKind::Syntax(note.to_string())
 }
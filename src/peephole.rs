@@ -0,0 +1,294 @@
+use crate::asm::{Arg64, Instr, MemRef, MovArgs, Offset, Reg, Reg32};
+
+/// A rewrite rule: given the instructions from some point on, it returns how many of them it
+/// replaces and what with, or `None` if it doesn't apply there.
+type Rewrite = fn(&[Instr]) -> Option<(usize, Vec<Instr>)>;
+
+/// Every rule only looks at adjacent instructions, so no label (and no jump into the middle of
+/// the window) can sit between the ones it rewrites.
+const RULES: &[Rewrite] = &[
+    self_move,
+    overwritten_move,
+    store_then_load,
+    load_then_store,
+    jump_to_next,
+    branch_over_jump,
+];
+
+/// Applies the rules until none of them changes anything. Works on the output of either backend.
+pub fn optimize(instrs: &[Instr]) -> Vec<Instr> {
+    let mut instrs = instrs.to_vec();
+    while let Some(next) = rewrite_once(&instrs) {
+        instrs = next;
+    }
+    instrs
+}
+
+fn rewrite_once(instrs: &[Instr]) -> Option<Vec<Instr>> {
+    let mut out = Vec::with_capacity(instrs.len());
+    let mut changed = false;
+    let mut i = 0;
+    'outer: while i < instrs.len() {
+        for rule in RULES {
+            if let Some((n, replacement)) = rule(&instrs[i..]) {
+                out.extend(replacement);
+                i += n;
+                changed = true;
+                continue 'outer;
+            }
+        }
+        out.push(instrs[i].clone());
+        i += 1;
+    }
+    changed.then_some(out)
+}
+
+fn mem_uses(m: &MemRef, r: Reg) -> bool {
+    m.reg == r || matches!(m.offset, Offset::Computed { reg, .. } if reg == r)
+}
+
+fn arg_uses(arg: &Arg64, r: Reg) -> bool {
+    match arg {
+        Arg64::Reg(reg) => *reg == r,
+        Arg64::Imm(_) => false,
+        Arg64::Mem(m) => mem_uses(m, r),
+    }
+}
+
+/// `mov r, r`
+fn self_move(is: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    match is {
+        [Instr::Mov(MovArgs::ToReg(r, Arg64::Reg(s))), ..] if r == s => Some((1, vec![])),
+        _ => None,
+    }
+}
+
+/// `mov r, x; mov r, y` where `y` doesn't read `r`: the first move is dead
+fn overwritten_move(is: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    match is {
+        [Instr::Mov(MovArgs::ToReg(r1, _)), second @ Instr::Mov(MovArgs::ToReg(r2, y)), ..]
+            if r1 == r2 && !arg_uses(y, *r2) =>
+        {
+            Some((2, vec![second.clone()]))
+        }
+        _ => None,
+    }
+}
+
+/// `mov [m], r; mov s, [m]` loads back what was just stored, so `s` can take it from `r`
+fn store_then_load(is: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    match is {
+        [store @ Instr::Mov(MovArgs::ToMem(m1, Reg32::Reg(r))), Instr::Mov(MovArgs::ToReg(s, Arg64::Mem(m2))), ..]
+            if m1 == m2 =>
+        {
+            if r == s {
+                Some((2, vec![store.clone()]))
+            } else {
+                Some((
+                    2,
+                    vec![
+                        store.clone(),
+                        Instr::Mov(MovArgs::ToReg(*s, Arg64::Reg(*r))),
+                    ],
+                ))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// `mov r, [m]; mov [m], r` stores back what was just loaded, unless the load changed the
+/// address
+fn load_then_store(is: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    match is {
+        [load @ Instr::Mov(MovArgs::ToReg(r, Arg64::Mem(m1))), Instr::Mov(MovArgs::ToMem(m2, Reg32::Reg(s))), ..]
+            if m1 == m2 && r == s && !mem_uses(m1, *r) =>
+        {
+            Some((2, vec![load.clone()]))
+        }
+        _ => None,
+    }
+}
+
+/// `jmp l` right before `l:`, possibly among other labels
+fn jump_to_next(is: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let [Instr::Jmp(target), rest @ ..] = is else {
+        return None;
+    };
+    rest.iter()
+        .map_while(|i| match i {
+            Instr::Label(l) => Some(l),
+            _ => None,
+        })
+        .any(|l| l == target)
+        .then(|| (1, vec![]))
+}
+
+/// The branch with the opposite condition to the same target
+fn negate(branch: &Instr, target: String) -> Option<Instr> {
+    Some(match branch {
+        Instr::Je(_) => Instr::Jne(target),
+        Instr::Jne(_) => Instr::Je(target),
+        Instr::Jz(_) => Instr::Jnz(target),
+        Instr::Jnz(_) => Instr::Jz(target),
+        Instr::Jl(_) => Instr::Jge(target),
+        Instr::Jge(_) => Instr::Jl(target),
+        Instr::Jg(_) => Instr::Jle(target),
+        Instr::Jle(_) => Instr::Jg(target),
+        Instr::Jo(_) => Instr::Jno(target),
+        Instr::Jno(_) => Instr::Jo(target),
+        _ => return None,
+    })
+}
+
+fn branch_target(branch: &Instr) -> Option<&String> {
+    match branch {
+        Instr::Je(l)
+        | Instr::Jne(l)
+        | Instr::Jz(l)
+        | Instr::Jnz(l)
+        | Instr::Jl(l)
+        | Instr::Jge(l)
+        | Instr::Jg(l)
+        | Instr::Jle(l)
+        | Instr::Jo(l)
+        | Instr::Jno(l) => Some(l),
+        _ => None,
+    }
+}
+
+/// `jcc l1; jmp l2; l1:` becomes `jncc l2; l1:`
+fn branch_over_jump(is: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    match is {
+        [branch, Instr::Jmp(l2), Instr::Label(l1), ..] if branch_target(branch) == Some(l1) => {
+            Some((2, vec![negate(branch, l2.clone())?]))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{Arg32, BinArgs, Reg::*};
+    use crate::mref;
+
+    fn mov(r: Reg, arg: Arg64) -> Instr {
+        Instr::Mov(MovArgs::ToReg(r, arg))
+    }
+
+    fn store(m: MemRef, r: Reg) -> Instr {
+        Instr::Mov(MovArgs::ToMem(m, Reg32::Reg(r)))
+    }
+
+    fn label(l: &str) -> Instr {
+        Instr::Label(l.to_string())
+    }
+
+    #[test]
+    fn removes_self_moves() {
+        let is = vec![mov(Rax, Arg64::Reg(Rax)), mov(Rbx, Arg64::Reg(Rax))];
+        assert_eq!(optimize(&is), vec![mov(Rbx, Arg64::Reg(Rax))]);
+    }
+
+    #[test]
+    fn removes_overwritten_moves() {
+        let is = vec![mov(Rax, Arg64::Imm(1)), mov(Rax, Arg64::Imm(2))];
+        assert_eq!(optimize(&is), vec![mov(Rax, Arg64::Imm(2))]);
+    }
+
+    #[test]
+    fn keeps_moves_read_by_the_next_one() {
+        let is = vec![
+            mov(Rax, Arg64::Imm(1)),
+            mov(Rax, Arg64::Mem(mref!(Rax + 8))),
+        ];
+        assert_eq!(optimize(&is), is);
+    }
+
+    #[test]
+    fn forwards_stores_to_loads() {
+        let slot = mref!(Rsp + 16);
+        let is = vec![store(slot, Rax), mov(Rcx, Arg64::Mem(slot))];
+        assert_eq!(
+            optimize(&is),
+            vec![store(slot, Rax), mov(Rcx, Arg64::Reg(Rax))]
+        );
+
+        let is = vec![store(slot, Rax), mov(Rax, Arg64::Mem(slot))];
+        assert_eq!(optimize(&is), vec![store(slot, Rax)]);
+    }
+
+    #[test]
+    fn keeps_loads_from_other_slots() {
+        let is = vec![
+            store(mref!(Rsp + 16), Rax),
+            mov(Rcx, Arg64::Mem(mref!(Rsp + 24))),
+        ];
+        assert_eq!(optimize(&is), is);
+    }
+
+    #[test]
+    fn removes_stores_of_what_was_just_loaded() {
+        let slot = mref!(Rsp + 8);
+        let is = vec![mov(Rax, Arg64::Mem(slot)), store(slot, Rax)];
+        assert_eq!(optimize(&is), vec![mov(Rax, Arg64::Mem(slot))]);
+    }
+
+    #[test]
+    fn keeps_stores_when_the_load_moved_the_address() {
+        let slot = mref!(Rax + 8);
+        let is = vec![mov(Rax, Arg64::Mem(slot)), store(slot, Rax)];
+        assert_eq!(optimize(&is), is);
+    }
+
+    #[test]
+    fn removes_jumps_to_the_next_label() {
+        let is = vec![Instr::Jmp("b".to_string()), label("a"), label("b")];
+        assert_eq!(optimize(&is), vec![label("a"), label("b")]);
+    }
+
+    #[test]
+    fn keeps_jumps_over_code() {
+        let is = vec![
+            Instr::Jmp("b".to_string()),
+            mov(Rax, Arg64::Imm(1)),
+            label("b"),
+        ];
+        assert_eq!(optimize(&is), is);
+    }
+
+    #[test]
+    fn flips_branches_over_jumps() {
+        let is = vec![
+            Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Imm(7))),
+            Instr::Je("then".to_string()),
+            Instr::Jmp("else".to_string()),
+            label("then"),
+            mov(Rax, Arg64::Imm(1)),
+        ];
+        assert_eq!(
+            optimize(&is),
+            vec![
+                Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Imm(7))),
+                Instr::Jne("else".to_string()),
+                label("then"),
+                mov(Rax, Arg64::Imm(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_until_nothing_changes() {
+        // dropping the self move makes the store adjacent to its load
+        let slot = mref!(Rsp + 8);
+        let is = vec![
+            store(slot, Rbx),
+            mov(Rcx, Arg64::Reg(Rcx)),
+            mov(Rbx, Arg64::Mem(slot)),
+            Instr::Jmp("end".to_string()),
+            label("end"),
+        ];
+        assert_eq!(optimize(&is), vec![store(slot, Rbx), label("end")]);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    asm::Reg,
+    dataflow::liveness,
+    ir::{Block, Step},
+    syntax::Symbol,
+};
+
+/// Where a variable of a function body lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Home {
+    Reg(Reg),
+    /// Index of the stack slot, slot `k` is at `[rbp - 8 * (k + 1)]`
+    Stack(u32),
+}
+
+pub struct Allocation {
+    /// Home of every variable assigned in the body, function arguments are not included and stay
+    /// where the caller pushed them
+    pub homes: HashMap<Symbol, Home>,
+    /// Number of stack slots used by spilled variables
+    pub slots: u32,
+    /// For every step, the registers holding values that are needed by the step or after it.
+    /// These must be saved around anything that calls out, both because the callee may clobber
+    /// them and so that the GC can find (and update) the heap references they hold.
+    pub live_regs: Vec<Vec<Reg>>,
+}
+
+/// Linear scan allocation of the variables assigned in `b` to `regs`.
+///
+/// The live range of a variable is approximated by the interval between the first and the last
+/// step where it is live (or assigned), in the order the steps are laid out. When there are more
+/// overlapping intervals than registers, the one ending last is spilled to the stack.
+pub fn allocate(
+    b: &Block,
+    args: &[Symbol],
+    regs: &[Reg],
+    is_fixed: impl Fn(&Symbol) -> bool,
+) -> Allocation {
+    let live = liveness(b);
+
+    let mut intervals: HashMap<Symbol, (usize, usize)> = HashMap::new();
+    let mut extend = |x: Symbol, i: usize| {
+        let range = intervals.entry(x).or_insert((i, i));
+        range.0 = range.0.min(i);
+        range.1 = range.1.max(i);
+    };
+    for (i, step) in b.steps.iter().enumerate() {
+        if let Some(x) = step.def() {
+            extend(x, i);
+        }
+        for x in live.live_in[i].iter().chain(&live.live_out[i]) {
+            extend(*x, i);
+        }
+    }
+    let assigned: HashSet<Symbol> = b.steps.iter().filter_map(Step::def).collect();
+    let mut order: Vec<(Symbol, (usize, usize))> = intervals
+        .into_iter()
+        .filter(|(x, _)| assigned.contains(x) && !args.contains(x) && !is_fixed(x))
+        .collect();
+    // ties are broken by name to keep the output deterministic
+    order.sort_by_key(|(x, (start, end))| (*start, *end, x.to_string()));
+
+    let mut homes = HashMap::new();
+    let mut slots = 0;
+    let mut free: Vec<Reg> = regs.iter().rev().copied().collect();
+    let mut active: Vec<(usize, Symbol, Reg)> = vec![];
+    for (x, (start, end)) in order {
+        active.retain(|&(active_end, _, reg)| {
+            if active_end < start {
+                free.push(reg);
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(reg) = free.pop() {
+            homes.insert(x, Home::Reg(reg));
+            active.push((end, x, reg));
+            continue;
+        }
+        let furthest = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (active_end, _, _))| *active_end)
+            .map(|(i, _)| i);
+        match furthest {
+            Some(i) if active[i].0 > end => {
+                let (_, spilled, reg) = active[i];
+                homes.insert(spilled, Home::Stack(slots));
+                homes.insert(x, Home::Reg(reg));
+                active[i] = (end, x, reg);
+            }
+            _ => {
+                homes.insert(x, Home::Stack(slots));
+            }
+        }
+        slots += 1;
+    }
+
+    let reg_of = |x: &Symbol| match homes.get(x) {
+        Some(Home::Reg(reg)) => Some(*reg),
+        _ => None,
+    };
+    let live_regs = (0..b.steps.len())
+        .map(|i| {
+            // the variable assigned by the step isn't holding anything yet
+            let mut used: Vec<Reg> = live.live_in[i].iter().filter_map(reg_of).collect();
+            used.sort_by_key(|reg| regs.iter().position(|r| r == reg));
+            used.dedup();
+            used
+        })
+        .collect();
+
+    Allocation {
+        homes,
+        slots,
+        live_regs,
+    }
+}
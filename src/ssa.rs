@@ -0,0 +1,428 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    cfg::Cfg,
+    dataflow,
+    ir::{Block, IRExpr, Prog, Step, Val},
+    syntax::Symbol,
+};
+
+/// Converts every function body (and main) to static single assignment form: each variable is
+/// assigned by exactly one step, and where different definitions of a variable meet, a `Phi`
+/// step at the top of the block picks the value of the predecessor control came from.
+///
+/// Only pruned phis are inserted, i.e. a block gets a phi for a variable only if the variable is
+/// live on entry to it. The hard-coded registers (`rax`, `r15`) are left alone.
+pub fn to_ssa(prog: &Prog) -> Prog {
+    Prog {
+        defs: prog
+            .defs
+            .iter()
+            .map(|d| {
+                let mut cfg = Cfg::from_def(d);
+                cfg_to_ssa(&mut cfg, &d.args);
+                cfg.to_def(d.name, d.args.clone())
+            })
+            .collect(),
+        main: {
+            let mut cfg = Cfg::from_block(&prog.main);
+            cfg_to_ssa(&mut cfg, &[]);
+            cfg.to_block()
+        },
+    }
+}
+
+/// Replaces the phis by copies at the end of the predecessors, splitting critical edges (from a
+/// block with several successors to a block with several predecessors) so the copies only run
+/// when control takes that edge.
+pub fn from_ssa(prog: &Prog) -> Prog {
+    Prog {
+        defs: prog
+            .defs
+            .iter()
+            .map(|d| {
+                let mut cfg = Cfg::from_def(d);
+                cfg_from_ssa(&mut cfg);
+                cfg.to_def(d.name, d.args.clone())
+            })
+            .collect(),
+        main: {
+            let mut cfg = Cfg::from_block(&prog.main);
+            cfg_from_ssa(&mut cfg);
+            cfg.to_block()
+        },
+    }
+}
+
+fn is_hard_coded_reg(x: &Symbol) -> bool {
+    matches!(x.to_string().as_str(), "rax" | "r15")
+}
+
+/// Generates labels that don't clash with the ones already in a body
+struct LabelGen {
+    used: HashSet<Symbol>,
+    next: usize,
+}
+
+impl LabelGen {
+    fn new(cfg: &Cfg) -> LabelGen {
+        LabelGen {
+            used: cfg.labels(),
+            next: 0,
+        }
+    }
+
+    fn fresh(&mut self, prefix: &str) -> Symbol {
+        loop {
+            let l = Symbol::new(format!("{prefix}_{}", self.next));
+            self.next += 1;
+            if self.used.insert(l) {
+                return l;
+            }
+        }
+    }
+}
+
+/// Immediate dominator of every block reachable from the entry (the entry is its own), using the
+/// iterative algorithm of Cooper, Harvey and Kennedy.
+pub fn dominators(cfg: &Cfg) -> Vec<Option<usize>> {
+    let rpo = cfg.reverse_postorder();
+    let mut order = vec![usize::MAX; cfg.blocks.len()];
+    for (i, b) in rpo.iter().enumerate() {
+        order[*b] = i;
+    }
+    let mut idom: Vec<Option<usize>> = vec![None; cfg.blocks.len()];
+    idom[cfg.entry] = Some(cfg.entry);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in rpo.iter().skip(1) {
+            let mut new_idom = None;
+            for &p in &cfg.blocks[b].preds {
+                if idom[p].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => p,
+                    Some(mut other) => {
+                        let mut p = p;
+                        while p != other {
+                            while order[p] > order[other] {
+                                p = idom[p].unwrap();
+                            }
+                            while order[other] > order[p] {
+                                other = idom[other].unwrap();
+                            }
+                        }
+                        p
+                    }
+                });
+            }
+            if new_idom != idom[b] {
+                idom[b] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+/// Dominance frontier of every block: the blocks where its dominance ends
+pub fn dominance_frontiers(cfg: &Cfg, idom: &[Option<usize>]) -> Vec<HashSet<usize>> {
+    let mut df = vec![HashSet::new(); cfg.blocks.len()];
+    for (b, bb) in cfg.blocks.iter().enumerate() {
+        let Some(b_idom) = idom[b] else { continue };
+        if bb.preds.len() < 2 {
+            continue;
+        }
+        for &p in &bb.preds {
+            let mut runner = p;
+            while idom[runner].is_some() && runner != b_idom {
+                df[runner].insert(b);
+                runner = idom[runner].unwrap();
+            }
+        }
+    }
+    df
+}
+
+fn cfg_to_ssa(cfg: &mut Cfg, args: &[Symbol]) {
+    cfg.remove_unreachable();
+    let mut labels = LabelGen::new(cfg);
+
+    // phis go after the label of a block and refer to the labels of its predecessors, so every
+    // block needs one, and the entry must not have predecessors
+    if !cfg.blocks[cfg.entry].preds.is_empty() {
+        let mut steps = vec![Step::Label(labels.fresh("entry"))];
+        steps.extend(cfg.to_block().steps);
+        *cfg = Cfg::from_block(&Block { steps });
+    }
+    for b in 0..cfg.exit {
+        if cfg.blocks[b].label().is_none() {
+            cfg.blocks[b]
+                .steps
+                .insert(0, Step::Label(labels.fresh("bb")));
+        }
+    }
+
+    let idom = dominators(cfg);
+    let df = dominance_frontiers(cfg, &idom);
+
+    // variables live on entry to every block
+    let live = dataflow::liveness(&cfg.to_block());
+    let mut start = 0;
+    let mut live_in = vec![];
+    for bb in &cfg.blocks {
+        live_in.push(live.live_in.get(start).cloned().unwrap_or_default());
+        start += bb.steps.len();
+    }
+
+    let mut def_blocks: HashMap<Symbol, HashSet<usize>> = HashMap::new();
+    for x in args {
+        def_blocks.entry(*x).or_default().insert(cfg.entry);
+    }
+    for (b, bb) in cfg.blocks.iter().enumerate() {
+        for x in bb.steps.iter().filter_map(Step::def) {
+            if !is_hard_coded_reg(&x) {
+                def_blocks.entry(x).or_default().insert(b);
+            }
+        }
+    }
+
+    // the variable each phi is for, in the order of the phis at the top of the block
+    let mut phi_vars: Vec<Vec<Symbol>> = vec![vec![]; cfg.blocks.len()];
+    let mut vars: Vec<&Symbol> = def_blocks.keys().collect();
+    vars.sort_by_key(|x| x.to_string());
+    for x in vars {
+        let mut worklist: Vec<usize> = def_blocks[x].iter().copied().collect();
+        while let Some(b) = worklist.pop() {
+            for &d in &df[b] {
+                if phi_vars[d].contains(x) || !live_in[d].contains(x) {
+                    continue;
+                }
+                let at = 1 + phi_vars[d].len();
+                cfg.blocks[d].steps.insert(at, Step::Phi(*x, vec![]));
+                phi_vars[d].push(*x);
+                if !def_blocks[x].contains(&d) {
+                    worklist.push(d);
+                }
+            }
+        }
+    }
+
+    let mut children = vec![vec![]; cfg.blocks.len()];
+    for (b, d) in idom.iter().enumerate() {
+        match d {
+            Some(d) if *d != b => children[*d].push(b),
+            _ => (),
+        }
+    }
+    let mut renamer = Renamer {
+        stacks: def_blocks.keys().map(|x| (*x, vec![])).collect(),
+        counters: HashMap::new(),
+    };
+    for x in args {
+        renamer.stacks.insert(*x, vec![*x]);
+    }
+    renamer.rename(cfg, cfg.entry, &children, &phi_vars);
+}
+
+struct Renamer {
+    /// The current names of every renamed variable, innermost last
+    stacks: HashMap<Symbol, Vec<Symbol>>,
+    counters: HashMap<Symbol, usize>,
+}
+
+impl Renamer {
+    fn fresh(&mut self, x: Symbol) -> Symbol {
+        let n = self.counters.entry(x).or_insert(0);
+        *n += 1;
+        let name = Symbol::new(format!("{x}%{n}"));
+        self.stacks.get_mut(&x).unwrap().push(name);
+        name
+    }
+
+    /// The value a use of `x` refers to, a variable read before it is ever assigned is nil
+    fn current(&self, x: Symbol) -> Val {
+        match self.stacks.get(&x) {
+            Some(stack) => stack.last().map_or(Val::Nil, |n| Val::Var(*n)),
+            None => Val::Var(x),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        cfg: &mut Cfg,
+        b: usize,
+        children: &[Vec<usize>],
+        phi_vars: &[Vec<Symbol>],
+    ) {
+        let mut pushed = vec![];
+        let mut steps = std::mem::take(&mut cfg.blocks[b].steps);
+        for (i, step) in steps.iter_mut().enumerate() {
+            if let Step::Phi(x, _) = step {
+                let orig = phi_vars[b][i - 1];
+                *x = self.fresh(orig);
+                pushed.push(orig);
+                continue;
+            }
+            for v in step.vals_mut() {
+                if let Val::Var(y) = v {
+                    *v = self.current(*y);
+                }
+            }
+            if let Step::Set(x, _) = step {
+                if self.stacks.contains_key(x) {
+                    pushed.push(*x);
+                    *x = self.fresh(*x);
+                }
+            }
+        }
+        cfg.blocks[b].steps = steps;
+
+        let mut succs = cfg.blocks[b].succs.clone();
+        succs.dedup();
+        for s in succs {
+            let Some(label) = cfg.blocks[b].label() else {
+                break;
+            };
+            for (i, x) in phi_vars[s].iter().enumerate() {
+                let v = self.current(*x);
+                if let Step::Phi(_, ops) = &mut cfg.blocks[s].steps[i + 1] {
+                    ops.push((label, v));
+                }
+            }
+        }
+        for &c in &children[b] {
+            self.rename(cfg, c, children, phi_vars);
+        }
+        for x in pushed {
+            self.stacks.get_mut(&x).unwrap().pop();
+        }
+    }
+}
+
+/// Assignments that happen at once, `(destination, source)`
+type Copies = Vec<(Symbol, Val)>;
+
+fn cfg_from_ssa(cfg: &mut Cfg) {
+    let index: HashMap<Symbol, usize> = cfg
+        .blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, bb)| bb.label().map(|l| (l, i)))
+        .collect();
+    let mut labels = LabelGen::new(cfg);
+
+    // copies to make at the end of every block, and the edges that need a block of their own
+    // (target, source, copies)
+    let mut at_end: Vec<Copies> = vec![vec![]; cfg.blocks.len()];
+    let mut split: Vec<(usize, usize, Copies)> = vec![];
+    for b in 0..cfg.blocks.len() {
+        let mut copies: Vec<(usize, Copies)> = vec![];
+        for step in &cfg.blocks[b].steps {
+            if let Step::Phi(x, ops) = step {
+                for (l, v) in ops {
+                    let p = index[l];
+                    match copies.iter_mut().find(|(q, _)| *q == p) {
+                        Some((_, c)) => c.push((*x, *v)),
+                        None => copies.push((p, vec![(*x, *v)])),
+                    }
+                }
+            }
+        }
+        for (p, c) in copies {
+            let mut succs = cfg.blocks[p].succs.clone();
+            succs.dedup();
+            if succs.len() > 1 {
+                split.push((b, p, c));
+            } else {
+                at_end[p].extend(c);
+            }
+        }
+    }
+
+    // the branch into a split edge goes to the new block instead
+    let mut edges: Vec<(usize, Symbol, Copies)> = vec![];
+    for (b, p, c) in split {
+        let target = cfg.blocks[b].label().unwrap();
+        let edge = labels.fresh("edge");
+        if let Some(Step::If(_, thn, els)) = cfg.blocks[p].steps.last_mut() {
+            for l in [thn, els] {
+                if *l == target {
+                    *l = edge;
+                }
+            }
+        }
+        edges.push((b, edge, c));
+    }
+
+    let mut blocks: Vec<Vec<Step>> = vec![];
+    for (b, bb) in cfg.blocks.iter().enumerate() {
+        let mut into = edges.iter().filter(|(t, _, _)| *t == b).peekable();
+        if into.peek().is_some() {
+            let target = bb.label().unwrap();
+            if let Some(prev) = blocks.last_mut() {
+                if !matches!(prev.last(), Some(Step::Goto(_) | Step::If(..))) {
+                    prev.push(Step::Goto(target));
+                }
+            }
+            while let Some((_, edge, c)) = into.next() {
+                let mut steps = vec![Step::Label(*edge)];
+                steps.extend(sequentialize(c));
+                if into.peek().is_some() {
+                    steps.push(Step::Goto(target));
+                }
+                blocks.push(steps);
+            }
+        }
+        let mut steps: Vec<Step> = bb
+            .steps
+            .iter()
+            .filter(|s| !matches!(s, Step::Phi(..)))
+            .cloned()
+            .collect();
+        if !at_end[b].is_empty() {
+            let jump = match steps.last() {
+                // with a single successor both branches go to the same place
+                Some(Step::If(_, l, _) | Step::Goto(l)) => Some(*l),
+                _ => None,
+            };
+            if jump.is_some() {
+                steps.pop();
+            }
+            steps.extend(sequentialize(&at_end[b]));
+            steps.extend(jump.map(Step::Goto));
+        }
+        blocks.push(steps);
+    }
+    *cfg = Cfg::from_block(&Block {
+        steps: blocks.into_iter().flatten().collect(),
+    });
+}
+
+/// The copies of the phis along one edge happen all at once, so a source that is the destination
+/// of another copy is read through a temporary
+fn sequentialize(copies: &[(Symbol, Val)]) -> Vec<Step> {
+    let dests: HashSet<Symbol> = copies.iter().map(|(x, _)| *x).collect();
+    let conflict = copies
+        .iter()
+        .any(|(_, v)| matches!(v, Val::Var(y) if dests.contains(y)));
+    if !conflict {
+        return copies
+            .iter()
+            .map(|(x, v)| Step::Set(*x, IRExpr::Val(*v)))
+            .collect();
+    }
+    let tmp = |x: &Symbol| Symbol::new(format!("{x}%copy"));
+    let mut steps: Vec<Step> = copies
+        .iter()
+        .map(|(x, v)| Step::Set(tmp(x), IRExpr::Val(*v)))
+        .collect();
+    steps.extend(
+        copies
+            .iter()
+            .map(|(x, _)| Step::Set(*x, IRExpr::Val(Val::Var(tmp(x))))),
+    );
+    steps
+}
@@ -3,6 +3,20 @@ use std::fmt;
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct Symbol(&'static str);
 
+/// A 1-based line and column in the source file
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// The region of source text an expression was parsed from. `end` is exclusive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: Pos,
+    pub end: Pos,
+}
+
 #[derive(Debug)]
 pub struct Prog {
     pub funs: Vec<FunDecl>,
@@ -14,28 +28,33 @@ pub struct FunDecl {
     pub name: Symbol,
     pub params: Vec<Symbol>,
     pub body: Expr,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub enum Expr {
     Number(i64),
     Boolean(bool),
-    Var(Symbol),
-    Let(Vec<(Symbol, Expr)>, Box<Expr>),
-    UnOp(Op1, Box<Expr>),
-    BinOp(Op2, Box<Expr>, Box<Expr>),
+    Var(Symbol, Span),
+    Let(Vec<(Symbol, Expr)>, Box<Expr>, Span),
+    UnOp(Op1, Box<Expr>, Span),
+    BinOp(Op2, Box<Expr>, Box<Expr>, Span),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
     Loop(Box<Expr>),
-    Break(Box<Expr>),
-    Set(Symbol, Box<Expr>),
+    Break(Box<Expr>, Span),
+    Set(Symbol, Box<Expr>, Span),
     MakeVec(Box<Expr>, Box<Expr>),
     Vec(Vec<Expr>),
     VecSet(Box<Expr>, Box<Expr>, Box<Expr>),
     VecGet(Box<Expr>, Box<Expr>),
     VecLen(Box<Expr>),
     Block(Vec<Expr>),
-    Call(Symbol, Vec<Expr>),
-    Input,
+    Call(Symbol, Vec<Expr>, Span),
+    /// `(fn (params...) body)`, a closure capturing the free variables of `body` by value
+    Lambda(Vec<Symbol>, Box<Expr>, Span),
+    /// `(call f args...)`, calls the closure `f` evaluates to
+    CallIndirect(Box<Expr>, Vec<Expr>, Span),
+    Input(Span),
     Nil,
     PrintStack,
     Gc,
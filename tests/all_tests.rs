@@ -29,6 +29,7 @@ success_tests! {
         file: "range.snek",
         input: "5",
         heap_size: 25,
+        env: "SNEK_GC_VERIFY=1",
         expected: "[1, [2, [3, [4, [5, nil]]]]]"
     },
     {
@@ -383,6 +384,265 @@ success_tests! {
         file: "bst.boa.snek",
         input: "6",
         expected: "[8, [6, [5, false, false], [7, false, false]], [10, [9, false, false], [11, false, false]]]\n[8, [6, [5, [4, false, false], false], [7, false, false]], [10, [9, false, false], [11, false, false]]]",
+    },
+    {
+        name: closure_basic,
+        file: "closure_basic.snek",
+        input: "5",
+        expected: "15",
+    },
+    {
+        name: closure_compose,
+        file: "closure_compose.snek",
+        input: "5",
+        expected: "12\n20\n<closure>\n[5, 5]",
+    },
+    {
+        name: closure_nested,
+        file: "closure_nested.snek",
+        input: "1",
+        expected: "321",
+    },
+    {
+        name: closure_forced_gc,
+        file: "closure_gc.snek",
+        input: "100",
+        heap_size: 200,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "6500",
+    },
+    {
+        name: tail_sum_deep,
+        file: "tail_sum.snek",
+        input: "3000000",
+        expected: "4500001500000",
+    },
+    {
+        name: tail_mutual_deep,
+        file: "tail_mutual.snek",
+        input: "3000000",
+        expected: "1500000\n1500002",
+    },
+    {
+        name: tail_call_to_more_args,
+        file: "tail_wider.snek",
+        input: "1000001",
+        expected: "3412\n3412\n[1000002, 1000000, 2000002]",
+    },
+    {
+        name: even_odd_deep,
+        file: "even_odd.snek",
+        input: "3000001",
+        expected: "3000001\nfalse\nfalse",
+    },
+    {
+        name: tail_gc,
+        file: "tail_gc.snek",
+        input: "1000000",
+        heap_size: 16,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "1",
+    },
+    {
+        name: regalloc_forced_gc,
+        file: "regalloc_gc.snek",
+        input: "1000",
+        heap_size: 24,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "21000",
+    },
+    {
+        name: ssa_loop_const,
+        file: "ssa_loop_const.snek",
+        input: "4",
+        expected: "15",
+    },
+    {
+        name: ssa_swap,
+        file: "ssa_swap.snek",
+        input: "10",
+        expected: "55\n12586269025",
+    },
+    {
+        name: ssa_branches,
+        file: "ssa_branches.snek",
+        input: "2",
+        expected: "[3, [2, [100, [0, [1, nil]]]]]",
+    },
+    {
+        name: break_in_block_found,
+        file: "break_in_block.snek",
+        input: "16",
+        expected: "4\n4",
+    },
+    {
+        name: break_in_block_not_found,
+        file: "break_in_block.snek",
+        input: "5",
+        expected: "false\n6",
+    },
+    {
+        name: types_refine,
+        file: "types_refine.snek",
+        input: "3",
+        expected: "true\n10",
+    },
+    {
+        name: bounds_loop,
+        file: "bounds_loop.snek",
+        input: "4",
+        expected: "[3, 2, 2, 2]\n9",
+    },
+    {
+        name: overflow_ranges,
+        file: "overflow_ranges.snek",
+        input: "5",
+        expected: "12\n225",
+    },
+    {
+        name: overflow_dead_branch,
+        file: "overflow_dead_branch.snek",
+        expected: "5",
+    },
+    {
+        name: overflow_dead_branch_o0,
+        file: "overflow_dead_branch.snek",
+        flags: "-O0",
+        expected: "5",
+    },
+    {
+        name: overflow_ranges_o1,
+        file: "overflow_ranges.snek",
+        input: "5",
+        flags: "-O1",
+        expected: "12\n225",
+    },
+    {
+        name: overflow_ranges_no_checks_removed,
+        file: "overflow_ranges.snek",
+        input: "5",
+        flags: "--disable checks,bounds,overflow",
+        expected: "12\n225",
+    },
+    {
+        name: inline_calls,
+        file: "inline_calls.snek",
+        input: "3",
+        expected: "6\n19\n156",
+    },
+    {
+        name: specialize_args,
+        file: "specialize_args.snek",
+        input: "2",
+        expected: "1024\n9\n49\n7\n4\n1\n-2\n2\n-1",
+    },
+    {
+        name: specialize_overflow,
+        file: "specialize_overflow.snek",
+        expected: "5",
+    },
+    {
+        name: specialize_args_not_inlined,
+        file: "specialize_args.snek",
+        input: "2",
+        flags: "--inline-limit 0",
+        expected: "1024\n9\n49\n7\n4\n1\n-2\n2\n-1",
+    },
+    {
+        name: specialize_args_one_round,
+        file: "specialize_args.snek",
+        input: "2",
+        flags: "--max-iterations 1",
+        expected: "1024\n9\n49\n7\n4\n1\n-2\n2\n-1",
+    },
+    {
+        name: licm_set,
+        file: "licm_set.snek",
+        input: "2",
+        expected: "180\n29",
+    },
+    {
+        name: licm_guarded,
+        file: "licm_guarded.snek",
+        input: "5",
+        expected: "0\n0\n1\n2\n3\n8",
+    },
+    {
+        name: licm_guarded_verified,
+        file: "licm_guarded.snek",
+        input: "5",
+        flags: "--verify-ir",
+        expected: "0\n0\n1\n2\n3\n8",
+    },
+    {
+        name: specialize_args_verified,
+        file: "specialize_args.snek",
+        input: "2",
+        flags: "--verify-ir",
+        expected: "1024\n9\n49\n7\n4\n1\n-2\n2\n-1",
+    },
+    {
+        name: cse_vec,
+        file: "cse_vec.snek",
+        input: "2",
+        expected: "17\n4\n12\n[3, 3, 7]\n3",
+    },
+    {
+        name: cse_vec_large,
+        file: "cse_vec.snek",
+        input: "20",
+        expected: "305\n40\n462\n[21, 3, 7]\n42",
+    },
+    {
+        name: gc_precise,
+        file: "gc_precise.snek",
+        input: "0",
+        heap_size: 50,
+        max_heap_size: 50,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "40",
+    },
+    {
+        name: gc_moves_roots,
+        file: "gc_moves_roots.snek",
+        input: "0",
+        heap_size: 30,
+        max_heap_size: 30,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "[1, 2, 3]\n3",
+    },
+    {
+        name: gc_old_to_young,
+        file: "gc_old_to_young.snek",
+        input: "0",
+        heap_size: 100,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "[[0, 0], [1, 1], [2, 4], [3, 9], [4, 16]]\n16",
+    },
+    {
+        name: heap_grows,
+        file: "heap_grows.snek",
+        input: "1000",
+        heap_size: 20,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "500500",
+    },
+    {
+        name: gc_corrupt_needs_verify,
+        file: "heap_grows.snek",
+        input: "100",
+        heap_size: 20,
+        env: "SNEK_GC_CORRUPT=header",
+        expected: "5050",
+    },
+    {
+        name: crowded_heap_grows,
+        file: "crowded_heap.snek",
+        input: "9990",
+        heap_size: 10000,
+        env: "SNEK_GC_STATS=1",
+        stderr: "gc summary: 978 collections (977 minor, 1 full), 1 heap growths,",
+        expected: "1009990",
     }
 }
 
@@ -392,23 +652,48 @@ runtime_error_tests! {
         file: "make_vec.snek",
         input: "5",
         heap_size: 5,
+        max_heap_size: 5,
+        env: "SNEK_GC_VERIFY=1",
         expected: "out of memory",
     },
     {
-        name: vec_get_oob,
-        file: "vec_get.snek",
-        input: "5",
-        expected: "",
+        name: heap_grows_oom,
+        file: "heap_grows.snek",
+        input: "1000",
+        heap_size: 20,
+        max_heap_size: 1000,
+        env: "SNEK_GC_VERIFY=1",
+        expected: "out of memory",
     },
     {
-        name: add_overflow,
-        file: "add_overflow.snek",
-        expected: "overflow",
+        name: gc_verify_corrupt_header,
+        file: "heap_grows.snek",
+        input: "100",
+        heap_size: 20,
+        env: "SNEK_GC_VERIFY=1 SNEK_GC_CORRUPT=header",
+        expected: "heap verification failed before collection: the object runs past the end of its generation",
     },
     {
-        name: mul_overflow,
-        file: "mul_overflow.snek",
-        expected: "overflow",
+        name: gc_verify_dumps_the_object,
+        file: "heap_grows.snek",
+        input: "100",
+        heap_size: 20,
+        env: "SNEK_GC_VERIFY=1 SNEK_GC_CORRUPT=header",
+        expected: "gc word: 0x0\n  size: 4294967295",
+    },
+    {
+        name: gc_verify_corrupt_forwarding,
+        file: "heap_grows.snek",
+        input: "100",
+        heap_size: 20,
+        env: "SNEK_GC_VERIFY=1 SNEK_GC_CORRUPT=forward",
+        expected: "heap verification failed after collection: unexpected gc word\nobject at",
+    },
+    {
+        name: vec_get_oob,
+        file: "vec_get.snek",
+        input: "5",
+        expected: "",
     },
     {
         name: invalid_argument_1,
@@ -510,10 +795,271 @@ runtime_error_tests! {
         file: "error-bounds.boa.snek",
         input: "2",
         expected: "bounds",
+    },
+    {
+        name: closure_not_callable,
+        file: "closure_not_callable.snek",
+        input: "1",
+        expected: "invalid argument",
+    },
+    {
+        name: closure_wrong_arity,
+        file: "closure_wrong_arity.snek",
+        input: "1",
+        expected: "wrong number of arguments",
+    },
+    {
+        name: overflow_loop,
+        file: "overflow_loop.snek",
+        input: "1",
+        expected: "overflow",
+    },
+    {
+        name: types_input_join,
+        file: "types_input_join.snek",
+        input: "true",
+        expected: "invalid argument",
+    },
+    {
+        name: licm_guarded_bool,
+        file: "licm_guarded.snek",
+        input: "true",
+        expected: "invalid argument",
+    },
+    {
+        name: unused_overflow,
+        file: "unused_overflow.snek",
+        input: "5",
+        expected: "overflow",
+    },
+    {
+        name: overflow_taken_branch,
+        file: "overflow_taken_branch.snek",
+        input: "1",
+        expected: "overflow",
+    },
+    {
+        name: types_return_bool,
+        file: "types_return_bool.snek",
+        input: "5",
+        expected: "invalid argument",
+    },
+    {
+        name: types_refine_bool,
+        file: "types_refine.snek",
+        input: "true",
+        expected: "invalid argument",
+    },
+    {
+        name: bounds_loop_empty,
+        file: "bounds_loop.snek",
+        input: "0",
+        expected: "index out of bounds",
+    },
+    {
+        name: bounds_loop_off_by_one,
+        file: "bounds_loop_off_by_one.snek",
+        expected: "index out of bounds",
     }
 }
 
-static_error_tests! {}
+static_error_tests! {
+    {
+        name: bad_bind,
+        file: "bad_bind.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_expr,
+        file: "bad_expr.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_expr2,
+        file: "bad_expr2.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_expr3,
+        file: "bad_expr3.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_expr4,
+        file: "bad_expr4.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_funcdef1,
+        file: "bad_funcdef1.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_funcdef2,
+        file: "bad_funcdef2.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_parse,
+        file: "bad_parse.snek",
+        expected: "syntax",
+    },
+    {
+        name: bad_var_name,
+        file: "bad_var_name.snek",
+        expected: "syntax",
+    },
+    {
+        name: break_outside,
+        file: "break_outside.snek",
+        expected: "syntax",
+    },
+    {
+        name: empty,
+        file: "empty.snek",
+        expected: "syntax",
+    },
+    {
+        name: empty_bind,
+        file: "empty_bind.snek",
+        expected: "syntax",
+    },
+    {
+        name: empty_block,
+        file: "empty_block.snek",
+        expected: "syntax",
+    },
+    {
+        name: float,
+        file: "float.snek",
+        expected: "syntax",
+    },
+    {
+        name: func_badarg,
+        file: "func_badarg.snek",
+        expected: "syntax",
+    },
+    {
+        name: func_badarg2,
+        file: "func_badarg2.snek",
+        expected: "syntax",
+    },
+    {
+        name: func_badname,
+        file: "func_badname.snek",
+        expected: "syntax, syntax",
+    },
+    {
+        name: func_badname2,
+        file: "func_badname2.snek",
+        expected: "syntax, syntax",
+    },
+    {
+        name: func_badname3,
+        file: "func_badname3.snek",
+        expected: "syntax, syntax",
+    },
+    {
+        name: multiple_exprs,
+        file: "multiple_exprs.snek",
+        expected: "syntax, syntax",
+    },
+    {
+        name: number_bounds_fail,
+        file: "number_bounds_fail.snek",
+        expected: "syntax",
+    },
+    {
+        name: duplicate_binding,
+        file: "duplicate_binding.snek",
+        expected: "duplicate-binding",
+    },
+    {
+        name: nested_duplicate_binding,
+        file: "nested_duplicate_binding.snek",
+        expected: "duplicate-binding, unbound-identifier, unbound-identifier",
+    },
+    {
+        name: duplicate_params,
+        file: "duplicate_params.snek",
+        expected: "duplicate-binding",
+    },
+    {
+        name: duplicate_func,
+        file: "duplicate_func.snek",
+        expected: "duplicate-function",
+    },
+    {
+        name: unbound_id,
+        file: "unbound_id.snek",
+        expected: "unbound-identifier",
+    },
+    {
+        name: set_unbound,
+        file: "set_unbound.snek",
+        expected: "unbound-identifier",
+    },
+    {
+        name: func_undefined,
+        file: "func_undefined.snek",
+        expected: "undefined-function",
+    },
+    {
+        name: func_wrong_args,
+        file: "func_wrong_args.snek",
+        expected: "wrong-arity",
+    },
+    {
+        name: break_no_loop,
+        file: "break_no_loop.snek",
+        expected: "break-outside-loop",
+    },
+    {
+        name: input_in_fun,
+        file: "input_in_fun.snek",
+        expected: "input-in-function",
+    },
+    {
+        name: multiple_errors,
+        file: "multiple_errors.snek",
+        expected: "duplicate-binding, input-in-function, wrong-arity, duplicate-function, duplicate-binding, break-outside-loop, unbound-identifier, undefined-function, unbound-identifier, wrong-arity",
+    },
+    {
+        name: multiple_syntax_errors,
+        file: "multiple_syntax_errors.snek",
+        expected: "syntax, syntax, syntax",
+    },
+    {
+        name: closure_assign_captured,
+        file: "closure_assign_captured.snek",
+        expected: "assign-to-captured",
+    },
+    {
+        name: closure_dup_param,
+        file: "closure_dup_param.snek",
+        expected: "duplicate-binding",
+    },
+    {
+        name: add_overflow,
+        file: "add_overflow.snek",
+        expected: "overflow",
+    },
+    {
+        name: mul_overflow,
+        file: "mul_overflow.snek",
+        expected: "overflow",
+    },
+    {
+        name: const_underflow,
+        file: "const_underflow.snek",
+        expected: "overflow",
+    },
+    {
+        name: overflow_location,
+        file: "overflow_location.snek",
+        expected: "overflow@4:10",
+    }
+}
 
 profile_tests! {
     {
@@ -903,5 +1449,12 @@ profile_tests! {
         file: "bigloop.snek",
         input: "100000000",
         expected: "100",
+    },
+    {
+        name: profile_heap_grows,
+        file: "heap_grows.snek",
+        input: "1000",
+        heap_size: 20,
+        expected: "500500",
     }
-}
\ No newline at end of file
+}
@@ -46,7 +46,11 @@ macro_rules! tests {
                 file: $file:literal,
                 $(input: $input:literal,)?
                 $(heap_size: $heap_size:literal,)?
+                $(max_heap_size: $max_heap_size:literal,)?
                 $(time_trials: $time_trials:literal,)?
+                $(flags: $flags:literal,)?
+                $(env: $env:literal,)?
+                $(stderr: $stderr:literal,)?
                 expected: $expected:literal $(,)?
                 $(" $(tt:$tt)* ")?
             }
@@ -64,23 +68,50 @@ macro_rules! tests {
                 let mut heap_size = None;
                 $(heap_size = Some($heap_size);)?
                 #[allow(unused_assignments, unused_mut)]
+                let mut max_heap_size = None;
+                $(max_heap_size = Some($max_heap_size);)?
+                #[allow(unused_assignments, unused_mut)]
                 let mut time_trials = None;
                 $(time_trials = Some($time_trials);)?
+                #[allow(unused_assignments, unused_mut)]
+                let mut flags = "";
+                $(flags = $flags;)?
+                #[allow(unused_assignments, unused_mut)]
+                let mut env = "";
+                $(env = $env;)?
+                #[allow(unused_assignments, unused_mut)]
+                let mut stderr = None;
+                $(stderr = Some($stderr);)?
                 let kind = $crate::infra::TestKind::$kind;
-                $crate::infra::run_test(stringify!($name), $subdir, $file, input, heap_size, time_trials, $expected, kind);
+                $crate::infra::run_test(stringify!($name), $subdir, $file, flags, env, input, (heap_size, max_heap_size), time_trials, $expected, stderr, kind);
             }
         )*
     };
 }
 
+/// The initial size of the heap and how large it may grow, both in words
+type Heap = (Option<usize>, Option<usize>);
+
+/// The runtime takes the maximum size after the initial one, which defaults to 10000 words
+fn heap_args((heap_size, max_heap_size): Heap) -> Vec<String> {
+    match (heap_size, max_heap_size) {
+        (heap_size, Some(max)) => vec![heap_size.unwrap_or(10000).to_string(), max.to_string()],
+        (Some(heap_size), None) => vec![heap_size.to_string()],
+        (None, None) => vec![],
+    }
+}
+
 pub(crate) fn run_test(
     name: &str,
     subdir: Option<&str>,
     file: &str,
+    flags: &str,
+    env: &str,
     input: Option<&str>,
-    heap_size: Option<usize>,
+    heap: Heap,
     time_trials: Option<u32>,
     expected: &str,
+    stderr: Option<&str>,
     kind: TestKind,
 ) {
     let mut path = PathBuf::new();
@@ -89,31 +120,45 @@ pub(crate) fn run_test(
         path.push(subdir);
     }
     path.push(file);
+    let flags: Vec<&str> = flags.split_whitespace().collect();
 
     match kind {
-        TestKind::Success => run_success_test(name, &path, expected, input, heap_size),
-        TestKind::RuntimeError => run_runtime_error_test(name, &path, expected, input, heap_size),
-        TestKind::StaticError => run_static_error_test(name, &path, expected),
-        TestKind::Profile => run_profile_test(name, &path, expected, input, heap_size, time_trials),
+        TestKind::Success => {
+            let actual = run_success_test(name, &path, &flags, env, expected, input, heap);
+            if let Some(stderr) = stderr {
+                assert!(actual.contains(stderr), "expected `{stderr}` in stderr, got `{actual}`");
+            }
+        }
+        TestKind::RuntimeError => {
+            run_runtime_error_test(name, &path, &flags, env, expected, input, heap)
+        }
+        TestKind::StaticError => run_static_error_test(name, &path, &flags, expected),
+        TestKind::Profile => {
+            run_profile_test(name, &path, &flags, expected, input, heap, time_trials)
+        }
     }
 }
 
+/// Returns what the program printed to stderr, which `stderr:` in a success test must contain
 fn run_success_test(
     name: &str,
     file: &Path,
+    flags: &[&str],
+    env: &str,
     expected: &str,
     input: Option<&str>,
-    heap_size: Option<usize>,
-) {
-    if let Err(err) = compile(name, file) {
+    heap: Heap,
+) -> String {
+    if let Err(err) = compile(name, file, flags, env) {
         panic!("expected a successful compilation, but got an error: `{err}`");
     }
-    match run(name, input, heap_size) {
+    match run(name, input, heap, env) {
         Err(err) => {
             panic!("expected a successful execution, but got an error: `{err}`");
         }
-        Ok(actual_output) => {
+        Ok((actual_output, stderr)) => {
             diff(expected, actual_output);
+            stderr
         }
     }
 }
@@ -121,48 +166,55 @@ fn run_success_test(
 fn run_runtime_error_test(
     name: &str,
     file: &Path,
+    flags: &[&str],
+    env: &str,
     expected: &str,
     input: Option<&str>,
-    heap_size: Option<usize>,
+    heap: Heap,
 ) {
-    if let Err(err) = compile(name, file) {
+    if let Err(err) = compile(name, file, flags, env) {
         panic!("expected a successful compilation, but got an error: `{err}`");
     }
-    match run(name, input, heap_size) {
-        Ok(out) => {
+    match run(name, input, heap, env) {
+        Ok((out, _)) => {
             panic!("expected a runtime error, but program executed succesfully - expected error: `{expected}`, output: `{out}`");
         }
         Err(err) => check_error_msg(&err, expected),
     }
 }
 
-fn run_static_error_test(name: &str, file: &Path, expected: &str) {
-    match compile(name, file) {
+fn run_static_error_test(name: &str, file: &Path, flags: &[&str], expected: &str) {
+    match compile(name, file, flags, "") {
         Ok(()) => {
             panic!(
                 "expected a static error, but compilation succeeded - expected error: `{expected}`"
             )
         }
-        Err(err) => check_error_msg(&err, expected),
+        Err(err) => check_error_kinds(&err, expected),
     }
 }
 
+/// Profiles run without extra environment variables, checking the heap would dominate them
 fn run_profile_test(
     name: &str,
     file: &Path,
+    flags: &[&str],
     expected: &str,
     input: Option<&str>,
-    heap_size: Option<usize>,
+    heap: Heap,
     time_trials: Option<u32>,
 ) {
-    run_success_test(name, file, expected, input, heap_size);
-    profile(name, input, heap_size, time_trials);
+    run_success_test(name, file, flags, "", expected, input, heap);
+    profile(name, input, heap, time_trials);
 }
 
-fn compile(name: &str, file: &Path) -> Result<(), String> {
+/// Builds the test's runtime with the hooks for damaging the heap when `env` asks for them,
+/// production builds leave them out
+fn compile(name: &str, file: &Path, flags: &[&str], env: &str) -> Result<(), String> {
     // Run the compiler
     let compiler: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
     let output = Command::new(&compiler)
+        .args(flags)
         .arg(file)
         .arg(&mk_path(name, Ext::Asm))
         .output()
@@ -172,7 +224,11 @@ fn compile(name: &str, file: &Path) -> Result<(), String> {
     }
 
     // Assemble and link
-    let output = Command::new("make")
+    let mut make = Command::new("make");
+    if env.contains("SNEK_GC_CORRUPT=") {
+        make.arg("RUNTIMEFLAGS=--cfg gc_corruption");
+    }
+    let output = make
         .arg(&mk_path(name, Ext::Run))
         .output()
         .expect("could not run make");
@@ -181,23 +237,31 @@ fn compile(name: &str, file: &Path) -> Result<(), String> {
     Ok(())
 }
 
-fn run(name: &str, input: Option<&str>, heap_size: Option<usize>) -> Result<String, String> {
+/// Runs the compiled test with the environment variables in `env`, given as space separated
+/// `NAME=value` pairs, e.g. `SNEK_GC_VERIFY=1` to check the heap around every collection,
+/// returning its output and what it printed to stderr
+fn run(name: &str, input: Option<&str>, heap: Heap, env: &str) -> Result<(String, String), String> {
     let mut cmd = Command::new(&mk_path(name, Ext::Run));
+    for var in env.split_whitespace() {
+        let (key, value) = var.split_once('=').expect("expected `NAME=value`");
+        cmd.env(key, value);
+    }
     if let Some(input) = input {
         cmd.arg(input);
     }
-    if let Some(heap_size) = heap_size {
-        cmd.arg(heap_size.to_string());
+    for size in heap_args(heap) {
+        cmd.arg(size);
     }
     let output = cmd.output().unwrap();
     if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap().trim().to_string())
+        let stderr = String::from_utf8(output.stderr).unwrap().trim().to_string();
+        Ok((String::from_utf8(output.stdout).unwrap().trim().to_string(), stderr))
     } else {
         Err(String::from_utf8(output.stderr).unwrap().trim().to_string())
     }
 }
 
-fn profile(name: &str, input: Option<&str>, heap_size: Option<usize>, time_trials: Option<u32>) {
+fn profile(name: &str, input: Option<&str>, heap: Heap, time_trials: Option<u32>) {
     if cfg!(windows) {
         eprintln!("The profiling tools being used do not work on your platform.");
         return;
@@ -208,14 +272,15 @@ fn profile(name: &str, input: Option<&str>, heap_size: Option<usize>, time_trial
         program_str.push_str(" ");
         program_str.push_str(input);
     }
-    if let Some(heap_size) = heap_size {
+    for size in heap_args(heap) {
         program_str.push_str(" ");
-        program_str.push_str(&heap_size.to_string());
+        program_str.push_str(&size);
     }
 
     profile_dynamic_instr_count(&program_str);
     profile_static_instr_count(mk_path(name, Ext::Obj).to_str().unwrap());
     profile_time_taken(&program_str, time_trials);
+    profile_gc(&program_str);
 }
 
 fn profile_dynamic_instr_count(program_str: &str) {
@@ -292,6 +357,19 @@ fn profile_time_taken(program_str: &str, trials: Option<u32>) {
     println!();
 }
 
+/// The runtime reports every collection and a summary on stderr when `SNEK_GC_STATS` is set
+fn profile_gc(program_str: &str) {
+    let cmd = format!("SNEK_GC_STATS=1 {program_str} 2>&1 >/dev/null | grep '^gc summary:'");
+    let out = Command::new("sh").args(["-c", &cmd]).output().unwrap();
+    if out.status.success() {
+        let out_str = String::from_utf8(out.stdout).unwrap().trim().to_string();
+        println!("Garbage collection: {out_str}");
+    } else {
+        eprintln!("Failed to get garbage collection statistics");
+    }
+    println!();
+}
+
 fn check_error_msg(found: &str, expected: &str) {
     let lower_found = found.trim().to_lowercase();
     let lower_expected = expected.trim().to_lowercase();
@@ -301,6 +379,35 @@ fn check_error_msg(found: &str, expected: &str) {
     );
 }
 
+/// Static errors are compared by kind: the compiler prints `error[<kind>]: <message>` for every
+/// error it finds and the kinds, in order, have to match the comma separated `expected` list, so
+/// rewording a message does not break the tests. An expected `<kind>@<line>:<col>` also checks
+/// the location printed under the error.
+fn check_error_kinds(found: &str, expected: &str) {
+    let expected: Vec<&str> = expected.split(',').map(str::trim).collect();
+    let lines: Vec<&str> = found.lines().collect();
+    let kinds: Vec<String> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| Some((i, line.strip_prefix("error[")?.split_once(']')?.0)))
+        .enumerate()
+        .map(|(n, (i, kind))| {
+            let location = lines
+                .get(i + 1)
+                .and_then(|line| line.trim().strip_prefix("--> "))
+                .and_then(|path| path.split_once(':'));
+            match (expected.get(n), location) {
+                (Some(e), Some((_, location))) if e.contains('@') => format!("{kind}@{location}"),
+                _ => kind.to_string(),
+            }
+        })
+        .collect();
+    assert_eq!(
+        kinds, expected,
+        "the reported errors are not of the expected kinds - found: `{found}`",
+    );
+}
+
 fn diff(expected: &str, found: String) {
     let expected = expected.trim();
 